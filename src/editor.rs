@@ -0,0 +1,187 @@
+use piston_window::{Button, Key, MouseButton};
+use specs::{Join, World};
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use input;
+use level::{self, Level, Prop};
+use systems::components::*;
+use systems::terrain::retrace_all;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Tool {
+    Paint,
+    Erase,
+    Spawn,
+    Prop,
+}
+
+const MIN_BRUSH: f64 = 2.0;
+const MAX_BRUSH: f64 = 60.0;
+const PICK_RADIUS: f64 = 20.0;
+const PROP_SIZE: f64 = 40.0;
+
+// Edits a level in place: terrain cells live in the world so the renderer and tracer can
+// show them, while spawns and props are kept on the level until it is saved or played.
+pub struct Editor {
+    pub level: Level,
+    pub path: PathBuf,
+    pub tool: Tool,
+    pub brush: f64,
+    pub material: Material,
+    pub cursor: [f64; 2],
+    pub status: String,
+    painting: bool,
+}
+
+impl Editor {
+    pub fn new(level: Level, path: &Path) -> Editor {
+        Editor {
+            level: level,
+            path: path.to_path_buf(),
+            tool: Tool::Paint,
+            brush: 8.0,
+            material: Material::Dirt,
+            cursor: [0.0, 0.0],
+            status: format!("editing {}", path.display()),
+            painting: false,
+        }
+    }
+
+    pub fn key(&mut self, world: &World, key: Key) {
+        match key {
+            Key::D1 => self.tool = Tool::Paint,
+            Key::D2 => self.tool = Tool::Erase,
+            Key::D3 => self.tool = Tool::Spawn,
+            Key::D4 => self.tool = Tool::Prop,
+            Key::LeftBracket => self.brush = (self.brush - 1.0).max(MIN_BRUSH),
+            Key::RightBracket => self.brush = (self.brush + 1.0).min(MAX_BRUSH),
+            Key::M => {
+                self.material = match self.material {
+                    Material::Dirt => Material::Rock,
+                    Material::Rock => Material::Sand,
+                    Material::Sand => Material::Dirt,
+                }
+            }
+            Key::S => self.save(world),
+            _ => {}
+        }
+    }
+
+    pub fn press(&mut self, world: &World, button: Button) {
+        match button {
+            Button::Mouse(MouseButton::Left) => {
+                match self.tool {
+                    Tool::Paint | Tool::Erase => {
+                        self.painting = true;
+                        self.stroke(world);
+                    }
+                    Tool::Spawn => self.level.spawns.push(self.cursor),
+                    Tool::Prop => {
+                        self.level.props.push(Prop {
+                            pos: self.cursor,
+                            bounds: Bounds::Rectangle(PROP_SIZE, PROP_SIZE),
+                        })
+                    }
+                }
+            }
+            Button::Mouse(MouseButton::Right) => self.remove_nearest(),
+            Button::Keyboard(key) => self.key(world, key),
+            _ => {}
+        }
+    }
+
+    pub fn release(&mut self, world: &World, button: Button) {
+        if button == Button::Mouse(MouseButton::Left) && self.painting {
+            self.painting = false;
+            retrace_all(world);
+        }
+    }
+
+    pub fn cursor_moved(&mut self, world: &World, x: f64, y: f64) {
+        self.cursor = [x, y];
+        if self.painting {
+            self.stroke(world);
+        }
+    }
+
+    fn stroke(&mut self, world: &World) {
+        let material = match self.tool {
+            Tool::Paint => Some(self.material),
+            _ => None,
+        };
+        let r = self.brush.ceil() as i64;
+        let mut terrain = world.write::<Terrain>();
+        for terrain in (&mut terrain).join() {
+            for x in -r..(r + 1) {
+                for y in -r..(r + 1) {
+                    if ((x * x + y * y) as f64).sqrt() > self.brush {
+                        continue;
+                    }
+                    let (px, py) = (self.cursor[0] + x as f64, self.cursor[1] + y as f64);
+                    // The tracer looks one cell to the left and above, so keep clear of the origin.
+                    if px < 1.0 || py < 1.0 {
+                        continue;
+                    }
+                    let p = [px as usize, py as usize];
+                    if terrain.points.contains(&p) != material.is_some() ||
+                       material.map_or(false, |m| terrain.material_at(p) != m) {
+                        terrain.set(&p, material);
+                        terrain.dirty = true;
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove_nearest(&mut self) {
+        let c = self.cursor;
+        let near = |p: &[f64; 2]| ((p[0] - c[0]).powi(2) + (p[1] - c[1]).powi(2)).sqrt() < PICK_RADIUS;
+        if let Some(i) = self.level.spawns.iter().position(|s| near(s)) {
+            self.level.spawns.remove(i);
+        } else if let Some(i) = self.level.props.iter().position(|p| near(&p.pos)) {
+            self.level.props.remove(i);
+        }
+    }
+
+    // The level as it stands, with the painted terrain written back as runs.
+    pub fn level(&self, world: &World) -> Level {
+        let mut level = self.level.clone();
+        if let Some(terrain) = (&world.read::<Terrain>()).join().next() {
+            level.terrain = level::terrain_runs(terrain);
+        }
+        level
+    }
+
+    // The level as the game would load it back, or None with the reason in the status
+    // line if it wouldn't.
+    pub fn playable(&mut self, world: &World) -> Option<Level> {
+        let level = level::parse(&level::write(&self.level(world)))
+            .map_err(|errors| errors[0].message.clone())
+            .and_then(|level| input::players(&level, None).map(|_| level));
+        match level {
+            Ok(level) => Some(level),
+            Err(message) => {
+                self.status = format!("can't play: {}", message);
+                None
+            }
+        }
+    }
+
+    pub fn save(&mut self, world: &World) {
+        let source = level::write(&self.level(world));
+        // Only write levels the game will accept back.
+        if let Err(errors) = level::parse(&source) {
+            self.status = format!("not saved: {}", errors[0].message);
+            return;
+        }
+        let result = File::create(&self.path).and_then(|mut f| f.write_all(source.as_bytes()));
+        self.status = match result {
+            Ok(()) => format!("saved {}", self.path.display()),
+            Err(e) => format!("could not save {}: {}", self.path.display(), e),
+        };
+        info!("{}", self.status);
+    }
+}
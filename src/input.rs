@@ -0,0 +1,103 @@
+use piston_window::{Button, Key};
+use piston_window::Button::Keyboard;
+
+use std::collections::{HashMap, HashSet};
+
+use level::Level;
+use systems::components::*;
+
+// Players sharing the keyboard each get one of these layouts.
+pub const LAYOUTS: i32 = 2;
+// A network game is between this peer and one other.
+const PEERS: usize = 2;
+
+fn bindings(layout: i32) -> [(Key, u8); 8] {
+    match layout {
+        2 => {
+            [(Key::Up, action::UP),
+             (Key::Down, action::DOWN),
+             (Key::Left, action::LEFT),
+             (Key::Right, action::RIGHT),
+             (Key::Comma, action::AIM_UP),
+             (Key::Period, action::AIM_DOWN),
+             (Key::Slash, action::CAST),
+             (Key::RShift, action::NEXT_SPELL)]
+        }
+        _ => {
+            [(Key::W, action::UP),
+             (Key::S, action::DOWN),
+             (Key::A, action::LEFT),
+             (Key::D, action::RIGHT),
+             (Key::Q, action::AIM_UP),
+             (Key::E, action::AIM_DOWN),
+             (Key::F, action::CAST),
+             (Key::R, action::NEXT_SPELL)]
+        }
+    }
+}
+
+// The actions held down on the given keyboard layout.
+pub fn actions(keys: &HashSet<Button>, layout: i32) -> Actions {
+    let mut actions = Actions::default();
+    for &(key, bit) in bindings(layout).iter() {
+        if keys.contains(&Keyboard(key)) {
+            actions.insert(bit);
+        }
+    }
+    actions
+}
+
+// Hot seat play: player n reads layout n.
+pub fn local_actions(keys: &HashSet<Button>) -> HashMap<i32, Actions> {
+    (1..LAYOUTS + 1).map(|pid| (pid, actions(keys, pid))).collect()
+}
+
+// The players people control: every spawn the computer isn't playing. Each needs its
+// input from somewhere, a keyboard layout of its own or, in a network game as `local`,
+// this peer or the other one, so levels with more of them than that are refused.
+pub fn players(level: &Level, local: Option<i32>) -> Result<Vec<i32>, String> {
+    let players: Vec<i32> = (1..level.spawns.len() as i32 + 1)
+        .filter(|&p| !level.ai.iter().any(|&(ai, _)| ai == p))
+        .collect();
+    match local {
+        Some(local) if !players.contains(&local) => {
+            Err(format!("no player {} for this peer to control", local))
+        }
+        Some(_) if players.len() > PEERS => {
+            Err(format!("{} players need controlling but a network game only has {} peers",
+                        players.len(),
+                        PEERS))
+        }
+        None if players.len() > LAYOUTS as usize => {
+            Err(format!("{} players need controlling but only {} can share the keyboard",
+                        players.len(),
+                        LAYOUTS))
+        }
+        _ => Ok(players),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use level;
+
+    const FOUR: &'static str = "terrain rect 0 400 800 100 rock\n\
+                                spawn 100 300\nspawn 300 300\nspawn 500 300\nspawn 700 300\n";
+
+    #[test]
+    fn every_player_needs_an_input() {
+        let level = level::parse(FOUR).ok().unwrap();
+        assert!(players(&level, None).is_err());
+        assert!(players(&level, Some(1)).is_err());
+    }
+
+    #[test]
+    fn computer_players_need_no_input() {
+        let source = format!("{}ai 2 easy\nai 4 hard\n", FOUR);
+        let level = level::parse(&source).ok().unwrap();
+        assert_eq!(players(&level, None), Ok(vec![1, 3]));
+        assert_eq!(players(&level, Some(3)), Ok(vec![1, 3]));
+        assert!(players(&level, Some(2)).is_err());
+    }
+}
@@ -0,0 +1,601 @@
+use specs::World;
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use systems::components::*;
+
+const NAMES: [&'static str; 4] = ["Merlin", "Morgana", "Gandalf", "Rincewind"];
+// Health a second lost in the water, and how far the water reaches.
+const WATER_DAMAGE: f64 = 20.0;
+const WATER_EXTENT: [f64; 2] = [20000.0, 5000.0];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TerrainSource {
+    Rect {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        material: Material,
+    },
+    // One horizontal strip of cells, which is how the editor writes painted terrain.
+    Run {
+        y: usize,
+        from: usize,
+        to: usize,
+        material: Material,
+    },
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Prop {
+    pub pos: [f64; 2],
+    pub bounds: Bounds,
+}
+
+#[derive(Clone)]
+pub struct Level {
+    pub background: Option<String>,
+    pub terrain: Vec<TerrainSource>,
+    pub spawns: Vec<[f64; 2]>,
+    pub water: Option<f64>,
+    pub wind: [f64; 2],
+    pub props: Vec<Prop>,
+    // Centre, width and height of each area that kills wizards outright.
+    pub kill_zones: Vec<[f64; 4]>,
+    pub spells: Vec<String>,
+    // Players played by the computer rather than a keyboard.
+    pub ai: Vec<(i32, Difficulty)>,
+}
+
+#[derive(Debug)]
+pub struct LevelError {
+    // Both 0 for problems with the level as a whole rather than any one line.
+    pub line: usize,
+    pub col: usize,
+    pub message: String,
+}
+
+impl LevelError {
+    fn whole(message: String) -> LevelError {
+        LevelError {
+            line: 0,
+            col: 0,
+            message: message,
+        }
+    }
+}
+
+impl fmt::Display for LevelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}:{}: {}", self.line, self.col, self.message)
+        }
+    }
+}
+
+struct Token<'a> {
+    text: &'a str,
+    col: usize,
+}
+
+fn tokenise(line: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut start = None;
+    for (i, ch) in line.char_indices().chain(Some((line.len(), ' '))) {
+        match (start, ch.is_whitespace()) {
+            (None, false) => start = Some(i),
+            (Some(s), true) => {
+                tokens.push(Token {
+                    text: &line[s..i],
+                    col: s + 1,
+                });
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    tokens
+}
+
+pub fn material_name(material: Material) -> &'static str {
+    match material {
+        Material::Dirt => "dirt",
+        Material::Rock => "rock",
+        Material::Sand => "sand",
+    }
+}
+
+pub fn difficulty_name(difficulty: Difficulty) -> &'static str {
+    match difficulty {
+        Difficulty::Easy => "easy",
+        Difficulty::Normal => "normal",
+        Difficulty::Hard => "hard",
+    }
+}
+
+pub fn parse_difficulty(s: &str) -> Option<Difficulty> {
+    match s {
+        "easy" => Some(Difficulty::Easy),
+        "normal" => Some(Difficulty::Normal),
+        "hard" => Some(Difficulty::Hard),
+        _ => None,
+    }
+}
+
+fn parse_material(s: &str) -> Option<Material> {
+    match s {
+        "dirt" => Some(Material::Dirt),
+        "rock" => Some(Material::Rock),
+        "sand" => Some(Material::Sand),
+        _ => None,
+    }
+}
+
+struct Line<'a> {
+    number: usize,
+    tokens: Vec<Token<'a>>,
+    end: usize,
+}
+
+impl<'a> Line<'a> {
+    fn error(&self, col: usize, message: String) -> LevelError {
+        LevelError {
+            line: self.number,
+            col: col,
+            message: message,
+        }
+    }
+
+    fn arg<T: FromStr>(&self, i: usize, what: &str) -> Result<T, LevelError> {
+        match self.tokens.get(i) {
+            Some(t) => {
+                t.text
+                    .parse()
+                    .map_err(|_| self.error(t.col, format!("expected {}, found `{}`", what, t.text)))
+            }
+            None => Err(self.error(self.end, format!("missing {}", what))),
+        }
+    }
+
+    fn material(&self, i: usize) -> Result<Material, LevelError> {
+        match self.tokens.get(i) {
+            Some(t) => {
+                parse_material(t.text)
+                    .ok_or_else(|| self.error(t.col, format!("unknown material `{}`", t.text)))
+            }
+            None => Ok(Material::Dirt),
+        }
+    }
+
+    fn expect_len(&self, n: usize) -> Result<(), LevelError> {
+        match self.tokens.get(n) {
+            Some(t) => Err(self.error(t.col, format!("unexpected `{}`", t.text))),
+            None => Ok(()),
+        }
+    }
+
+    fn positive(&self, i: usize, what: &str) -> Result<f64, LevelError> {
+        let v: f64 = try!(self.arg(i, what));
+        if v > 0.0 {
+            Ok(v)
+        } else {
+            Err(self.error(self.tokens[i].col, format!("{} must be positive", what)))
+        }
+    }
+}
+
+fn parse_terrain(line: &Line) -> Result<TerrainSource, LevelError> {
+    let kind = line.tokens.get(1).map_or("", |t| t.text);
+    match kind {
+        "rect" => {
+            try!(line.expect_len(7));
+            Ok(TerrainSource::Rect {
+                x: try!(line.arg(2, "x")),
+                y: try!(line.arg(3, "y")),
+                width: try!(line.arg(4, "width")),
+                height: try!(line.arg(5, "height")),
+                material: try!(line.material(6)),
+            })
+        }
+        "run" => {
+            try!(line.expect_len(6));
+            let (from, to) = (try!(line.arg(3, "start x")), try!(line.arg(4, "end x")));
+            if to < from {
+                return Err(line.error(line.tokens[4].col, "run ends before it starts".to_string()));
+            }
+            Ok(TerrainSource::Run {
+                y: try!(line.arg(2, "y")),
+                from: from,
+                to: to,
+                material: try!(line.material(5)),
+            })
+        }
+        _ => {
+            let col = line.tokens.get(1).map_or(line.end, |t| t.col);
+            Err(line.error(col, "expected terrain `rect` or `run`".to_string()))
+        }
+    }
+}
+
+fn parse_prop(line: &Line) -> Result<Prop, LevelError> {
+    let kind = line.tokens.get(1).map_or("", |t| t.text);
+    let pos = [try!(line.arg(2, "x")), try!(line.arg(3, "y"))];
+    let bounds = match kind {
+        "rect" => {
+            try!(line.expect_len(6));
+            Bounds::Rectangle(try!(line.positive(4, "width")), try!(line.positive(5, "height")))
+        }
+        "circle" => {
+            try!(line.expect_len(5));
+            Bounds::Circle(try!(line.positive(4, "radius")))
+        }
+        _ => {
+            let col = line.tokens.get(1).map_or(line.end, |t| t.col);
+            return Err(line.error(col, "expected prop `rect` or `circle`".to_string()));
+        }
+    };
+    Ok(Prop {
+        pos: pos,
+        bounds: bounds,
+    })
+}
+
+fn parse_ai(line: &Line) -> Result<(i32, Difficulty), LevelError> {
+    try!(line.expect_len(3));
+    let player = try!(line.arg::<i32>(1, "player"));
+    let difficulty = try!(parse_difficulty(line.tokens[2].text).ok_or_else(|| {
+        line.error(line.tokens[2].col,
+                   "expected difficulty `easy`, `normal` or `hard`".to_string())
+    }));
+    Ok((player, difficulty))
+}
+
+fn parse_kill_zone(line: &Line) -> Result<[f64; 4], LevelError> {
+    try!(line.expect_len(5));
+    Ok([try!(line.arg(1, "x")),
+        try!(line.arg(2, "y")),
+        try!(line.positive(3, "width")),
+        try!(line.positive(4, "height"))])
+}
+
+fn parse_pair(line: &Line, first: &str, second: &str) -> Result<[f64; 2], LevelError> {
+    try!(line.expect_len(3));
+    Ok([try!(line.arg(1, first)), try!(line.arg(2, second))])
+}
+
+// Sets a value that may only appear once, remembering where it was set for the error.
+fn once<T>(slot: &mut Option<(T, usize)>,
+           value: T,
+           line: &Line,
+           what: &str)
+           -> Result<(), LevelError> {
+    if let Some((_, first)) = *slot {
+        return Err(line.error(1, format!("{} already set on line {}", what, first)));
+    }
+    *slot = Some((value, line.number));
+    Ok(())
+}
+
+pub fn parse(source: &str) -> Result<Level, Vec<LevelError>> {
+    let mut errors = vec![];
+    let mut background = None;
+    let mut water = None;
+    let mut wind = None;
+    let mut spells = None;
+    let mut terrain = vec![];
+    let mut spawns = vec![];
+    let mut props = vec![];
+    let mut ai = vec![];
+    let mut kill_zones = vec![];
+
+    for (i, text) in source.lines().enumerate() {
+        let text = text.split('#').next().unwrap_or("");
+        let line = Line {
+            number: i + 1,
+            tokens: tokenise(text),
+            end: text.trim_right().len() + 1,
+        };
+        let directive = match line.tokens.first() {
+            Some(t) => t,
+            None => continue,
+        };
+        let result = match directive.text {
+            "background" => {
+                line.expect_len(2)
+                    .and_then(|_| line.arg::<String>(1, "texture name"))
+                    .and_then(|b| once(&mut background, b, &line, "background"))
+            }
+            "water" => {
+                line.expect_len(2)
+                    .and_then(|_| line.arg::<f64>(1, "water height"))
+                    .and_then(|w| once(&mut water, w, &line, "water"))
+            }
+            "wind" => {
+                parse_pair(&line, "minimum wind", "maximum wind").and_then(|w| if w[0] > w[1] {
+                    let message = "maximum wind is below the minimum".to_string();
+                    Err(line.error(line.tokens[2].col, message))
+                } else {
+                    once(&mut wind, w, &line, "wind")
+                })
+            }
+            "terrain" => parse_terrain(&line).map(|t| terrain.push(t)),
+            "spawn" => parse_pair(&line, "x", "y").map(|s| spawns.push((s, line.number))),
+            "prop" => parse_prop(&line).map(|p| props.push(p)),
+            "killzone" => parse_kill_zone(&line).map(|z| kill_zones.push(z)),
+            "ai" => parse_ai(&line).map(|a| ai.push((a, line.number))),
+            "spells" => {
+                let known = SpellBook::standard();
+                let mut names = vec![];
+                let mut result = Ok(());
+                for t in line.tokens.iter().skip(1) {
+                    if known.spells.iter().any(|s| s.name == t.text) {
+                        names.push(t.text.to_string());
+                    } else {
+                        result = Err(line.error(t.col, format!("unknown spell `{}`", t.text)));
+                        break;
+                    }
+                }
+                if names.is_empty() && result.is_ok() {
+                    result = Err(line.error(line.end, "expected at least one spell".to_string()));
+                }
+                result.and_then(|_| once(&mut spells, names, &line, "spells"))
+            }
+            other => Err(line.error(directive.col, format!("unknown directive `{}`", other))),
+        };
+        if let Err(e) = result {
+            errors.push(e);
+        }
+    }
+
+    if terrain.is_empty() {
+        errors.push(LevelError::whole("level has no terrain".to_string()));
+    }
+    if spawns.len() < 2 {
+        let message = format!("level needs at least 2 spawn points, found {}", spawns.len());
+        errors.push(LevelError::whole(message));
+    }
+    if spawns.len() > NAMES.len() {
+        let (_, line) = spawns[NAMES.len()];
+        errors.push(LevelError {
+            line: line,
+            col: 1,
+            message: format!("at most {} spawn points are supported", NAMES.len()),
+        });
+    }
+    for (i, &((player, _), line)) in ai.iter().enumerate() {
+        let message = if player < 1 || player as usize > spawns.len() {
+            format!("no spawn point for ai player {}", player)
+        } else if ai[..i].iter().any(|&((p, _), _)| p == player) {
+            format!("ai player {} already set", player)
+        } else {
+            continue;
+        };
+        errors.push(LevelError {
+            line: line,
+            col: 1,
+            message: message,
+        });
+    }
+    let cells = build_terrain(&terrain);
+    for &(s, line) in spawns.iter() {
+        if s[0] >= 0.0 && s[1] >= 0.0 && cells.points.contains(&[s[0] as usize, s[1] as usize]) {
+            errors.push(LevelError {
+                line: line,
+                col: 1,
+                message: "spawn point is inside terrain".to_string(),
+            });
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Level {
+        background: background.map(|(b, _)| b),
+        terrain: terrain,
+        spawns: spawns.into_iter().map(|(s, _)| s).collect(),
+        water: water.map(|(w, _)| w),
+        wind: wind.map_or([0.0, 0.0], |(w, _)| w),
+        props: props,
+        kill_zones: kill_zones,
+        spells: spells.map_or(vec![], |(s, _)| s),
+        ai: ai.into_iter().map(|(a, _)| a).collect(),
+    })
+}
+
+// The inverse of `build_terrain`: every row of cells as runs of a single material.
+pub fn terrain_runs(terrain: &Terrain) -> Vec<TerrainSource> {
+    let mut cells: Vec<[usize; 2]> = terrain.points.iter().cloned().collect();
+    cells.sort_by_key(|p| (p[1], p[0]));
+    let mut runs = vec![];
+    let mut current: Option<TerrainSource> = None;
+    for p in cells {
+        let material = terrain.material_at(p);
+        current = match current {
+            Some(TerrainSource::Run { y, from, to, material: m }) if y == p[1] && to + 1 == p[0] &&
+                                                                     m == material => {
+                Some(TerrainSource::Run {
+                    y: y,
+                    from: from,
+                    to: p[0],
+                    material: m,
+                })
+            }
+            previous => {
+                if let Some(run) = previous {
+                    runs.push(run);
+                }
+                Some(TerrainSource::Run {
+                    y: p[1],
+                    from: p[0],
+                    to: p[0],
+                    material: material,
+                })
+            }
+        };
+    }
+    if let Some(run) = current {
+        runs.push(run);
+    }
+    runs
+}
+
+fn write_prop(prop: &Prop) -> Option<String> {
+    let (x, y) = (prop.pos[0], prop.pos[1]);
+    match prop.bounds {
+        Bounds::Rectangle(w, h) => Some(format!("prop rect {} {} {} {}\n", x, y, w, h)),
+        Bounds::Circle(r) => Some(format!("prop circle {} {} {}\n", x, y, r)),
+        Bounds::Polygon(_) |
+        Bounds::Compound(_) => None,
+    }
+}
+
+pub fn write(level: &Level) -> String {
+    let mut out = String::new();
+    if let Some(ref background) = level.background {
+        out.push_str(&format!("background {}\n", background));
+    }
+    out.push_str(&format!("wind {} {}\n", level.wind[0], level.wind[1]));
+    if let Some(water) = level.water {
+        out.push_str(&format!("water {}\n", water));
+    }
+    if !level.spells.is_empty() {
+        out.push_str(&format!("spells {}\n", level.spells.join(" ")));
+    }
+    out.push_str("\n");
+    for spawn in level.spawns.iter() {
+        out.push_str(&format!("spawn {} {}\n", spawn[0], spawn[1]));
+    }
+    for &(player, difficulty) in level.ai.iter() {
+        out.push_str(&format!("ai {} {}\n", player, difficulty_name(difficulty)));
+    }
+    for line in level.props.iter().filter_map(write_prop) {
+        out.push_str(&line);
+    }
+    for z in level.kill_zones.iter() {
+        out.push_str(&format!("killzone {} {} {} {}\n", z[0], z[1], z[2], z[3]));
+    }
+    out.push_str("\n");
+    for source in level.terrain.iter() {
+        let line = match *source {
+            TerrainSource::Rect { x, y, width, height, material } => {
+                format!("terrain rect {} {} {} {} {}\n", x, y, width, height, material_name(material))
+            }
+            TerrainSource::Run { y, from, to, material } => {
+                format!("terrain run {} {} {} {}\n", y, from, to, material_name(material))
+            }
+        };
+        out.push_str(&line);
+    }
+    out
+}
+
+pub fn load(path: &Path) -> Result<Level, Vec<LevelError>> {
+    let mut source = String::new();
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_string(&mut source)) {
+        return Err(vec![LevelError::whole(format!("could not read level: {}", e))]);
+    }
+    parse(&source)
+}
+
+pub fn build_terrain(sources: &[TerrainSource]) -> Terrain {
+    let mut terrain = Terrain::empty();
+    for source in sources {
+        match *source {
+            TerrainSource::Rect { x, y, width, height, material } => {
+                terrain.fill(x, y, width, height, material)
+            }
+            TerrainSource::Run { y, from, to, material } => {
+                terrain.fill(from, y, to - from + 1, 1, material)
+            }
+        }
+    }
+    terrain.changed.clear();
+    terrain.repaint = false;
+    terrain.edited = None;
+    terrain
+}
+
+// Just the terrain and level presentation, which is all the editor needs.
+pub fn populate_terrain(world: &mut World, level: &Level) {
+    world.create_entity()
+        .with(Pos { x: 0.0, y: 0.0 })
+        .with(Bounds::Polygon(Box::new(vec![])))
+        .with(CollisionObjectData::new(Layer::Terrain))
+        .with(build_terrain(&level.terrain));
+    world.write_resource::<Wind>().range = level.wind;
+    *world.write_resource::<LevelInfo>() = LevelInfo {
+        background: level.background.clone(),
+        water: level.water,
+    };
+}
+
+pub fn populate(world: &mut World, level: &Level) {
+    populate_terrain(world, level);
+
+    for prop in level.props.iter() {
+        world.create_entity()
+            .with(Pos {
+                x: prop.pos[0],
+                y: prop.pos[1],
+            })
+            .with(prop.bounds.clone())
+            .with(CollisionObjectData::new(Layer::Terrain));
+    }
+
+    if let Some(water) = level.water {
+        world.create_entity()
+            .with(Pos {
+                x: 0.0,
+                y: water + WATER_EXTENT[1] / 2.0,
+            })
+            .with(Bounds::Rectangle(WATER_EXTENT[0], WATER_EXTENT[1]))
+            .with(CollisionObjectData::sensor())
+            .with(Zone::Water(WATER_DAMAGE));
+    }
+    for z in level.kill_zones.iter() {
+        world.create_entity()
+            .with(Pos { x: z[0], y: z[1] })
+            .with(Bounds::Rectangle(z[2], z[3]))
+            .with(CollisionObjectData::sensor())
+            .with(Zone::Kill);
+    }
+
+    for (i, spawn) in level.spawns.iter().enumerate() {
+        let id = i + 1;
+        let mut book = SpellBook::standard();
+        if !level.spells.is_empty() {
+            book = SpellBook::new(book.spells
+                .into_iter()
+                .filter(|s| level.spells.contains(&s.name))
+                .collect());
+        }
+        let mut wizard = world.create_entity();
+        if let Some(&(_, difficulty)) = level.ai.iter().find(|&&(p, _)| p == id as i32) {
+            wizard = wizard.with(AiController::new(id as i32, difficulty));
+        }
+        wizard.with(Name(NAMES[i].to_string()))
+            .with(Health::new(100.0))
+            .with(Score::default())
+            .with(book)
+            .with(Aim::new())
+            .with(Pos {
+                x: spawn[0],
+                y: spawn[1],
+            })
+            .with(Vel { x: 0.0, y: 0.0 })
+            .with(CharacterController::new())
+            .with(Player(id as i32))
+            .with(Sprite::new("wizard", [32.0, 32.0], 4, id as i32))
+            .with(Animation::wizard())
+            .with(Bounds::Rectangle(50.0, 50.0))
+            .with(CollisionObjectData::new(Layer::Players));
+    }
+}
@@ -0,0 +1,53 @@
+use log::{self, LogLevel, LogLevelFilter, LogMetadata, LogRecord, SetLoggerError};
+
+use std::env;
+use std::time::Instant;
+
+// Writes one `key=value` line per record so logs can be grepped or parsed.
+// The level comes from WIZARDS_LOG (error, warn, info, debug, trace), defaulting to info.
+struct GameLogger {
+    level: LogLevelFilter,
+    start: Instant,
+}
+
+impl log::Log for GameLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let elapsed = self.start.elapsed();
+        let t = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        let line = format!("t={:.3} level={} target={} msg=\"{}\"",
+                           t,
+                           record.level(),
+                           record.target(),
+                           record.args());
+        if record.level() <= LogLevel::Warn {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+fn level_from_env() -> LogLevelFilter {
+    env::var("WIZARDS_LOG")
+        .ok()
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(LogLevelFilter::Info)
+}
+
+pub fn init() -> Result<(), SetLoggerError> {
+    let level = level_from_env();
+    log::set_logger(|max| {
+        max.set(level);
+        Box::new(GameLogger {
+            level: level,
+            start: Instant::now(),
+        })
+    })
+}
@@ -1,82 +1,85 @@
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate bincode;
 extern crate piston_window;
 extern crate specs;
 extern crate ncollide;
 extern crate nalgebra;
+extern crate gfx_device_gl;
+extern crate image;
 
 use piston_window::*;
 use piston_window::Button::Keyboard;
 use specs::{DispatcherBuilder, Dispatcher, ReadStorage, System, VecStorage, World, WriteStorage,
             FetchMut, Join};
-use std::collections::HashSet;
-use std::ops::DerefMut;
+use std::collections::{HashMap, HashSet};
 use std::iter::*;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 
 mod systems;
+mod render;
+mod logging;
+mod save;
+mod level;
+mod editor;
+mod snapshot;
+mod input;
+mod net;
+use systems::ai::*;
+use systems::animation::*;
 use systems::assorted::*;
+use systems::character::*;
 use systems::components::*;
 use systems::collision::*;
+use systems::navigation::*;
 use systems::terrain::*;
+use systems::particles::*;
+use systems::spells::*;
+use systems::zones::*;
+use systems::profile::*;
+use systems::query::*;
+use render::Renderer;
+use save::SaveError;
+use level::Level;
+use editor::Editor;
+use net::{Lockstep, Rollback, Session, Simulation, UdpTransport};
+use net::spectate::{Broadcast, Playback, SpectatorClient, SpectatorServer, FAST_FORWARD};
+use snapshot::Snapshot;
 
 struct Game<'a> {
     world: World,
     // TODO: are these lifetimes right?
     dispatcher: Dispatcher<'a, 'a>,
-}
-fn create_terrain(world: &mut World) {
-    world.create_entity()
-        .with(Pos { x: 0.0, y: 0.0 })
-        .with(Bounds::Polygon(Box::new(vec![])))
-        .with(CollisionObjectData::new(3))
-        .with(Terrain::new(200, 400, 500, 100));
-}
-fn create_players(world: &mut World) {
-    world.create_entity()
-        .with(Pos {
-            x: 350.0,
-            y: 100.0,
-        })
-        .with(Vel { x: 0.0, y: 0.0 })
-        .with(Player(1))
-        .with(Bounds::Rectangle(50.0, 50.0))
-        .with(CollisionObjectData::new(1));
-    world.create_entity()
-        .with(Pos {
-            x: 400.0,
-            y: 50.0,
-        })
-        .with(Vel { x: 0.0, y: 0.0 })
-        .with(Bounds::Circle(25.0))
-        .with(Player(2))
-        .with(CollisionObjectData::new(2));
-
-}
-
-fn draw_bounds(bounds : &Bounds, pos : &Pos, c: Context, g: &mut G2d ) {
-    match bounds {
-        &Bounds::Rectangle(x, y) => {
-            rectangle([1.0, 0.0, 0.0, 1.0],
-                      [pos.x - (x / 2.0), pos.y - (y / 2.0), x, y],
-                      c.transform,
-                      g);
-        }
-        &Bounds::Circle(r) => {
-            ellipse([0.0, 0.0, 1.0, 1.0],
-                    [pos.x - r, pos.y - r, 2.0 * r, 2.0 * r],
-                    c.transform,
-                    g);
-        }
-        &Bounds::Polygon(ref ps) => {
-            let ps = Vec::from_iter(ps[..].into_iter().map(|p| [p[0] + pos.x, p[1] + pos.y]));
-
-            polygon([0.0, 1.0, 0.0, 0.5], &ps, c.transform, g)
-        }
-    }
+    profiler: Profiler,
+    keys: HashSet<Button>,
 }
 impl<'a> Game<'a> {
-    fn new() -> Game<'a> {
+    fn empty() -> Game<'a> {
         let mut world = World::new();
         world.add_resource(Delta(0.0));
-        world.add_resource(GameInput(HashSet::new()));
+        world.add_resource(GameInput::default());
+        world.add_resource(AnimationEvents(vec![]));
+        world.add_resource(Particles::new());
+        world.add_resource(Bursts(vec![]));
+        world.add_resource(SpellCasts(vec![]));
+        world.add_resource(LevelInfo::default());
+        world.add_resource(DebugOverlay::default());
+        world.add_resource(CollisionDebug::default());
+        world.add_resource(CollisionSync::new());
+        world.add_resource(CollisionEvents::default());
+        world.add_resource(CollisionLayers::new());
+        world.add_resource(CollisionQuery::default());
+        world.add_resource(NavGraph::new());
+        world.add_resource(TurnState::new(1, 30.0));
+        world.add_resource(Wind {
+            speed: 0.0,
+            range: [-40.0, 40.0],
+        });
 
         world.register::<Pos>();
         world.register::<Vel>();
@@ -84,77 +87,461 @@ impl<'a> Game<'a> {
         world.register::<Player>();
         world.register::<CollisionObjectData>();
         world.register::<Terrain>();
+        world.register::<Sprite>();
+        world.register::<Animation>();
+        world.register::<Emitter>();
+        world.register::<Name>();
+        world.register::<Health>();
+        world.register::<Score>();
+        world.register::<SpellBook>();
+        world.register::<Aim>();
+        world.register::<Projectile>();
+        world.register::<AiController>();
+        world.register::<CharacterController>();
+        world.register::<Zone>();
 
-        create_players(&mut world);
-
-        create_terrain(&mut world);
-
+        let profiler = Profiler::new();
+        let p = &profiler;
         let dispatcher = DispatcherBuilder::new()
-            .add(TerrainSystem, "TerrainSystem", &[])
-            .add(UpdateControlSystem, "ControlSystem", &[])
-            .add(UpdatePositionSystem,
+            .add(Timed::new(TerrainSystem::new(p), "TerrainSystem", p),
+                 "TerrainSystem",
+                 &[])
+            .add(Timed::new(TurnSystem, "TurnSystem", p), "TurnSystem", &[])
+            .add(Timed::new(AiSystem, "AiSystem", p), "AiSystem", &["TurnSystem"])
+            .add(Timed::new(UpdateControlSystem, "ControlSystem", p),
+                 "ControlSystem",
+                 &["AiSystem"])
+            .add(Timed::new(CharacterSystem, "CharacterSystem", p),
+                 "CharacterSystem",
+                 &["AiSystem", "ControlSystem"])
+            .add(Timed::new(SpellSystem, "SpellSystem", p),
+                 "SpellSystem",
+                 &["TurnSystem", "AiSystem", "CharacterSystem"])
+            .add(Timed::new(ProjectileSystem, "ProjectileSystem", p),
+                 "ProjectileSystem",
+                 &["TerrainSystem", "ControlSystem", "CharacterSystem"])
+            .add(Timed::new(NavSystem, "NavSystem", p),
+                 "NavSystem",
+                 &["TerrainSystem", "ProjectileSystem"])
+            .add(Timed::new(UpdatePositionSystem, "UpdatePositionSystem", p),
                  "UpdatePositionSystem",
-                 &["ControlSystem"])
-            .add_thread_local(CollisionSystem::new())
+                 &["ControlSystem", "CharacterSystem", "ProjectileSystem"])
+            .add(Timed::new(SpriteFacingSystem, "SpriteFacingSystem", p),
+                 "SpriteFacingSystem",
+                 &["ControlSystem", "CharacterSystem"])
+            .add(Timed::new(ZoneSystem, "ZoneSystem", p),
+                 "ZoneSystem",
+                 &["ProjectileSystem"])
+            .add(Timed::new(AnimationSystem, "AnimationSystem", p),
+                 "AnimationSystem",
+                 &["UpdatePositionSystem", "SpriteFacingSystem", "SpellSystem",
+                   "ProjectileSystem", "ZoneSystem"])
+            .add(Timed::new(ParticleSystem, "ParticleSystem", p),
+                 "ParticleSystem",
+                 &["TerrainSystem", "AnimationSystem"])
+            .add_thread_local(Timed::new(CollisionSystem::new(p), "CollisionSystem", p))
             .build();
         Game {
             world: world,
             dispatcher: dispatcher,
+            profiler: profiler,
+            keys: HashSet::new(),
         }
     }
-    fn update(&mut self, d: f64) {
+    fn new(level: &Level) -> Game<'a> {
+        let mut game = Game::empty();
+        level::populate(&mut game.world, level);
+        game
+    }
+    // Only the terrain, for the level editor to paint on.
+    fn editing(level: &Level) -> Game<'a> {
+        let mut game = Game::empty();
+        level::populate_terrain(&mut game.world, level);
+        retrace_all(&game.world);
+        game
+    }
+    fn load(path: &Path) -> Result<Game<'a>, SaveError> {
+        let state = try!(save::load(path));
+        let mut game = Game::empty();
+        save::restore(&mut game.world, &state);
+        Ok(game)
+    }
+    fn save(&self, path: &Path) -> Result<(), SaveError> {
+        save::save(&self.world, path)
+    }
+    // Hot seat play, with every player reading the keyboard. Returns what was played.
+    fn update(&mut self, d: f64) -> HashMap<i32, Actions> {
+        let inputs = input::local_actions(&self.keys);
+        self.step(&inputs, d);
+        inputs
+    }
+    fn step(&mut self, inputs: &HashMap<i32, Actions>, d: f64) {
         {
             let mut delta = self.world.write_resource::<Delta>();
             *delta = Delta(d);
+            let mut input = self.world.write_resource::<GameInput>();
+            let last = std::mem::replace(&mut input.actions, inputs.clone());
+            input.last = last;
         }
+        let start = Instant::now();
         self.dispatcher.dispatch(&mut self.world.res);
+        spawn_projectiles(&mut self.world);
         self.world.maintain();
+        self.profiler.end_frame(millis(start.elapsed()));
     }
     fn keypress(&mut self, button: Button) {
-        let mut input_set = self.world.write_resource::<GameInput>();
-        let x = input_set.deref_mut();
-        x.0.insert(button);
+        self.keys.insert(button);
+    }
+    fn toggle_debug(&mut self, key: Key) -> bool {
+        self.world.write_resource::<DebugOverlay>().toggle(key)
     }
     fn keyrelease(&mut self, button: Button) {
-        let mut input_set = self.world.write_resource::<GameInput>();
-        let x = input_set.deref_mut();
-        x.0.remove(&button);
-    }
-    fn render(&self, c: Context, g: &mut G2d) {
-        let pos = &self.world.read::<Pos>();
-        let bounds = &self.world.read::<Bounds>();
-        clear([0.5, 0.5, 0.5, 1.0], g);
-        for (pos, bounds) in (pos, bounds).join() {
-            draw_bounds(bounds, pos, c, g)
+        self.keys.remove(&button);
+    }
+    fn render(&self, renderer: &mut Renderer, c: Context, g: &mut G2d) {
+        renderer.render(&self.world, &self.profiler, c, g);
+    }
+}
+impl<'a> Simulation for Game<'a> {
+    type State = Snapshot;
+    fn step(&mut self, inputs: &HashMap<i32, Actions>, dt: f64) {
+        Game::step(self, inputs, dt);
+    }
+    fn checksum(&self) -> u64 {
+        net::checksum(&self.world)
+    }
+    fn snapshot(&self, previous: Option<&Snapshot>) -> Snapshot {
+        snapshot::capture(&self.world, previous)
+    }
+    fn restore(&mut self, state: &Snapshot) {
+        snapshot::restore(&mut self.world, state);
+    }
+}
+
+const QUICKSAVE: &'static str = "quicksave.wiz";
+const DEFAULT_LEVEL: &'static str = "levels/default.level";
+// How long a spectator waits for the broadcast to say what level it's on.
+const BROADCAST_WAIT_MS: u64 = 5000;
+
+struct Options {
+    level: String,
+    // Local address, peer address and the player this peer controls.
+    net: Option<(String, String, i32)>,
+    // Predict and roll back instead of waiting on remote input.
+    rollback: bool,
+    // Stream the match to spectators connecting to this address.
+    broadcast: Option<String>,
+    // Watch the match broadcast from this address.
+    spectate: Option<String>,
+    // Players to hand to the computer, on top of any the level names.
+    ai: Vec<(i32, Difficulty)>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        level: DEFAULT_LEVEL.to_string(),
+        net: None,
+        rollback: false,
+        broadcast: None,
+        spectate: None,
+        ai: vec![],
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--net" => {
+                let local = try!(args.next().ok_or("--net needs a local address"));
+                let peer = try!(args.next().ok_or("--net needs the peer's address"));
+                let player = try!(args.next()
+                    .and_then(|p| p.parse().ok())
+                    .ok_or("--net needs the player to control"));
+                options.net = Some((local, peer, player));
+            }
+            "--rollback" => options.rollback = true,
+            "--broadcast" => {
+                options.broadcast = Some(try!(args.next().ok_or("--broadcast needs an address")));
+            }
+            "--spectate" => {
+                options.spectate = Some(try!(args.next().ok_or("--spectate needs an address")));
+            }
+            "--ai" => {
+                let player = try!(args.next()
+                    .and_then(|p| p.parse().ok())
+                    .ok_or("--ai needs the player to hand over"));
+                let difficulty = try!(args.next()
+                    .and_then(|d| level::parse_difficulty(&d))
+                    .ok_or("--ai needs a difficulty: easy, normal or hard"));
+                options.ai.push((player, difficulty));
+            }
+            _ => options.level = arg,
         }
-        for col in (&self.world.read::<CollisionObjectData>())
-            .join()
-            .flat_map(|c| c.contacts.values().flat_map(|v| v)) {
-            let r = 10.0;
-            ellipse([0.0, 1.0, 1.0, 1.0],
-                    [col[0] - r, col[1] - r, 2.0 * r, 2.0 * r],
-                    c.transform,
-                    g);
+    }
+    Ok(options)
+}
+
+// Connects to a broadcast and waits for the level it started from. Any steps that came
+// with it are already queued in the playback.
+fn join_broadcast(addr: &str) -> Result<(SpectatorClient, Level, Playback), String> {
+    let mut client = try!(SpectatorClient::connect(addr)
+        .map_err(|e| format!("could not join broadcast addr={} error=\"{}\"", addr, e)));
+    let mut playback = Playback::new();
+    let mut level = None;
+    for _ in 0..BROADCAST_WAIT_MS / 10 {
+        for message in client.poll() {
+            match message {
+                Broadcast::Start { level: source } => {
+                    let parsed = try!(level::parse(&source).map_err(|errors| {
+                        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                        format!("broadcast sent a bad level errors=\"{}\"", errors.join("; "))
+                    }));
+                    level = Some(parsed);
+                }
+                Broadcast::Step { dt, inputs } => playback.push(dt, inputs),
+            }
+        }
+        if let Some(level) = level.take() {
+            return Ok((client, level, playback));
         }
+        if client.closed() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
     }
+    Err(format!("broadcast never sent a level addr={}", addr))
 }
 
 fn main() {
+    if let Err(e) = logging::init() {
+        println!("Could not start logging: {}", e);
+    }
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    let level_path = options.level;
+    let (mut level, mut spectator) = match options.spectate {
+        Some(ref addr) => {
+            match join_broadcast(addr) {
+                Ok((client, level, playback)) => {
+                    info!("spectating addr={}", addr);
+                    (level, Some((client, playback)))
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            }
+        }
+        None => {
+            match level::load(Path::new(&level_path)) {
+                Ok(mut level) => {
+                    for &(player, difficulty) in options.ai.iter() {
+                        if player < 1 || player as usize > level.spawns.len() {
+                            warn!("no spawn point for ai player={}", player);
+                            continue;
+                        }
+                        level.ai.retain(|&(p, _)| p != player);
+                        level.ai.push((player, difficulty));
+                    }
+                    (level, None)
+                }
+                Err(errors) => {
+                    for e in errors {
+                        error!("{}:{}", level_path, e);
+                    }
+                    return;
+                }
+            }
+        }
+    };
+
+    // Spectators only watch, so only need the broadcast to have checked the level.
+    let players = if spectator.is_some() {
+        vec![]
+    } else {
+        match input::players(&level, options.net.as_ref().map(|n| n.2)) {
+            Ok(players) => players,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        }
+    };
+
+    let mut session: Option<Box<Session<Game>>> = match options.net {
+        Some((local, peer, player)) => {
+            match UdpTransport::bind(&*local, &*peer) {
+                Ok(transport) => {
+                    info!("started network session local={} peer={} player={}",
+                          local,
+                          peer,
+                          player);
+                    let session: Box<Session<Game>> = if options.rollback {
+                        Box::new(Rollback::new(transport,
+                                               player,
+                                               players,
+                                               net::ROLLBACK_INPUT_DELAY))
+                    } else {
+                        Box::new(Lockstep::new(transport, player, players, net::INPUT_DELAY))
+                    };
+                    Some(session)
+                }
+                Err(e) => {
+                    error!("could not open socket local={} error=\"{}\"", local, e);
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
+    let mut broadcast = match options.broadcast {
+        Some(ref addr) => {
+            match SpectatorServer::bind(&**addr, &level::write(&level)) {
+                Ok(server) => {
+                    info!("broadcasting addr={}", addr);
+                    Some(server)
+                }
+                Err(e) => {
+                    error!("could not start broadcast addr={} error=\"{}\"", addr, e);
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
+    // Editing or loading would desync a network game and can't be broadcast.
+    let locked = session.is_some() || broadcast.is_some() || spectator.is_some();
+
     let mut window: PistonWindow =
         WindowSettings::new("Hello Piston!", [700, 500]).exit_on_esc(true).build().unwrap();
-
-    let mut game = Game::new();
+    let mut game = Game::new(&level);
+    let mut renderer = Renderer::new(&mut window.factory);
+    let mut editor: Option<Editor> = None;
 
     while let Some(e) = window.next() {
         match e {
             Input::Update(UpdateArgs { dt: delta }) => {
-                game.update(delta);
+                if let Some((ref mut client, ref mut playback)) = spectator {
+                    for message in client.poll() {
+                        if let Broadcast::Step { dt, inputs } = message {
+                            playback.push(dt, inputs);
+                        }
+                    }
+                    playback.update(&mut game, delta);
+                } else if let Some(ref mut session) = session {
+                    // Whichever player we are, we play with the first layout.
+                    let local = input::actions(&game.keys, 1);
+                    session.update(&mut game, delta, local);
+                    // Only what every peer agrees on goes out; predictions might be undone.
+                    for inputs in session.settled() {
+                        if let Some(ref mut broadcast) = broadcast {
+                            broadcast.record(net::TICK, &inputs);
+                        }
+                    }
+                } else if editor.is_none() {
+                    let inputs = game.update(delta);
+                    if let Some(ref mut broadcast) = broadcast {
+                        broadcast.record(delta, &inputs);
+                    }
+                }
+                if let Some(ref mut broadcast) = broadcast {
+                    broadcast.update();
+                }
             }
             Input::Render(_) => {
+                renderer.update(&game.world, &mut window.factory, &mut window.encoder);
                 window.draw_2d(&e, |c, mut g| {
-                    game.render(c, &mut g);
+                    game.render(&mut renderer, c, &mut g);
+                    if let Some(ref editor) = editor {
+                        renderer.render_editor(editor, &game.world, c, &mut g);
+                    }
                 });
             }
+            Input::Press(Keyboard(Key::F10)) if !locked => {
+                match editor.take() {
+                    Some(mut editing) => {
+                        match editing.playable(&game.world) {
+                            Some(playable) => {
+                                level = playable;
+                                game = Game::new(&level);
+                                renderer.reset();
+                            }
+                            None => editor = Some(editing),
+                        }
+                    }
+                    None => {
+                        game = Game::editing(&level);
+                        editor = Some(Editor::new(level.clone(), Path::new(&level_path)));
+                        renderer.reset();
+                    }
+                }
+            }
+            Input::Move(Motion::MouseCursor(x, y)) => {
+                if let Some(ref mut editor) = editor {
+                    editor.cursor_moved(&game.world, x, y);
+                }
+            }
+            Input::Press(button) if editor.is_some() => {
+                if let Some(ref mut editor) = editor {
+                    editor.press(&game.world, button);
+                }
+            }
+            Input::Release(button) if editor.is_some() => {
+                if let Some(ref mut editor) = editor {
+                    editor.release(&game.world, button);
+                }
+            }
+            Input::Press(Keyboard(key)) if game.toggle_debug(key) => {}
+            Input::Press(Keyboard(Key::Tab)) => {
+                renderer.scoreboard = true;
+            }
+            Input::Release(Keyboard(Key::Tab)) => {
+                renderer.scoreboard = false;
+            }
+            Input::Press(Keyboard(Key::F6)) => {
+                renderer.profiler = !renderer.profiler;
+            }
+            Input::Press(Keyboard(Key::F8)) => {
+                match game.save(Path::new(QUICKSAVE)) {
+                    Ok(()) => info!("saved game path={}", QUICKSAVE),
+                    Err(e) => warn!("could not save game error=\"{}\"", e),
+                }
+            }
+            Input::Press(Keyboard(Key::F9)) if !locked => {
+                match Game::load(Path::new(QUICKSAVE)) {
+                    Ok(loaded) => {
+                        info!("loaded game path={}", QUICKSAVE);
+                        game = loaded;
+                        renderer.reset();
+                    }
+                    Err(e) => warn!("could not load game error=\"{}\"", e),
+                }
+            }
+            Input::Press(Keyboard(Key::F7)) => {
+                match game.profiler.dump(Path::new("profile.csv")) {
+                    Ok(()) => info!("wrote frame profile path=profile.csv"),
+                    Err(e) => warn!("could not write frame profile error=\"{}\"", e),
+                }
+            }
+            // Spectators steer the playback, not the wizards.
+            Input::Press(Keyboard(key)) if spectator.is_some() => {
+                if let Some((_, ref mut playback)) = spectator {
+                    match key {
+                        Key::Space => playback.paused = !playback.paused,
+                        Key::Right => playback.speed = FAST_FORWARD,
+                        Key::End => playback.skip_to_live(&mut game),
+                        _ => {}
+                    }
+                }
+            }
+            Input::Release(Keyboard(Key::Right)) if spectator.is_some() => {
+                if let Some((_, ref mut playback)) = spectator {
+                    playback.speed = 1.0;
+                }
+            }
             Input::Press(button) => {
                 game.keypress(button);
             }
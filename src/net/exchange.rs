@@ -0,0 +1,158 @@
+use bincode::{self, Infinite};
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use systems::components::*;
+
+use super::transport::Transport;
+
+// How many of our most recent inputs go in every packet. This must be more than the
+// furthest a session lets us get ahead of a peer, so a peer that lost packets always
+// gets what it is waiting on again.
+pub const REDUNDANCY: usize = 16;
+const CHECKSUMS_SENT: usize = 4;
+const CHECKSUMS_KEPT: u64 = 16;
+pub const CHECKSUM_INTERVAL: u64 = 30;
+
+#[derive(Serialize, Deserialize)]
+struct Packet {
+    player: i32,
+    // Tick of the first entry in `inputs`; the rest follow on consecutively.
+    first: u64,
+    inputs: Vec<Actions>,
+    checksums: Vec<(u64, u64)>,
+}
+
+// The traffic every kind of session has in common: sending our latest inputs and
+// checksums each frame, taking in the peers', and comparing checksums.
+pub struct Exchange<T: Transport> {
+    transport: T,
+    local: i32,
+    players: Vec<i32>,
+    sent: VecDeque<(u64, Actions)>,
+    checksums: BTreeMap<u64, u64>,
+    remote_checksums: BTreeMap<u64, HashMap<i32, u64>>,
+    // The latest tick whose checksum has been compared with a peer's.
+    verified: u64,
+    desync: Option<u64>,
+}
+
+impl<T: Transport> Exchange<T> {
+    pub fn new(transport: T, local: i32, players: Vec<i32>) -> Exchange<T> {
+        Exchange {
+            transport: transport,
+            local: local,
+            players: players,
+            sent: VecDeque::new(),
+            checksums: BTreeMap::new(),
+            remote_checksums: BTreeMap::new(),
+            verified: 0,
+            desync: None,
+        }
+    }
+
+    pub fn local(&self) -> i32 {
+        self.local
+    }
+
+    pub fn players(&self) -> &[i32] {
+        &self.players
+    }
+
+    pub fn verified(&self) -> u64 {
+        self.verified
+    }
+
+    // The first tick whose checksums disagreed.
+    pub fn desync(&self) -> Option<u64> {
+        self.desync
+    }
+
+    // Queues our input for `tick`; ticks must be added in order.
+    pub fn add_input(&mut self, tick: u64, actions: Actions) {
+        self.sent.push_back((tick, actions));
+        if self.sent.len() > REDUNDANCY {
+            self.sent.pop_front();
+        }
+    }
+
+    // Our checksum of the state at the start of `tick`, once that can no longer change.
+    pub fn add_checksum(&mut self, tick: u64, sum: u64) {
+        self.checksums.insert(tick, sum);
+        let horizon = tick.saturating_sub(CHECKSUM_INTERVAL * CHECKSUMS_KEPT);
+        self.checksums = self.checksums.split_off(&horizon);
+        self.remote_checksums = self.remote_checksums.split_off(&horizon);
+        self.verify(tick);
+    }
+
+    fn verify(&mut self, tick: u64) {
+        let local = match self.checksums.get(&tick) {
+            Some(&sum) => sum,
+            None => return,
+        };
+        if let Some(remote) = self.remote_checksums.remove(&tick) {
+            self.verified = self.verified.max(tick);
+            for (player, sum) in remote {
+                if sum != local && self.desync.is_none() {
+                    error!("desync tick={} player={} local={:016x} remote={:016x}",
+                           tick,
+                           player,
+                           local,
+                           sum);
+                    self.desync = Some(tick);
+                }
+            }
+        }
+    }
+
+    pub fn send(&mut self) {
+        let packet = Packet {
+            player: self.local,
+            first: self.sent.front().map_or(0, |&(tick, _)| tick),
+            inputs: self.sent.iter().map(|&(_, a)| a).collect(),
+            checksums: self.checksums
+                .iter()
+                .rev()
+                .take(CHECKSUMS_SENT)
+                .map(|(&tick, &sum)| (tick, sum))
+                .collect(),
+        };
+        match bincode::serialize(&packet, Infinite) {
+            Ok(data) => self.transport.send(&data),
+            Err(e) => warn!("could not encode packet error=\"{}\"", e),
+        }
+    }
+
+    // Hands every remote input that arrived to `input` as (player, tick, actions).
+    // Inputs are resent many times over, so expect to see each one repeatedly.
+    pub fn receive<F>(&mut self, mut input: F)
+        where F: FnMut(i32, u64, Actions)
+    {
+        while let Some(data) = self.transport.receive() {
+            let packet: Packet = match bincode::deserialize(&data) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    warn!("dropped malformed packet error=\"{}\"", e);
+                    continue;
+                }
+            };
+            if packet.player == self.local || !self.players.contains(&packet.player) {
+                debug!("dropped packet from unexpected player={}", packet.player);
+                continue;
+            }
+            for (i, &actions) in packet.inputs.iter().enumerate() {
+                input(packet.player, packet.first + i as u64, actions);
+            }
+            let oldest = self.checksums.keys().next().cloned().unwrap_or(0);
+            for &(tick, sum) in packet.checksums.iter() {
+                if tick >= oldest {
+                    self.remote_checksums
+                        .entry(tick)
+                        .or_insert_with(HashMap::new)
+                        .insert(packet.player, sum);
+                    self.verify(tick);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::{BTreeMap, HashMap};
+
+use systems::components::*;
+
+use super::*;
+
+// Each peer simulates tick n only once it has every player's input for tick n. Local
+// input is scheduled `delay` ticks ahead, which hides the round trip as long as it is
+// shorter than the delay; otherwise the game stalls until the input arrives.
+pub struct Lockstep<T: Transport> {
+    exchange: Exchange<T>,
+    delay: u64,
+    tick: u64,
+    // The first tick we haven't scheduled local input for.
+    scheduled: u64,
+    inputs: BTreeMap<u64, HashMap<i32, Actions>>,
+    settled: Vec<HashMap<i32, Actions>>,
+    accumulator: f64,
+}
+
+impl<T: Transport> Lockstep<T> {
+    pub fn new(transport: T, local: i32, players: Vec<i32>, delay: u64) -> Lockstep<T> {
+        assert!((delay as usize) < REDUNDANCY);
+        Lockstep {
+            inputs: empty_inputs(&players, delay),
+            exchange: Exchange::new(transport, local, players),
+            delay: delay,
+            tick: 0,
+            scheduled: delay,
+            settled: vec![],
+            accumulator: 0.0,
+        }
+    }
+
+    fn schedule(&mut self, local: Actions) {
+        while self.scheduled <= self.tick + self.delay {
+            let tick = self.scheduled;
+            self.inputs
+                .entry(tick)
+                .or_insert_with(HashMap::new)
+                .insert(self.exchange.local(), local);
+            self.exchange.add_input(tick, local);
+            self.scheduled += 1;
+        }
+    }
+
+    fn receive(&mut self) {
+        let (inputs, current) = (&mut self.inputs, self.tick);
+        self.exchange.receive(|player, tick, actions| {
+            if tick >= current {
+                inputs.entry(tick).or_insert_with(HashMap::new).insert(player, actions);
+            }
+        });
+    }
+}
+
+impl<T: Transport, S: Simulation> Session<S> for Lockstep<T> {
+    fn update(&mut self, sim: &mut S, dt: f64, local: Actions) {
+        self.receive();
+        if self.exchange.desync().is_none() {
+            self.accumulator = (self.accumulator + dt).min(MAX_CATCH_UP);
+            while self.accumulator >= TICK {
+                self.schedule(local);
+                let ready = {
+                    let players = self.exchange.players();
+                    self.inputs
+                        .get(&self.tick)
+                        .map_or(false, |i| players.iter().all(|p| i.contains_key(p)))
+                };
+                if !ready {
+                    trace!("stalled tick={}", self.tick);
+                    break;
+                }
+                let inputs = self.inputs.remove(&self.tick).unwrap_or_default();
+                sim.step(&inputs, TICK);
+                self.settled.push(inputs);
+                self.tick += 1;
+                self.accumulator -= TICK;
+                if self.tick % CHECKSUM_INTERVAL == 0 {
+                    self.exchange.add_checksum(self.tick, sim.checksum());
+                }
+            }
+        }
+        self.exchange.send();
+    }
+
+    fn idle(&mut self, _: &mut S) {
+        self.receive();
+        self.exchange.send();
+    }
+
+    fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    fn settled(&mut self) -> Vec<HashMap<i32, Actions>> {
+        self.settled.drain(..).collect()
+    }
+
+    fn verified(&self) -> u64 {
+        self.exchange.verified()
+    }
+
+    fn desync(&self) -> Option<u64> {
+        self.exchange.desync()
+    }
+}
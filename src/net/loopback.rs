@@ -0,0 +1,336 @@
+// Two peers and a spectator run in one process, to check that they stay in step.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+use systems::components::*;
+
+use super::*;
+use super::spectate::*;
+
+type Queue = Rc<RefCell<VecDeque<Vec<u8>>>>;
+
+// An in-process pair of queues, for running two peers in one process.
+struct LoopbackTransport {
+    outgoing: Queue,
+    incoming: Queue,
+}
+
+impl LoopbackTransport {
+    fn pair() -> (LoopbackTransport, LoopbackTransport) {
+        let a: Queue = Rc::new(RefCell::new(VecDeque::new()));
+        let b: Queue = Rc::new(RefCell::new(VecDeque::new()));
+        (LoopbackTransport {
+            outgoing: a.clone(),
+            incoming: b.clone(),
+        },
+         LoopbackTransport {
+            outgoing: b,
+            incoming: a,
+        })
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&mut self, packet: &[u8]) {
+        self.outgoing.borrow_mut().push_back(packet.to_vec());
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.incoming.borrow_mut().pop_front()
+    }
+}
+
+// Holds every packet back for a number of sends, standing in for network latency. Sessions
+// send once a frame, so the delay is in frames.
+struct Delayed<T: Transport> {
+    inner: T,
+    delay: u64,
+    sends: u64,
+    pending: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl<T: Transport> Delayed<T> {
+    fn new(inner: T, delay: u64) -> Delayed<T> {
+        Delayed {
+            inner: inner,
+            delay: delay,
+            sends: 0,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<T: Transport> Transport for Delayed<T> {
+    fn send(&mut self, packet: &[u8]) {
+        self.pending.push_back((self.sends + self.delay, packet.to_vec()));
+        while self.pending.front().map_or(false, |&(due, _)| due <= self.sends) {
+            if let Some((_, packet)) = self.pending.pop_front() {
+                self.inner.send(&packet);
+            }
+        }
+        self.sends += 1;
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.inner.receive()
+    }
+}
+
+// Drops every `nth` packet, so runs exercise the resending of inputs.
+struct Lossy<T: Transport> {
+    inner: T,
+    nth: usize,
+    sent: usize,
+}
+
+impl<T: Transport> Transport for Lossy<T> {
+    fn send(&mut self, packet: &[u8]) {
+        self.sent += 1;
+        if self.nth == 0 || self.sent % self.nth != 0 {
+            self.inner.send(packet);
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        self.inner.receive()
+    }
+}
+
+// Made up input: random actions, each held for a random number of ticks.
+struct Script {
+    state: u64,
+    actions: Actions,
+    hold: u32,
+}
+
+impl Script {
+    fn new(seed: u64) -> Script {
+        Script {
+            state: seed,
+            actions: Actions::default(),
+            hold: 0,
+        }
+    }
+
+    fn random(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    fn next(&mut self) -> Actions {
+        if self.hold == 0 {
+            self.actions = Actions(self.random() as u8);
+            self.hold = 10 + (self.random() % 50) as u32;
+        }
+        self.hold -= 1;
+        self.actions
+    }
+}
+
+// The network between the two peers.
+struct Conditions {
+    drop_every: usize,
+    latency: u64,
+    rollback: bool,
+}
+
+fn session<S>(transport: LoopbackTransport,
+              local: i32,
+              conditions: &Conditions)
+              -> Box<Session<S>>
+    where S: Simulation + 'static
+{
+    let transport = Lossy {
+        inner: Delayed::new(transport, conditions.latency),
+        nth: conditions.drop_every,
+        sent: 0,
+    };
+    let players = vec![1, 2];
+    if conditions.rollback {
+        Box::new(Rollback::new(transport, local, players, ROLLBACK_INPUT_DELAY))
+    } else {
+        Box::new(Lockstep::new(transport, local, players, INPUT_DELAY))
+    }
+}
+
+// Runs two peers in this process for at least `ticks` ticks of scripted input. Returns
+// the last tick both agreed on, or the tick they desynced on.
+fn run<S>(a: &mut S, b: &mut S, ticks: u64, conditions: &Conditions) -> Result<u64, u64>
+    where S: Simulation + 'static
+{
+    let (ta, tb) = LoopbackTransport::pair();
+    let mut la = session(ta, 1, conditions);
+    let mut lb = session(tb, 2, conditions);
+    // Round up so that both peers take a checksum on the final tick.
+    let ticks = (ticks + CHECKSUM_INTERVAL - 1) / CHECKSUM_INTERVAL * CHECKSUM_INTERVAL;
+    let (mut sa, mut sb) = (Script::new(0x9E3779B97F4A7C15), Script::new(0xD1B54A32D192ED03));
+    // Input only changes when a peer actually advances, as it would with a held key.
+    let mut last: HashMap<i32, (u64, Actions)> = HashMap::new();
+    let mut frames = 0;
+    while la.verified() < ticks || lb.verified() < ticks {
+        // A peer that has finished keeps answering until the other has caught up.
+        if la.tick() < ticks {
+            let next_a = next_input(&mut last, 1, la.tick(), &mut sa);
+            la.update(a, TICK, next_a);
+        } else {
+            la.idle(a);
+        }
+        if lb.tick() < ticks {
+            let next_b = next_input(&mut last, 2, lb.tick(), &mut sb);
+            lb.update(b, TICK, next_b);
+        } else {
+            lb.idle(b);
+        }
+        if let Some(tick) = la.desync().or(lb.desync()) {
+            return Err(tick);
+        }
+        frames += 1;
+        if frames > ticks * 4 + conditions.latency * 10 + 100 {
+            error!("loopback run stopped making progress a={} b={}", la.tick(), lb.tick());
+            return Err(la.verified().min(lb.verified()));
+        }
+    }
+    info!("loopback run finished ticks={} frames={}", ticks, frames);
+    Ok(ticks)
+}
+
+fn next_input(last: &mut HashMap<i32, (u64, Actions)>,
+              player: i32,
+              tick: u64,
+              script: &mut Script)
+              -> Actions {
+    let entry = last.entry(player).or_insert((u64::max_value(), Actions::default()));
+    if entry.0 != tick {
+        *entry = (tick, script.next());
+    }
+    entry.1
+}
+
+// Broadcasts `ticks` ticks of scripted play from `live` over a local TCP socket, with a
+// spectator joining halfway through and playing back into `watcher` a tick a frame, as
+// the game would. Returns the live tick the spectator caught up on, or Err if it never
+// did or the two ended up different.
+fn spectate<S: Simulation>(live: &mut S, watcher: &mut S, ticks: u64) -> Result<u64, u64> {
+    let mut server = match SpectatorServer::bind("127.0.0.1:0", "") {
+        Ok(server) => server,
+        Err(e) => {
+            error!("could not start broadcast error=\"{}\"", e);
+            return Err(0);
+        }
+    };
+    let addr = match server.local_addr() {
+        Ok(addr) => addr,
+        Err(_) => return Err(0),
+    };
+    let mut scripts = [Script::new(0x9E3779B97F4A7C15), Script::new(0xD1B54A32D192ED03)];
+    let mut client = None;
+    let mut playback = Playback::new();
+    let mut caught_up = None;
+    for tick in 0..ticks {
+        let inputs: HashMap<i32, Actions> =
+            scripts.iter_mut().enumerate().map(|(i, s)| (i as i32 + 1, s.next())).collect();
+        live.step(&inputs, TICK);
+        server.record(TICK, &inputs);
+        if tick == ticks / 2 {
+            client = SpectatorClient::connect(addr).ok();
+        }
+        server.update();
+        if let Some(ref mut client) = client {
+            receive(client, &mut playback);
+            playback.update(watcher, TICK);
+            if caught_up.is_none() && playback.live() {
+                caught_up = Some(tick);
+            }
+        }
+    }
+    let caught_up = match caught_up {
+        Some(tick) => tick,
+        None => {
+            error!("spectator never caught up behind={}", playback.behind());
+            return Err(ticks);
+        }
+    };
+    // Let the last few steps through the socket.
+    for _ in 0..1000 {
+        server.update();
+        if let Some(ref mut client) = client {
+            receive(client, &mut playback);
+        }
+        playback.update(watcher, TICK);
+        if playback.behind() == 0 && live.checksum() == watcher.checksum() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    let (a, b) = (live.checksum(), watcher.checksum());
+    if a == b {
+        info!("spectator caught up tick={} spectators={}", caught_up, server.spectators());
+        Ok(caught_up)
+    } else {
+        error!("spectator diverged live={:016x} spectator={:016x}", a, b);
+        Err(ticks)
+    }
+}
+
+fn receive(client: &mut SpectatorClient, playback: &mut Playback) {
+    for message in client.poll() {
+        if let Broadcast::Step { dt, inputs } = message {
+            playback.push(dt, inputs);
+        }
+    }
+}
+
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use level;
+    use Game;
+
+    const TICKS: u64 = 300;
+
+    fn games() -> (Game<'static>, Game<'static>) {
+        let level = level::load(Path::new("levels/default.level")).ok().unwrap();
+        (Game::new(&level), Game::new(&level))
+    }
+
+    #[test]
+    fn lockstep_peers_agree() {
+        let (mut a, mut b) = games();
+        let conditions = Conditions {
+            drop_every: 7,
+            latency: 0,
+            rollback: false,
+        };
+        assert!(run(&mut a, &mut b, TICKS, &conditions).is_ok());
+        assert_eq!(a.checksum(), b.checksum());
+    }
+
+    #[test]
+    fn rollback_peers_agree_under_latency() {
+        let (mut a, mut b) = games();
+        let conditions = Conditions {
+            drop_every: 7,
+            latency: 6,
+            rollback: true,
+        };
+        assert!(run(&mut a, &mut b, TICKS, &conditions).is_ok());
+    }
+
+    #[test]
+    fn late_spectator_fast_forwards_to_live() {
+        let (mut live, mut watcher) = games();
+        let joined = TICKS / 2;
+        let caught_up = spectate(&mut live, &mut watcher, TICKS).unwrap();
+        // At normal speed a late joiner would stay `joined` ticks behind for good.
+        let frames = caught_up - joined;
+        assert!((frames as f64) < joined as f64 / (FAST_FORWARD - 1.0) + 10.0,
+                "took {} frames to catch up",
+                frames);
+    }
+}
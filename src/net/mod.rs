@@ -0,0 +1,124 @@
+use specs::{Join, World};
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hasher;
+
+use systems::components::*;
+
+pub mod transport;
+pub mod exchange;
+pub mod lockstep;
+pub mod rollback;
+#[cfg(test)]
+mod loopback;
+pub mod spectate;
+
+pub use self::transport::*;
+pub use self::exchange::*;
+pub use self::lockstep::Lockstep;
+pub use self::rollback::Rollback;
+
+// While networked the simulation only ever advances by this much, so every peer
+// integrates exactly the same steps whatever its frame rate.
+pub const TICK: f64 = 1.0 / 60.0;
+pub const INPUT_DELAY: u64 = 4;
+// Rollback hides latency itself, so it only needs enough delay to cover a frame or two.
+pub const ROLLBACK_INPUT_DELAY: u64 = 2;
+// After a stall, catch up at most this many ticks in one frame.
+const MAX_CATCH_UP: f64 = 4.0 * TICK;
+
+// The deterministic part of the game that a session drives.
+pub trait Simulation {
+    type State;
+    fn step(&mut self, inputs: &HashMap<i32, Actions>, dt: f64);
+    fn checksum(&self) -> u64;
+    // `previous` is the last state taken, which may share unchanged parts with this one.
+    fn snapshot(&self, previous: Option<&Self::State>) -> Self::State;
+    fn restore(&mut self, state: &Self::State);
+}
+
+// A way of keeping a simulation in step with remote peers.
+pub trait Session<S: Simulation> {
+    // Advances by up to `dt`, with `local` as our input for any tick scheduled meanwhile.
+    fn update(&mut self, sim: &mut S, dt: f64, local: Actions);
+    // Takes in packets and settles what they decide, without starting any new ticks.
+    fn idle(&mut self, sim: &mut S);
+    fn tick(&self) -> u64;
+    // The inputs of every tick that has become final since the last call, oldest first.
+    fn settled(&mut self) -> Vec<HashMap<i32, Actions>>;
+    // The latest tick whose checksum a peer has confirmed.
+    fn verified(&self) -> u64;
+    // The first tick whose checksums disagreed. Sessions stop advancing after one.
+    fn desync(&self) -> Option<u64>;
+}
+
+// Nobody can have sent input for the first `delay` ticks, so they're empty for all.
+fn empty_inputs(players: &[i32], delay: u64) -> BTreeMap<u64, HashMap<i32, Actions>> {
+    (0..delay)
+        .map(|tick| (tick, players.iter().map(|&p| (p, Actions::default())).collect()))
+        .collect()
+}
+
+// FNV-1a, so every process hashes the same state to the same value.
+struct Fnv(u64);
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+// A hash of everything the simulation's outcome depends on. Cosmetic state such as
+// particles and animation frames is left out.
+pub fn checksum(world: &World) -> u64 {
+    let mut h = Fnv(0xcbf29ce484222325);
+    let (entities, pos, vel) = (world.entities(), world.read::<Pos>(), world.read::<Vel>());
+    let (health, aim, spells, terrain) = (world.read::<Health>(),
+                                          world.read::<Aim>(),
+                                          world.read::<SpellBook>(),
+                                          world.read::<Terrain>());
+    for (e, pos) in (&*entities, &pos).join() {
+        h.write_u32(e.id());
+        h.write_u64(pos.x.to_bits());
+        h.write_u64(pos.y.to_bits());
+        if let Some(vel) = vel.get(e) {
+            h.write_u64(vel.x.to_bits());
+            h.write_u64(vel.y.to_bits());
+        }
+        if let Some(health) = health.get(e) {
+            h.write_u64(health.current.to_bits());
+        }
+        if let Some(aim) = aim.get(e) {
+            h.write_u64(aim.angle.to_bits());
+            h.write_u64(aim.power.to_bits());
+        }
+        if let Some(book) = spells.get(e) {
+            h.write_usize(book.active);
+        }
+        if let Some(terrain) = terrain.get(e) {
+            // The set iterates in a different order on every peer, so the cells are
+            // hashed one by one and summed.
+            let cells = terrain.points.iter().fold(0u64, |sum, p| {
+                let mut cell = Fnv(0xcbf29ce484222325);
+                cell.write_usize(p[0]);
+                cell.write_usize(p[1]);
+                sum.wrapping_add(cell.finish())
+            });
+            h.write_usize(terrain.points.len());
+            h.write_u64(cells);
+        }
+    }
+    let turn = world.read_resource::<TurnState>();
+    h.write_u32(turn.turn);
+    h.write_i32(turn.active);
+    h.write_u64(turn.time_left.to_bits());
+    h.write_u64(world.read_resource::<Wind>().speed.to_bits());
+    h.finish()
+}
@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use systems::components::*;
+
+use super::*;
+
+// How far we let the simulation run ahead of the last tick every player's input is
+// known for. Beyond this we stall, as lockstep would.
+pub const MAX_ROLLBACK: u64 = 8;
+
+struct Frame<State> {
+    tick: u64,
+    // The simulation at the start of `tick`.
+    state: State,
+    // What `tick` was simulated with, guesses included.
+    inputs: HashMap<i32, Actions>,
+}
+
+// Never waits for remote input: a player whose input hasn't arrived is assumed to still
+// be doing what they last did. When their real input turns out different, the
+// simulation is restored to the tick it arrived for and simulated forward again.
+pub struct Rollback<S: Simulation, T: Transport> {
+    exchange: Exchange<T>,
+    delay: u64,
+    tick: u64,
+    // The first tick we haven't scheduled local input for.
+    scheduled: u64,
+    // Real inputs, local and remote.
+    inputs: BTreeMap<u64, HashMap<i32, Actions>>,
+    // Every tick before this one has all its real inputs.
+    confirmed: u64,
+    // One frame per tick from `confirmed` on.
+    frames: VecDeque<Frame<S::State>>,
+    // The earliest tick found to have been simulated with a wrong guess.
+    mispredicted: Option<u64>,
+    // Checksums of states that could still be rolled back.
+    checksums: BTreeMap<u64, u64>,
+    settled: Vec<HashMap<i32, Actions>>,
+    accumulator: f64,
+}
+
+impl<S: Simulation, T: Transport> Rollback<S, T> {
+    pub fn new(transport: T, local: i32, players: Vec<i32>, delay: u64) -> Rollback<S, T> {
+        assert!(((delay + MAX_ROLLBACK) as usize) < REDUNDANCY);
+        Rollback {
+            inputs: empty_inputs(&players, delay),
+            exchange: Exchange::new(transport, local, players),
+            delay: delay,
+            tick: 0,
+            scheduled: delay,
+            confirmed: 0,
+            frames: VecDeque::new(),
+            mispredicted: None,
+            checksums: BTreeMap::new(),
+            settled: vec![],
+            accumulator: 0.0,
+        }
+    }
+
+    fn schedule(&mut self, local: Actions) {
+        while self.scheduled <= self.tick + self.delay {
+            let tick = self.scheduled;
+            self.inputs
+                .entry(tick)
+                .or_insert_with(HashMap::new)
+                .insert(self.exchange.local(), local);
+            self.exchange.add_input(tick, local);
+            self.scheduled += 1;
+        }
+    }
+
+    // The real input if we have it, otherwise the latest one we have before `tick`.
+    fn input_for(&self, player: i32, tick: u64) -> Actions {
+        self.inputs
+            .range(..tick + 1)
+            .rev()
+            .filter_map(|(_, i)| i.get(&player).cloned())
+            .next()
+            .unwrap_or_default()
+    }
+
+    fn receive(&mut self) {
+        let (inputs, frames, mispredicted, confirmed) =
+            (&mut self.inputs, &self.frames, &mut self.mispredicted, self.confirmed);
+        self.exchange.receive(|player, tick, actions| {
+            if tick < confirmed || inputs.get(&tick).map_or(false, |i| i.contains_key(&player)) {
+                return;
+            }
+            inputs.entry(tick).or_insert_with(HashMap::new).insert(player, actions);
+            if let Some(frame) = frames.iter().find(|f| f.tick == tick) {
+                if frame.inputs.get(&player).cloned().unwrap_or_default() != actions {
+                    *mispredicted = Some(mispredicted.map_or(tick, |t| t.min(tick)));
+                }
+            }
+        });
+    }
+
+    fn simulate(&mut self, sim: &mut S) {
+        let tick = self.tick;
+        let inputs: HashMap<i32, Actions> =
+            self.exchange.players().iter().map(|&p| (p, self.input_for(p, tick))).collect();
+        let state = sim.snapshot(self.frames.back().map(|f| &f.state));
+        self.frames.push_back(Frame {
+            tick: tick,
+            state: state,
+            inputs: inputs.clone(),
+        });
+        sim.step(&inputs, TICK);
+        self.tick += 1;
+        if self.tick % CHECKSUM_INTERVAL == 0 {
+            self.checksums.insert(self.tick, sim.checksum());
+        }
+    }
+
+    fn rewind(&mut self, sim: &mut S, from: u64) {
+        let index = match self.frames.iter().position(|f| f.tick == from) {
+            Some(index) => index,
+            None => {
+                warn!("no frame to roll back to tick={}", from);
+                return;
+            }
+        };
+        let end = self.tick;
+        debug!("rolling back from={} to={}", end, from);
+        sim.restore(&self.frames[index].state);
+        self.frames.truncate(index);
+        self.tick = from;
+        while self.tick < end {
+            self.simulate(sim);
+        }
+    }
+
+    // Moves `confirmed` on as far as the inputs allow and lets go of what that settles.
+    fn confirm(&mut self) {
+        while self.confirmed < self.tick {
+            let complete = {
+                let players = self.exchange.players();
+                self.inputs
+                    .get(&self.confirmed)
+                    .map_or(false, |i| players.iter().all(|p| i.contains_key(p)))
+            };
+            if !complete {
+                break;
+            }
+            self.confirmed += 1;
+        }
+        let settled: Vec<(u64, u64)> =
+            self.checksums.range(..self.confirmed + 1).map(|(&t, &s)| (t, s)).collect();
+        for (tick, sum) in settled {
+            self.checksums.remove(&tick);
+            self.exchange.add_checksum(tick, sum);
+        }
+        // Confirmed frames were simulated with real inputs, or rolled back until they were.
+        while self.frames.front().map_or(false, |f| f.tick < self.confirmed) {
+            if let Some(frame) = self.frames.pop_front() {
+                self.settled.push(frame.inputs);
+            }
+        }
+        // The last confirmed tick stays, to guess from.
+        let keep = self.confirmed.saturating_sub(1);
+        self.inputs = self.inputs.split_off(&keep);
+    }
+}
+
+impl<S: Simulation, T: Transport> Session<S> for Rollback<S, T> {
+    fn update(&mut self, sim: &mut S, dt: f64, local: Actions) {
+        self.receive();
+        if self.exchange.desync().is_none() {
+            if let Some(from) = self.mispredicted.take() {
+                self.rewind(sim, from);
+            }
+            self.confirm();
+            self.accumulator = (self.accumulator + dt).min(MAX_CATCH_UP);
+            while self.accumulator >= TICK {
+                self.schedule(local);
+                if self.tick >= self.confirmed + MAX_ROLLBACK {
+                    trace!("stalled tick={} confirmed={}", self.tick, self.confirmed);
+                    break;
+                }
+                self.simulate(sim);
+                self.accumulator -= TICK;
+            }
+            self.confirm();
+        }
+        self.exchange.send();
+    }
+
+    fn idle(&mut self, sim: &mut S) {
+        self.receive();
+        if let Some(from) = self.mispredicted.take() {
+            self.rewind(sim, from);
+        }
+        self.confirm();
+        self.exchange.send();
+    }
+
+    fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    fn settled(&mut self) -> Vec<HashMap<i32, Actions>> {
+        self.settled.drain(..).collect()
+    }
+
+    fn verified(&self) -> u64 {
+        self.exchange.verified()
+    }
+
+    fn desync(&self) -> Option<u64> {
+        self.exchange.desync()
+    }
+}
@@ -0,0 +1,284 @@
+use bincode::{self, Infinite};
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+
+use systems::components::*;
+
+use super::Simulation;
+
+// Playback speed while fast forwarding a broadcast.
+pub const FAST_FORWARD: f64 = 8.0;
+// Steps a playback can have queued and still count as caught up with the match.
+const LIVE_LAG: usize = 2;
+const READ_CHUNK: usize = 4096;
+// Refuse frames bigger than this rather than trusting a corrupt length.
+const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+pub enum Broadcast {
+    // Always sent first: the level the match started from, in the level file format.
+    Start { level: String },
+    // One step of the simulation, in order.
+    Step { dt: f64, inputs: HashMap<i32, Actions> },
+}
+
+// A length prefixed, bincoded message.
+fn frame(message: &Broadcast) -> Vec<u8> {
+    let body = match bincode::serialize(message, Infinite) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("could not encode broadcast error=\"{}\"", e);
+            return vec![];
+        }
+    };
+    let len = body.len() as u32;
+    let mut out = vec![len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8];
+    out.extend(body);
+    out
+}
+
+struct Spectator {
+    stream: TcpStream,
+    addr: SocketAddr,
+    pending: Vec<u8>,
+}
+
+impl Spectator {
+    // Writes as much as the socket takes; false once the spectator has gone.
+    fn flush(&mut self) -> bool {
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(e) => {
+                    debug!("spectator write failed addr={} error=\"{}\"", self.addr, e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+// Streams the input log of a match to any number of spectators. Everyone gets the log
+// from the start, so late joiners can replay their way up to the live match.
+pub struct SpectatorServer {
+    listener: TcpListener,
+    spectators: Vec<Spectator>,
+    log: Vec<u8>,
+}
+
+impl SpectatorServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A, level: &str) -> io::Result<SpectatorServer> {
+        let listener = try!(TcpListener::bind(addr));
+        try!(listener.set_nonblocking(true));
+        Ok(SpectatorServer {
+            listener: listener,
+            spectators: vec![],
+            log: frame(&Broadcast::Start { level: level.to_string() }),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn spectators(&self) -> usize {
+        self.spectators.len()
+    }
+
+    pub fn record(&mut self, dt: f64, inputs: &HashMap<i32, Actions>) {
+        let step = frame(&Broadcast::Step {
+            dt: dt,
+            inputs: inputs.clone(),
+        });
+        for spectator in self.spectators.iter_mut() {
+            spectator.pending.extend_from_slice(&step);
+        }
+        self.log.extend(step);
+    }
+
+    // Takes in new spectators and sends everyone what they're owed. Call once a frame.
+    pub fn update(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    if let Err(e) = stream.set_nonblocking(true) {
+                        warn!("could not accept spectator addr={} error=\"{}\"", addr, e);
+                        continue;
+                    }
+                    info!("spectator joined addr={} log_bytes={}", addr, self.log.len());
+                    self.spectators.push(Spectator {
+                        stream: stream,
+                        addr: addr,
+                        pending: self.log.clone(),
+                    });
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("could not accept spectator error=\"{}\"", e);
+                    break;
+                }
+            }
+        }
+        let mut i = 0;
+        while i < self.spectators.len() {
+            if self.spectators[i].flush() {
+                i += 1;
+            } else {
+                let gone = self.spectators.swap_remove(i);
+                info!("spectator left addr={}", gone.addr);
+            }
+        }
+    }
+}
+
+pub struct SpectatorClient {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    closed: bool,
+}
+
+impl SpectatorClient {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<SpectatorClient> {
+        let stream = try!(TcpStream::connect(addr));
+        try!(stream.set_nonblocking(true));
+        Ok(SpectatorClient {
+            stream: stream,
+            buffer: vec![],
+            closed: false,
+        })
+    }
+
+    // True once the server has gone and everything it sent has been handed out.
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    // Every complete message that has arrived, without blocking.
+    pub fn poll(&mut self) -> Vec<Broadcast> {
+        let mut chunk = [0; READ_CHUNK];
+        while !self.closed {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    info!("broadcast ended");
+                    self.closed = true;
+                }
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("lost broadcast error=\"{}\"", e);
+                    self.closed = true;
+                }
+            }
+        }
+        let mut messages: Vec<Broadcast> = vec![];
+        let mut used = 0;
+        while self.buffer.len() - used >= 4 {
+            let len = {
+                let b = &self.buffer[used..used + 4];
+                b[0] as usize | (b[1] as usize) << 8 | (b[2] as usize) << 16 | (b[3] as usize) << 24
+            };
+            if len > MAX_FRAME {
+                warn!("dropped broadcast with oversized frame len={}", len);
+                self.closed = true;
+                self.buffer.clear();
+                return messages;
+            }
+            let start = used + 4;
+            if self.buffer.len() < start + len {
+                break;
+            }
+            match bincode::deserialize(&self.buffer[start..start + len]) {
+                Ok(message) => messages.push(message),
+                Err(e) => warn!("skipped malformed broadcast frame error=\"{}\"", e),
+            }
+            used = start + len;
+        }
+        self.buffer.drain(..used);
+        messages
+    }
+}
+
+// Replays received steps into a local copy of the match, at whatever pace the
+// spectator likes. A spectator joining late gets the match from the start, so playback
+// runs at `FAST_FORWARD` until it has caught up with the live match.
+pub struct Playback {
+    steps: VecDeque<(f64, HashMap<i32, Actions>)>,
+    clock: f64,
+    catching_up: bool,
+    pub paused: bool,
+    pub speed: f64,
+}
+
+impl Playback {
+    pub fn new() -> Playback {
+        Playback {
+            steps: VecDeque::new(),
+            clock: 0.0,
+            catching_up: true,
+            paused: false,
+            speed: 1.0,
+        }
+    }
+
+    // Whether playback has caught up with the match since joining.
+    pub fn live(&self) -> bool {
+        !self.catching_up
+    }
+
+    pub fn push(&mut self, dt: f64, inputs: HashMap<i32, Actions>) {
+        self.steps.push_back((dt, inputs));
+    }
+
+    // Steps received but not played yet.
+    pub fn behind(&self) -> usize {
+        self.steps.len()
+    }
+
+    // Plays as many steps as `dt` of real time covers at the current speed.
+    pub fn update<S: Simulation>(&mut self, sim: &mut S, dt: f64) {
+        if self.paused {
+            return;
+        }
+        let speed = if self.catching_up { self.speed.max(FAST_FORWARD) } else { self.speed };
+        self.clock += dt * speed;
+        let mut played = false;
+        loop {
+            let step = match self.steps.front() {
+                Some(&(step, _)) => step,
+                None => break,
+            };
+            if step > self.clock {
+                break;
+            }
+            self.clock -= step;
+            if let Some((step, inputs)) = self.steps.pop_front() {
+                sim.step(&inputs, step);
+                played = true;
+            }
+        }
+        // Don't bank time while waiting on the broadcast.
+        if self.steps.is_empty() {
+            self.clock = 0.0;
+        }
+        // Nothing played yet means the log hasn't started arriving.
+        if played && self.steps.len() <= LIVE_LAG {
+            self.catching_up = false;
+        }
+    }
+
+    // Plays everything received so far at once.
+    pub fn skip_to_live<S: Simulation>(&mut self, sim: &mut S) {
+        while let Some((step, inputs)) = self.steps.pop_front() {
+            sim.step(&inputs, step);
+        }
+        self.clock = 0.0;
+        self.catching_up = false;
+    }
+}
@@ -0,0 +1,60 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+const MAX_PACKET: usize = 1500;
+
+// Moves whole packets to the other peer. Delivery may drop, duplicate or reorder them;
+// `Lockstep` copes with all three.
+pub trait Transport {
+    fn send(&mut self, packet: &[u8]);
+    // Next packet that has arrived, without blocking.
+    fn receive(&mut self) -> Option<Vec<u8>>;
+}
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    buffer: Vec<u8>,
+}
+
+impl UdpTransport {
+    pub fn bind<A: ToSocketAddrs, B: ToSocketAddrs>(local: A, peer: B) -> io::Result<UdpTransport> {
+        let socket = try!(UdpSocket::bind(local));
+        try!(socket.set_nonblocking(true));
+        let peer = try!(try!(peer.to_socket_addrs())
+            .next()
+            .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "no address for peer")));
+        Ok(UdpTransport {
+            socket: socket,
+            peer: peer,
+            buffer: vec![0; MAX_PACKET],
+        })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, packet: &[u8]) {
+        if let Err(e) = self.socket.send_to(packet, self.peer) {
+            trace!("udp send failed peer={} error=\"{}\"", self.peer, e);
+        }
+    }
+
+    fn receive(&mut self) -> Option<Vec<u8>> {
+        loop {
+            match self.socket.recv_from(&mut self.buffer) {
+                Ok((len, from)) => {
+                    if from == self.peer {
+                        return Some(self.buffer[..len].to_vec());
+                    }
+                    debug!("ignored packet from={}", from);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                // Typically the peer isn't listening yet; keep going until it is.
+                Err(e) => {
+                    trace!("udp receive failed error=\"{}\"", e);
+                    return None;
+                }
+            }
+        }
+    }
+}
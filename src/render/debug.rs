@@ -0,0 +1,75 @@
+use piston_window::*;
+use specs::{Join, World};
+
+use systems::components::*;
+use super::draw_bounds;
+use super::terrain::{TerrainCanvas, CHUNK_SIZE};
+
+fn draw_contacts(debug: &CollisionDebug, c: Context, g: &mut G2d) {
+    for contact in debug.contacts.iter() {
+        let (p, n) = (contact.point, contact.normal);
+        let r = 3.0;
+        ellipse([0.0, 1.0, 1.0, 1.0], [p[0] - r, p[1] - r, 2.0 * r, 2.0 * r], c.transform, g);
+        line([0.0, 1.0, 1.0, 1.0],
+             1.0,
+             [p[0], p[1], p[0] + n[0] * 15.0, p[1] + n[1] * 15.0],
+             c.transform,
+             g);
+        // Penetration drawn to scale along the normal.
+        line([1.0, 0.2, 0.2, 1.0],
+             2.0,
+             [p[0], p[1], p[0] + n[0] * contact.depth, p[1] + n[1] * contact.depth],
+             c.transform,
+             g);
+    }
+}
+
+fn draw_aabbs(debug: &CollisionDebug, c: Context, g: &mut G2d) {
+    for aabb in debug.aabbs.iter() {
+        Rectangle::new_border([1.0, 1.0, 0.0, 0.7], 0.5).draw(*aabb, &c.draw_state, c.transform, g);
+    }
+}
+
+fn draw_velocities(world: &World, c: Context, g: &mut G2d) {
+    for (pos, vel) in (&world.read::<Pos>(), &world.read::<Vel>()).join() {
+        line([1.0, 0.5, 0.0, 1.0],
+             1.0,
+             [pos.x, pos.y, pos.x + vel.x * 0.25, pos.y + vel.y * 0.25],
+             c.transform,
+             g);
+    }
+}
+
+fn draw_chunks(canvas: &TerrainCanvas, pos: &Pos, c: Context, g: &mut G2d) {
+    let size = CHUNK_SIZE as f64;
+    for origin in canvas.chunk_origins() {
+        Rectangle::new_border([1.0, 0.0, 1.0, 0.5], 0.5)
+            .draw([pos.x + origin[0], pos.y + origin[1], size, size], &c.draw_state, c.transform, g);
+    }
+}
+
+pub fn draw_debug<'b, I>(world: &World, terrain: I, c: Context, g: &mut G2d)
+    where I: Iterator<Item = (&'b TerrainCanvas, &'b Pos)>
+{
+    let overlay = world.read_resource::<DebugOverlay>();
+    let debug = world.read_resource::<CollisionDebug>();
+    if overlay.shapes {
+        for (pos, bounds) in (&world.read::<Pos>(), &world.read::<Bounds>()).join() {
+            draw_bounds(bounds, pos, 0.4, c, g)
+        }
+    }
+    if overlay.chunks {
+        for (canvas, pos) in terrain {
+            draw_chunks(canvas, pos, c, g);
+        }
+    }
+    if overlay.aabbs {
+        draw_aabbs(&debug, c, g);
+    }
+    if overlay.contacts {
+        draw_contacts(&debug, c, g);
+    }
+    if overlay.velocities {
+        draw_velocities(world, c, g);
+    }
+}
@@ -0,0 +1,83 @@
+use piston_window::*;
+use specs::{Join, World};
+
+use editor::{Editor, Tool};
+use systems::components::*;
+use systems::decompose::edges;
+use super::draw_bounds;
+
+const OUTLINE: [f32; 4] = [1.0, 1.0, 0.2, 1.0];
+const MARKER: [f32; 4] = [0.2, 1.0, 0.4, 0.9];
+
+// The collision outline the terrain tracer produced, which is what players will stand on.
+fn draw_outline(world: &World, c: Context, g: &mut G2d) {
+    let pos = world.read::<Pos>();
+    let bounds = world.read::<Bounds>();
+    let terrain = world.read::<Terrain>();
+    for (pos, bounds, _) in (&pos, &bounds, &terrain).join() {
+        for part in bounds.parts() {
+            if let Bounds::Polygon(ref ps) = part.shape {
+                let (x, y) = (pos.x + part.offset[0], pos.y + part.offset[1]);
+                for (a, b) in edges(ps) {
+                    line(OUTLINE, 0.75, [a[0] + x, a[1] + y, b[0] + x, b[1] + y], c.transform, g);
+                }
+            }
+        }
+    }
+}
+
+pub fn draw_editor(editor: &Editor,
+                   world: &World,
+                   glyphs: Option<&mut Glyphs>,
+                   c: Context,
+                   g: &mut G2d) {
+    draw_outline(world, c, g);
+    for prop in editor.level.props.iter() {
+        draw_bounds(&prop.bounds,
+                    &Pos {
+                        x: prop.pos[0],
+                        y: prop.pos[1],
+                    },
+                    0.6,
+                    c,
+                    g);
+    }
+    for s in editor.level.spawns.iter() {
+        ellipse(MARKER, [s[0] - 6.0, s[1] - 6.0, 12.0, 12.0], c.transform, g);
+    }
+
+    let cursor = editor.cursor;
+    match editor.tool {
+        Tool::Paint | Tool::Erase => {
+            let r = editor.brush;
+            Ellipse::new_border(OUTLINE, 0.75).draw([cursor[0] - r, cursor[1] - r, 2.0 * r, 2.0 * r],
+                                                    &c.draw_state,
+                                                    c.transform,
+                                                    g);
+        }
+        Tool::Spawn => {
+            ellipse([0.2, 1.0, 0.4, 0.4],
+                    [cursor[0] - 6.0, cursor[1] - 6.0, 12.0, 12.0],
+                    c.transform,
+                    g)
+        }
+        Tool::Prop => {
+            rectangle([1.0, 0.0, 0.0, 0.3],
+                      [cursor[0] - 20.0, cursor[1] - 20.0, 40.0, 40.0],
+                      c.transform,
+                      g)
+        }
+    }
+
+    if let Some(glyphs) = glyphs {
+        let help = format!("{:?}  brush {}  {:?}  |  1-4 tool  [ ] brush  M material  S save  \
+                            F10 play  |  {}",
+                           editor.tool,
+                           editor.brush,
+                           editor.material,
+                           editor.status);
+        rectangle([0.0, 0.0, 0.0, 0.6], [0.0, 0.0, c.get_view_size()[0], 22.0], c.transform, g);
+        text::Text::new_color([1.0, 1.0, 1.0, 1.0], 12)
+            .draw(&help, glyphs, &c.draw_state, c.transform.trans(6.0, 15.0), g);
+    }
+}
@@ -0,0 +1,130 @@
+use piston_window::*;
+use specs::{Join, World};
+
+use std::iter::*;
+
+use systems::components::*;
+
+const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const SHADE: [f32; 4] = [0.0, 0.0, 0.0, 0.6];
+
+fn label(glyphs: &mut Glyphs,
+         s: &str,
+         size: u32,
+         colour: [f32; 4],
+         x: f64,
+         y: f64,
+         c: Context,
+         g: &mut G2d) {
+    text::Text::new_color(colour, size).draw(s, glyphs, &c.draw_state, c.transform.trans(x, y), g);
+}
+
+// Rough centring; good enough for short labels in a proportional font.
+fn centred(s: &str, size: u32, x: f64) -> f64 {
+    x - (s.len() as f64) * (size as f64) * 0.28
+}
+
+fn draw_overheads(world: &World, glyphs: &mut Glyphs, c: Context, g: &mut G2d) {
+    let pos = world.read::<Pos>();
+    let bounds = world.read::<Bounds>();
+    let health = world.read::<Health>();
+    let names = world.read::<Name>();
+    for (pos, bounds, health, name) in (&pos, &bounds, &health, &names).join() {
+        let top = pos.y - bounds.half_extents()[1] - 12.0;
+        let width = 40.0;
+        let fill = (health.current / health.max).max(0.0).min(1.0);
+        rectangle(SHADE, [pos.x - width / 2.0, top, width, 5.0], c.transform, g);
+        rectangle([1.0 - fill as f32, fill as f32, 0.2, 1.0],
+                  [pos.x - width / 2.0, top, width * fill, 5.0],
+                  c.transform,
+                  g);
+        label(glyphs, &name.0, 12, WHITE, centred(&name.0, 12, pos.x), top - 4.0, c, g);
+    }
+}
+
+fn draw_aim(world: &World, c: Context, g: &mut G2d) {
+    let turn = world.read_resource::<TurnState>();
+    let pos = world.read::<Pos>();
+    let aim = world.read::<Aim>();
+    let player = world.read::<Player>();
+    for (pos, aim, _) in (&pos, &aim, &player).join().filter(|&(_, _, p)| p.0 == turn.active) {
+        let length = 30.0 + 40.0 * aim.power;
+        line([1.0, 1.0, 0.4, 0.8],
+             1.5,
+             [pos.x, pos.y, pos.x + aim.angle.cos() * length, pos.y + aim.angle.sin() * length],
+             c.transform,
+             g);
+    }
+}
+
+fn draw_spells(world: &World, glyphs: &mut Glyphs, height: f64, c: Context, g: &mut G2d) {
+    let turn = world.read_resource::<TurnState>();
+    let player = world.read::<Player>();
+    let books = world.read::<SpellBook>();
+    for (_, book) in (&player, &books).join().filter(|&(p, _)| p.0 == turn.active) {
+        let top = height - 20.0 * book.spells.len() as f64 - 10.0;
+        rectangle(SHADE, [5.0, top - 16.0, 160.0, height - top + 10.0], c.transform, g);
+        for (i, spell) in book.spells.iter().enumerate() {
+            let cooldown = book.cooldowns[i];
+            let colour = if i == book.active { [1.0, 0.9, 0.3, 1.0] } else { WHITE };
+            let text = if cooldown > 0.0 {
+                format!("{} ({:.1}s)", spell.name, cooldown)
+            } else {
+                spell.name.clone()
+            };
+            label(glyphs, &text, 14, colour, 12.0, top + 20.0 * i as f64, c, g);
+        }
+    }
+}
+
+fn draw_turn(world: &World, glyphs: &mut Glyphs, width: f64, c: Context, g: &mut G2d) {
+    let turn = world.read_resource::<TurnState>();
+    let wind = world.read_resource::<Wind>();
+    let timer = format!("{:.0}", turn.time_left.max(0.0).ceil());
+    label(glyphs, &timer, 24, WHITE, centred(&timer, 24, width / 2.0), 30.0, c, g);
+
+    // Wind arrow scaled to the strongest wind this level can produce.
+    let strongest = wind.range[0].abs().max(wind.range[1].abs()).max(1.0);
+    let length = 50.0 * wind.speed / strongest;
+    let (x, y) = (width - 80.0, 25.0);
+    rectangle(SHADE, [x - 60.0, y - 15.0, 120.0, 30.0], c.transform, g);
+    line([0.6, 0.9, 1.0, 1.0], 2.0, [x, y, x + length, y], c.transform, g);
+    let head = if length < 0.0 { 6.0 } else { -6.0 };
+    line([0.6, 0.9, 1.0, 1.0], 2.0, [x + length, y, x + length + head, y - 5.0], c.transform, g);
+    line([0.6, 0.9, 1.0, 1.0], 2.0, [x + length, y, x + length + head, y + 5.0], c.transform, g);
+}
+
+fn draw_scoreboard(world: &World, glyphs: &mut Glyphs, size: [f64; 2], c: Context, g: &mut G2d) {
+    let player = world.read::<Player>();
+    let names = world.read::<Name>();
+    let health = world.read::<Health>();
+    let score = world.read::<Score>();
+    let mut rows = Vec::from_iter((&player, &names, &health, &score).join());
+    rows.sort_by_key(|&(p, _, _, _)| p.0);
+    let (w, h) = (360.0, 60.0 + 24.0 * rows.len() as f64);
+    let (x, y) = ((size[0] - w) / 2.0, (size[1] - h) / 2.0);
+    rectangle([0.0, 0.0, 0.0, 0.8], [x, y, w, h], c.transform, g);
+    label(glyphs, "Wizard", 16, WHITE, x + 15.0, y + 30.0, c, g);
+    label(glyphs, "Health", 16, WHITE, x + 150.0, y + 30.0, c, g);
+    label(glyphs, "Damage", 16, WHITE, x + 220.0, y + 30.0, c, g);
+    label(glyphs, "Kills", 16, WHITE, x + 300.0, y + 30.0, c, g);
+    for (i, &(_, name, health, score)) in rows.iter().enumerate() {
+        let row = y + 56.0 + 24.0 * i as f64;
+        let colour = if health.alive() { WHITE } else { [0.6, 0.6, 0.6, 1.0] };
+        label(glyphs, &name.0, 14, colour, x + 15.0, row, c, g);
+        label(glyphs, &format!("{:.0}", health.current.max(0.0)), 14, colour, x + 150.0, row, c, g);
+        label(glyphs, &format!("{:.0}", score.damage), 14, colour, x + 220.0, row, c, g);
+        label(glyphs, &format!("{}", score.kills), 14, colour, x + 300.0, row, c, g);
+    }
+}
+
+pub fn draw_hud(world: &World, glyphs: &mut Glyphs, scoreboard: bool, c: Context, g: &mut G2d) {
+    let size = c.get_view_size();
+    draw_aim(world, c, g);
+    draw_overheads(world, glyphs, c, g);
+    draw_spells(world, glyphs, size[1], c, g);
+    draw_turn(world, glyphs, size[0], c, g);
+    if scoreboard {
+        draw_scoreboard(world, glyphs, size, c, g);
+    }
+}
@@ -0,0 +1,189 @@
+use piston_window::*;
+use gfx_device_gl::Factory;
+use specs::{Entity, Join, World};
+
+use std::collections::HashMap;
+use std::iter::*;
+use std::path::Path;
+
+use systems::components::*;
+use systems::particles::Particles;
+use systems::profile::Profiler;
+use editor::Editor;
+
+pub mod textures;
+pub mod terrain;
+pub mod particles;
+pub mod hud;
+pub mod debug;
+pub mod profiler;
+pub mod editor;
+use self::textures::*;
+use self::terrain::*;
+use self::particles::*;
+use self::hud::*;
+use self::debug::*;
+use self::profiler::*;
+use self::editor::*;
+
+pub struct Renderer {
+    textures: Textures,
+    terrain: HashMap<Entity, TerrainCanvas>,
+    glyphs: Option<Glyphs>,
+    pub scoreboard: bool,
+    pub profiler: bool,
+}
+
+pub fn draw_bounds(bounds: &Bounds, pos: &Pos, alpha: f32, c: Context, g: &mut G2d) {
+    match bounds {
+        &Bounds::Rectangle(x, y) => {
+            rectangle([1.0, 0.0, 0.0, alpha],
+                      [pos.x - (x / 2.0), pos.y - (y / 2.0), x, y],
+                      c.transform,
+                      g);
+        }
+        &Bounds::Circle(r) => {
+            ellipse([0.0, 0.0, 1.0, alpha],
+                    [pos.x - r, pos.y - r, 2.0 * r, 2.0 * r],
+                    c.transform,
+                    g);
+        }
+        &Bounds::Polygon(ref ps) => {
+            let ps = Vec::from_iter(ps[..].into_iter().map(|p| [p[0] + pos.x, p[1] + pos.y]));
+
+            polygon([0.0, 1.0, 0.0, alpha * 0.5], &ps, c.transform, g)
+        }
+        &Bounds::Compound(ref parts) => {
+            for part in parts.iter() {
+                let c = c.trans(pos.x + part.offset[0], pos.y + part.offset[1])
+                    .rot_rad(part.angle);
+                draw_bounds(&part.shape, &Pos { x: 0.0, y: 0.0 }, alpha, c, g);
+            }
+        }
+    }
+}
+
+fn draw_water(info: &LevelInfo, c: Context, g: &mut G2d) {
+    if let Some(level) = info.water {
+        let size = c.get_view_size();
+        rectangle([0.1, 0.3, 0.8, 0.5],
+                  [0.0, level, size[0], (size[1] - level).max(0.0)],
+                  c.transform,
+                  g);
+    }
+}
+
+fn draw_sprite(sprite: &Sprite, texture: &G2dTexture, pos: &Pos, c: Context, g: &mut G2d) {
+    let (w, h) = (sprite.frame_size[0], sprite.frame_size[1]);
+    let mut transform = c.transform.trans(pos.x, pos.y);
+    if sprite.flip_x {
+        transform = transform.flip_h();
+    }
+    Image::new()
+        .src_rect(sprite.source_rect())
+        .rect([-w / 2.0, -h / 2.0, w, h])
+        .draw(texture, &c.draw_state, transform, g);
+}
+
+impl Renderer {
+    pub fn new(factory: &mut Factory) -> Renderer {
+        Renderer {
+            textures: Textures::load_dirs(factory,
+                                          &[Path::new("assets/sprites"),
+                                            Path::new("assets/backgrounds")]),
+            terrain: HashMap::new(),
+            glyphs: match Glyphs::new("assets/fonts/DejaVuSans.ttf", factory.clone()) {
+                Ok(glyphs) => Some(glyphs),
+                Err(e) => {
+                    warn!("could not load hud font error=\"{}\"", e);
+                    None
+                }
+            },
+            scoreboard: false,
+            profiler: false,
+        }
+    }
+
+    pub fn render_editor(&mut self, editor: &Editor, world: &World, c: Context, g: &mut G2d) {
+        draw_editor(editor, world, self.glyphs.as_mut(), c, g);
+    }
+
+    fn draw_background(&self, info: &LevelInfo, c: Context, g: &mut G2d) {
+        let texture = info.background.as_ref().and_then(|b| self.textures.get(b));
+        if let Some(texture) = texture {
+            let size = c.get_view_size();
+            Image::new()
+                .rect([0.0, 0.0, size[0], size[1]])
+                .draw(texture, &c.draw_state, c.transform, g);
+        }
+    }
+
+    // Forgets everything tied to the current world's entities, e.g. after loading a save.
+    pub fn reset(&mut self) {
+        self.terrain.clear();
+    }
+
+    // Brings GPU side resources in line with the world; call before drawing.
+    pub fn update(&mut self, world: &World, factory: &mut Factory, encoder: &mut GfxEncoder) {
+        let entities = &world.entities();
+        let mut terrain = world.write::<Terrain>();
+        self.terrain.retain(|&e, _| entities.is_alive(e));
+        for (e, terrain) in (&**entities, &mut terrain).join() {
+            let changed = terrain.changed.drain(..).collect::<Vec<_>>();
+            let repaint = terrain.repaint;
+            terrain.repaint = false;
+            if let Some(canvas) = self.terrain.get_mut(&e) {
+                if !repaint {
+                    canvas.update(factory, encoder, terrain, &changed);
+                    continue;
+                }
+            }
+            self.terrain.insert(e, TerrainCanvas::new(factory, terrain));
+        }
+    }
+
+    pub fn render(&mut self, world: &World, profiler: &Profiler, c: Context, g: &mut G2d) {
+        let pos = &world.read::<Pos>();
+        let bounds = &world.read::<Bounds>();
+        let sprites = &world.read::<Sprite>();
+        clear([0.5, 0.5, 0.5, 1.0], g);
+        let info = world.read_resource::<LevelInfo>();
+        self.draw_background(&info, c, g);
+
+        for (e, pos) in (&*world.entities(), pos).join() {
+            if let Some(canvas) = self.terrain.get(&e) {
+                canvas.draw(pos, c, g);
+            }
+        }
+        // Anything without a usable sprite falls back to its collision shape. Sensors are
+        // invisible; the debug overlay shows them.
+        let col = world.read::<CollisionObjectData>();
+        for (e, pos, bounds) in (&*world.entities(), pos, bounds).join() {
+            let textured = sprites.get(e).map_or(false, |s| self.textures.get(&s.texture).is_some());
+            let sensor = col.get(e).map_or(false, |c| c.sensor);
+            if !textured && !sensor && !self.terrain.contains_key(&e) {
+                draw_bounds(bounds, pos, 1.0, c, g)
+            }
+        }
+        let mut drawn = Vec::from_iter((pos, sprites)
+            .join()
+            .filter_map(|(p, s)| self.textures.get(&s.texture).map(|t| (s.z, p, s, t))));
+        drawn.sort_by_key(|&(z, _, _, _)| z);
+        for (_, pos, sprite, texture) in drawn {
+            draw_sprite(sprite, texture, pos, c, g);
+        }
+        draw_particles(&world.read_resource::<Particles>(), c, g);
+        draw_water(&info, c, g);
+
+        draw_debug(world,
+                   self.terrain.iter().filter_map(|(e, canvas)| pos.get(*e).map(|p| (canvas, p))),
+                   c,
+                   g);
+        if let Some(ref mut glyphs) = self.glyphs {
+            draw_hud(world, glyphs, self.scoreboard, c, g);
+            if self.profiler {
+                draw_profiler(profiler, glyphs, c, g);
+            }
+        }
+    }
+}
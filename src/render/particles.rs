@@ -0,0 +1,46 @@
+use piston_window::*;
+
+use std::collections::HashMap;
+
+use systems::particles::*;
+
+// gfx_graphics streams at most this many vertices per draw call.
+const BATCH_VERTICES: usize = 1020;
+
+fn quantise(c: [f32; 4]) -> [u8; 4] {
+    [(c[0] * 15.0) as u8, (c[1] * 15.0) as u8, (c[2] * 15.0) as u8, (c[3] * 15.0) as u8]
+}
+
+fn colour_of(key: [u8; 4]) -> [f32; 4] {
+    [key[0] as f32 / 15.0, key[1] as f32 / 15.0, key[2] as f32 / 15.0, key[3] as f32 / 15.0]
+}
+
+fn to_screen(m: [[f64; 3]; 2], x: f64, y: f64) -> [f32; 2] {
+    [(m[0][0] * x + m[0][1] * y + m[0][2]) as f32, (m[1][0] * x + m[1][1] * y + m[1][2]) as f32]
+}
+
+// Particles are bucketed by (coarsened) colour and each bucket goes out as a
+// single triangle list instead of one draw call per particle.
+pub fn draw_particles(particles: &Particles, c: Context, g: &mut G2d) {
+    let mut buckets: HashMap<[u8; 4], Vec<[f32; 2]>> = HashMap::new();
+    for p in particles.iter() {
+        let key = quantise(p.colour());
+        if key[3] == 0 {
+            continue;
+        }
+        let h = p.size / 2.0;
+        let m = c.transform;
+        let a = to_screen(m, p.pos[0] - h, p.pos[1] - h);
+        let b = to_screen(m, p.pos[0] + h, p.pos[1] - h);
+        let cc = to_screen(m, p.pos[0] + h, p.pos[1] + h);
+        let d = to_screen(m, p.pos[0] - h, p.pos[1] + h);
+        buckets.entry(key).or_insert_with(Vec::new).extend_from_slice(&[a, b, cc, a, cc, d]);
+    }
+    for (key, vertices) in buckets.iter() {
+        g.tri_list(&c.draw_state, &colour_of(*key), |f| {
+            for batch in vertices.chunks(BATCH_VERTICES) {
+                f(batch)
+            }
+        });
+    }
+}
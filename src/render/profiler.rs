@@ -0,0 +1,35 @@
+use piston_window::*;
+
+use systems::profile::*;
+
+const ROW: f64 = 16.0;
+// Bars are scaled so a full 60fps frame budget spans the whole width.
+const BUDGET_MS: f64 = 16.7;
+const WIDTH: f64 = 200.0;
+
+pub fn draw_profiler(profiler: &Profiler, glyphs: &mut Glyphs, c: Context, g: &mut G2d) {
+    let sample = match profiler.latest() {
+        Some(s) => s,
+        None => return,
+    };
+    let (x, y) = (10.0, 60.0);
+    let h = ROW * (sample.systems.len() as f64 + 3.0) + 10.0;
+    rectangle([0.0, 0.0, 0.0, 0.7], [x - 5.0, y - 15.0, WIDTH + 190.0, h], c.transform, g);
+    let text = text::Text::new_color([1.0, 1.0, 1.0, 1.0], 11);
+    let summary = format!("frame {:.2}ms  collision iters {}  retrace {:.2}ms",
+                          sample.frame_ms,
+                          sample.collision_iterations,
+                          sample.terrain_retrace_ms);
+    text.draw(&summary, glyphs, &c.draw_state, c.transform.trans(x, y), g);
+    for (i, &(name, ms)) in sample.systems.iter().enumerate() {
+        let row = y + ROW * (i as f64 + 1.5);
+        let fill = (ms / BUDGET_MS).min(1.0) * WIDTH;
+        let colour = if ms > BUDGET_MS / 4.0 { [1.0, 0.3, 0.2, 0.9] } else { [0.3, 0.9, 0.4, 0.9] };
+        rectangle(colour, [x + 180.0, row - 9.0, fill, 10.0], c.transform, g);
+        text.draw(&format!("{} {:.3}ms", name, ms),
+                  glyphs,
+                  &c.draw_state,
+                  c.transform.trans(x, row),
+                  g);
+    }
+}
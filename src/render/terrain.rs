@@ -0,0 +1,161 @@
+use piston_window::*;
+use gfx_device_gl::Factory;
+use image::{ImageBuffer, Rgba, RgbaImage};
+
+use std::collections::{HashMap, HashSet};
+
+use systems::components::*;
+
+pub const CHUNK_SIZE: usize = 64;
+const EDGE_BAND: usize = 4;
+
+struct Chunk {
+    image: RgbaImage,
+    texture: G2dTexture,
+}
+
+// The terrain grid painted into fixed size textures, so carving a crater only
+// re-uploads the handful of chunks it touched.
+pub struct TerrainCanvas {
+    chunks: HashMap<[usize; 2], Chunk>,
+}
+
+fn chunk_key(p: [usize; 2]) -> [usize; 2] {
+    [p[0] / CHUNK_SIZE, p[1] / CHUNK_SIZE]
+}
+
+fn base_colour(material: Material) -> [f32; 3] {
+    match material {
+        Material::Dirt => [0.47, 0.33, 0.2],
+        Material::Rock => [0.4, 0.4, 0.44],
+        Material::Sand => [0.82, 0.74, 0.47],
+    }
+}
+
+// Distance to the nearest open cell along the four axes, capped at the band width.
+fn edge_distance(terrain: &Terrain, p: [usize; 2]) -> usize {
+    for d in 1..(EDGE_BAND + 1) {
+        let open = |x: Option<usize>, y: Option<usize>| match (x, y) {
+            (Some(x), Some(y)) => !terrain.points.contains(&[x, y]),
+            _ => true,
+        };
+        if open(Some(p[0]), p[1].checked_sub(d)) || open(Some(p[0]), Some(p[1] + d)) ||
+           open(p[0].checked_sub(d), Some(p[1])) || open(Some(p[0] + d), Some(p[1])) {
+            return d;
+        }
+    }
+    EDGE_BAND + 1
+}
+
+fn cell_colour(terrain: &Terrain, p: [usize; 2]) -> Rgba<u8> {
+    if !terrain.points.contains(&p) {
+        return Rgba([0, 0, 0, 0]);
+    }
+    let mut colour = base_colour(terrain.material_at(p));
+    let edge = edge_distance(terrain, p);
+    if edge <= EDGE_BAND {
+        let t = 1.0 - (edge as f32 - 1.0) / EDGE_BAND as f32;
+        let band = [0.3, 0.2, 0.1];
+        for i in 0..3 {
+            colour[i] += (band[i] - colour[i]) * t;
+        }
+    }
+    if terrain.scorched.contains(&p) {
+        for c in colour.iter_mut() {
+            *c *= 0.45;
+        }
+    }
+    // A little per-cell grain so flat areas don't look like a fill.
+    let grain = ((p[0].wrapping_mul(7919) ^ p[1].wrapping_mul(104729)) % 9) as f32 / 100.0;
+    let to_byte = |c: f32| ((c + grain).min(1.0).max(0.0) * 255.0) as u8;
+    Rgba([to_byte(colour[0]), to_byte(colour[1]), to_byte(colour[2]), 255])
+}
+
+fn paint(terrain: &Terrain, key: [usize; 2], image: &mut RgbaImage, from: [usize; 2], to: [usize; 2]) {
+    let origin = [key[0] * CHUNK_SIZE, key[1] * CHUNK_SIZE];
+    for x in from[0]..to[0] {
+        for y in from[1]..to[1] {
+            image.put_pixel(x as u32,
+                            y as u32,
+                            cell_colour(terrain, [origin[0] + x, origin[1] + y]));
+        }
+    }
+}
+
+fn new_chunk(factory: &mut Factory, terrain: &Terrain, key: [usize; 2]) -> Option<Chunk> {
+    let mut image = ImageBuffer::new(CHUNK_SIZE as u32, CHUNK_SIZE as u32);
+    paint(terrain, key, &mut image, [0, 0], [CHUNK_SIZE, CHUNK_SIZE]);
+    Texture::from_image(factory, &image, &TextureSettings::new()).ok().map(|texture| {
+        Chunk {
+            image: image,
+            texture: texture,
+        }
+    })
+}
+
+impl TerrainCanvas {
+    pub fn new(factory: &mut Factory, terrain: &Terrain) -> TerrainCanvas {
+        let keys: HashSet<[usize; 2]> = terrain.points.iter().map(|&p| chunk_key(p)).collect();
+        let mut chunks = HashMap::new();
+        for key in keys {
+            if let Some(chunk) = new_chunk(factory, terrain, key) {
+                chunks.insert(key, chunk);
+            }
+        }
+        TerrainCanvas { chunks: chunks }
+    }
+
+    // Repaints the cells around each change, far enough out to cover the edge band.
+    // Cells added outside the existing chunks (e.g. painted in the editor) get new chunks.
+    pub fn update(&mut self,
+                  factory: &mut Factory,
+                  encoder: &mut GfxEncoder,
+                  terrain: &Terrain,
+                  changed: &[[usize; 2]]) {
+        for &p in changed {
+            let key = chunk_key(p);
+            if terrain.points.contains(&p) && !self.chunks.contains_key(&key) {
+                if let Some(chunk) = new_chunk(factory, terrain, key) {
+                    self.chunks.insert(key, chunk);
+                }
+            }
+        }
+        let mut dirty = HashSet::new();
+        for &p in changed {
+            let min = [p[0].saturating_sub(EDGE_BAND), p[1].saturating_sub(EDGE_BAND)];
+            let max = [p[0] + EDGE_BAND + 1, p[1] + EDGE_BAND + 1];
+            for (&key, chunk) in self.chunks.iter_mut() {
+                let origin = [key[0] * CHUNK_SIZE, key[1] * CHUNK_SIZE];
+                let from = [min[0].max(origin[0]), min[1].max(origin[1])];
+                let to = [max[0].min(origin[0] + CHUNK_SIZE), max[1].min(origin[1] + CHUNK_SIZE)];
+                if from[0] < to[0] && from[1] < to[1] {
+                    paint(terrain,
+                          key,
+                          &mut chunk.image,
+                          [from[0] - origin[0], from[1] - origin[1]],
+                          [to[0] - origin[0], to[1] - origin[1]]);
+                    dirty.insert(key);
+                }
+            }
+        }
+        for key in dirty {
+            if let Some(chunk) = self.chunks.get_mut(&key) {
+                let _ = chunk.texture.update(encoder, &chunk.image);
+            }
+        }
+    }
+
+    pub fn chunk_origins<'a>(&'a self) -> Box<Iterator<Item = [f64; 2]> + 'a> {
+        Box::new(self.chunks
+            .keys()
+            .map(|k| [(k[0] * CHUNK_SIZE) as f64, (k[1] * CHUNK_SIZE) as f64]))
+    }
+
+    pub fn draw(&self, pos: &Pos, c: Context, g: &mut G2d) {
+        for (key, chunk) in self.chunks.iter() {
+            let x = pos.x + (key[0] * CHUNK_SIZE) as f64;
+            let y = pos.y + (key[1] * CHUNK_SIZE) as f64;
+            image(&chunk.texture, c.transform.trans(x, y), g);
+        }
+    }
+}
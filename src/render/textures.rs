@@ -0,0 +1,48 @@
+use piston_window::*;
+use gfx_device_gl::Factory;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+pub struct Textures(HashMap<String, G2dTexture>);
+
+impl Textures {
+    pub fn new() -> Textures {
+        Textures(HashMap::new())
+    }
+
+    // Loads every png in `dirs`, keyed by file stem, so "assets/sprites/wizard.png" is "wizard".
+    pub fn load_dirs(factory: &mut Factory, dirs: &[&Path]) -> Textures {
+        let mut textures = Textures::new();
+        for dir in dirs {
+            textures.load_dir(factory, dir);
+        }
+        textures
+    }
+
+    fn load_dir(&mut self, factory: &mut Factory, dir: &Path) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.extension().map_or(false, |e| e == "png") {
+                if let Some(name) = path.file_stem().and_then(|s| s.to_str()).map(String::from) {
+                    match Texture::from_path(factory, &path, Flip::None, &TextureSettings::new()) {
+                        Ok(texture) => {
+                            debug!("loaded texture name={} path={:?}", name, path);
+                            self.0.insert(name, texture);
+                        }
+                        Err(e) => warn!("could not load texture path={:?} error=\"{}\"", path, e),
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&G2dTexture> {
+        self.0.get(name)
+    }
+}
@@ -0,0 +1,364 @@
+use specs::{Entity, Join, World};
+use bincode::{self, Infinite};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use systems::components::*;
+use systems::particles::Emitter;
+
+const MAGIC: &'static [u8; 4] = b"WZ13";
+pub const SAVE_VERSION: u32 = 8;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavedProjectile {
+    // Index into `SavedState::entities`, since entity ids don't survive a reload.
+    pub caster: Option<usize>,
+    pub damage: f64,
+    pub radius: f64,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct SavedEntity {
+    pub pos: Option<Pos>,
+    pub vel: Option<Vel>,
+    pub bounds: Option<Bounds>,
+    pub collision_layer: Option<Layer>,
+    pub sensor: bool,
+    // Indices into `SavedState::entities`, with the seconds left to pass through each.
+    pub ignore: Vec<(usize, f64)>,
+    pub fast: bool,
+    pub zone: Option<Zone>,
+    pub player: Option<Player>,
+    pub name: Option<Name>,
+    pub health: Option<Health>,
+    pub score: Option<Score>,
+    pub spells: Option<SpellBook>,
+    pub aim: Option<Aim>,
+    pub ai: Option<AiController>,
+    pub character: Option<CharacterController>,
+    pub projectile: Option<SavedProjectile>,
+    pub terrain: Option<Terrain>,
+    pub sprite: Option<Sprite>,
+    pub animation: Option<Animation>,
+    pub emitter: Option<Emitter>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SavedState {
+    pub entities: Vec<SavedEntity>,
+    pub turn: TurnState,
+    pub wind: Wind,
+    pub level: LevelInfo,
+}
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(io::Error),
+    Format(String),
+    Version(u32),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &SaveError::Io(ref e) => write!(f, "{}", e),
+            &SaveError::Format(ref e) => write!(f, "corrupt save: {}", e),
+            &SaveError::Version(v) => {
+                write!(f, "save version {} is not supported (expected {})", v, SAVE_VERSION)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for SaveError {
+    fn from(e: io::Error) -> SaveError {
+        SaveError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for SaveError {
+    fn from(e: bincode::Error) -> SaveError {
+        SaveError::Format(format!("{}", e))
+    }
+}
+
+pub fn capture(world: &World) -> SavedState {
+    let entities = world.entities();
+    let (pos, vel, bounds, col) = (world.read::<Pos>(),
+                                   world.read::<Vel>(),
+                                   world.read::<Bounds>(),
+                                   world.read::<CollisionObjectData>());
+    let (player, name, health, score) = (world.read::<Player>(),
+                                         world.read::<Name>(),
+                                         world.read::<Health>(),
+                                         world.read::<Score>());
+    let (spells, aim, projectile, terrain) = (world.read::<SpellBook>(),
+                                              world.read::<Aim>(),
+                                              world.read::<Projectile>(),
+                                              world.read::<Terrain>());
+    let (sprite, animation, emitter) =
+        (world.read::<Sprite>(), world.read::<Animation>(), world.read::<Emitter>());
+    let ai = world.read::<AiController>();
+    let character = world.read::<CharacterController>();
+    let zone = world.read::<Zone>();
+
+    let all: Vec<Entity> = (&*entities).join().collect();
+    let index: HashMap<Entity, usize> = all.iter().enumerate().map(|(i, &e)| (e, i)).collect();
+    let saved = all.iter()
+        .map(|&e| {
+            SavedEntity {
+                pos: pos.get(e).cloned(),
+                vel: vel.get(e).cloned(),
+                bounds: bounds.get(e).cloned(),
+                collision_layer: col.get(e).map(|c| c.layer),
+                sensor: col.get(e).map_or(false, |c| c.sensor),
+                ignore: col.get(e).map_or(vec![], |c| {
+                    c.ignore
+                        .iter()
+                        .filter_map(|&(other, left)| index.get(&other).map(|&i| (i, left)))
+                        .collect()
+                }),
+                fast: col.get(e).map_or(false, |c| c.fast),
+                zone: zone.get(e).cloned(),
+                player: player.get(e).cloned(),
+                name: name.get(e).cloned(),
+                health: health.get(e).cloned(),
+                score: score.get(e).cloned(),
+                spells: spells.get(e).cloned(),
+                aim: aim.get(e).cloned(),
+                ai: ai.get(e).cloned(),
+                character: character.get(e).cloned(),
+                projectile: projectile.get(e).map(|p| {
+                    SavedProjectile {
+                        caster: index.get(&p.caster).cloned(),
+                        damage: p.damage,
+                        radius: p.radius,
+                    }
+                }),
+                terrain: terrain.get(e).cloned(),
+                sprite: sprite.get(e).cloned(),
+                animation: animation.get(e).cloned(),
+                emitter: emitter.get(e).cloned(),
+            }
+        })
+        .collect();
+    SavedState {
+        entities: saved,
+        turn: world.read_resource::<TurnState>().clone(),
+        wind: world.read_resource::<Wind>().clone(),
+        level: world.read_resource::<LevelInfo>().clone(),
+    }
+}
+
+// A projectile whose caster is gone has nobody to credit and nothing to do.
+fn orphaned(saved: &SavedEntity, count: usize) -> bool {
+    saved.projectile.as_ref().map_or(false, |p| p.caster.map_or(true, |i| i >= count))
+}
+
+// Populates a freshly built world, returning the entities it made. Collision objects are
+// not part of the state; the collision system creates them again from `Bounds` on its
+// first run. Orphaned projectiles are left out.
+pub fn restore(world: &mut World, state: &SavedState) -> Vec<Entity> {
+    let count = state.entities.len();
+    let created: Vec<Option<Entity>> = state.entities
+        .iter()
+        .map(|saved| if orphaned(saved, count) {
+            None
+        } else {
+            Some(world.create_entity().build())
+        })
+        .collect();
+    {
+        let mut pos = world.write::<Pos>();
+        let mut vel = world.write::<Vel>();
+        let mut bounds = world.write::<Bounds>();
+        let mut col = world.write::<CollisionObjectData>();
+        let mut player = world.write::<Player>();
+        let mut name = world.write::<Name>();
+        let mut health = world.write::<Health>();
+        let mut score = world.write::<Score>();
+        let mut spells = world.write::<SpellBook>();
+        let mut aim = world.write::<Aim>();
+        let mut ai = world.write::<AiController>();
+        let mut character = world.write::<CharacterController>();
+        let mut zone = world.write::<Zone>();
+        let mut projectile = world.write::<Projectile>();
+        let mut terrain = world.write::<Terrain>();
+        let mut sprite = world.write::<Sprite>();
+        let mut animation = world.write::<Animation>();
+        let mut emitter = world.write::<Emitter>();
+        for (e, saved) in created.iter().zip(state.entities.iter()) {
+            let e = match *e {
+                Some(e) => e,
+                None => continue,
+            };
+            if let Some(c) = saved.pos {
+                pos.insert(e, c);
+            }
+            if let Some(c) = saved.vel {
+                vel.insert(e, c);
+            }
+            if let Some(ref c) = saved.bounds {
+                bounds.insert(e, c.clone());
+            }
+            if let Some(layer) = saved.collision_layer {
+                let mut c = CollisionObjectData::new(layer);
+                c.sensor = saved.sensor;
+                c.ignore = saved.ignore
+                    .iter()
+                    .filter_map(|&(i, left)| created.get(i).and_then(|&other| other).map(|other| (other, left)))
+                    .collect();
+                c.fast = saved.fast;
+                col.insert(e, c);
+            }
+            if let Some(c) = saved.zone {
+                zone.insert(e, c);
+            }
+            if let Some(c) = saved.player {
+                player.insert(e, c);
+            }
+            if let Some(ref c) = saved.name {
+                name.insert(e, c.clone());
+            }
+            if let Some(c) = saved.health {
+                health.insert(e, c);
+            }
+            if let Some(c) = saved.score {
+                score.insert(e, c);
+            }
+            if let Some(ref c) = saved.spells {
+                spells.insert(e, c.clone());
+            }
+            if let Some(c) = saved.aim {
+                aim.insert(e, c);
+            }
+            if let Some(ref c) = saved.ai {
+                ai.insert(e, c.clone());
+            }
+            if let Some(c) = saved.character {
+                character.insert(e, c);
+            }
+            if let Some(ref p) = saved.projectile {
+                if let Some(caster) = p.caster.and_then(|i| created[i]) {
+                    projectile.insert(e,
+                                      Projectile {
+                                          caster: caster,
+                                          damage: p.damage,
+                                          radius: p.radius,
+                                      });
+                }
+            }
+            if let Some(ref c) = saved.terrain {
+                let mut t = c.clone();
+                t.dirty = true;
+                terrain.insert(e, t);
+            }
+            if let Some(ref c) = saved.sprite {
+                sprite.insert(e, c.clone());
+            }
+            if let Some(ref c) = saved.animation {
+                animation.insert(e, c.clone());
+            }
+            if let Some(ref c) = saved.emitter {
+                emitter.insert(e, c.clone());
+            }
+        }
+    }
+    *world.write_resource::<TurnState>() = state.turn.clone();
+    *world.write_resource::<Wind>() = state.wind.clone();
+    *world.write_resource::<LevelInfo>() = state.level.clone();
+    created.into_iter().filter_map(|e| e).collect()
+}
+
+pub fn save(world: &World, path: &Path) -> Result<(), SaveError> {
+    let mut out = BufWriter::new(try!(File::create(path)));
+    try!(out.write_all(MAGIC));
+    try!(bincode::serialize_into(&mut out, &SAVE_VERSION, Infinite));
+    try!(bincode::serialize_into(&mut out, &capture(world), Infinite));
+    try!(out.flush());
+    Ok(())
+}
+
+pub fn load(path: &Path) -> Result<SavedState, SaveError> {
+    let mut input = BufReader::new(try!(File::open(path)));
+    let mut magic = [0u8; 4];
+    try!(input.read_exact(&mut magic));
+    if &magic != MAGIC {
+        return Err(SaveError::Format("not a wizards save file".to_string()));
+    }
+    let version: u32 = try!(bincode::deserialize_from(&mut input, Infinite));
+    if version != SAVE_VERSION {
+        return Err(SaveError::Version(version));
+    }
+    Ok(try!(bincode::deserialize_from(&mut input, Infinite)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use specs::Join;
+    use level;
+    use net;
+    use Game;
+
+    fn game() -> Game<'static> {
+        let level = level::load(Path::new("levels/default.level")).ok().unwrap();
+        let mut game = Game::new(&level);
+        for _ in 0..10 {
+            game.step(&HashMap::new(), 1.0 / 60.0);
+        }
+        game
+    }
+
+    #[test]
+    fn round_trip() {
+        let game = game();
+        let path = env::temp_dir().join("wizards_xiii_round_trip.wiz");
+        save(&game.world, &path).unwrap();
+        let state = load(&path).unwrap();
+        let _ = ::std::fs::remove_file(&path);
+
+        let mut loaded = Game::empty();
+        let created = restore(&mut loaded.world, &state);
+        assert_eq!(created.len(), game.world.entities().join().count());
+        assert_eq!(net::checksum(&loaded.world), net::checksum(&game.world));
+        let names = |world: &World| {
+            let mut names: Vec<String> =
+                world.read::<Name>().join().map(|n| n.0.clone()).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(&loaded.world), names(&game.world));
+    }
+
+    #[test]
+    fn orphaned_projectiles_are_dropped() {
+        let game = game();
+        let mut state = capture(&game.world);
+        let count = state.entities.len();
+        state.entities.push(SavedEntity {
+            pos: Some(Pos { x: 1.0, y: 2.0 }),
+            vel: Some(Vel { x: 3.0, y: 4.0 }),
+            bounds: Some(Bounds::Circle(2.0)),
+            collision_layer: Some(Layer::Projectiles),
+            fast: true,
+            projectile: Some(SavedProjectile {
+                caster: None,
+                damage: 10.0,
+                radius: 5.0,
+            }),
+            ..SavedEntity::default()
+        });
+
+        let mut loaded = Game::empty();
+        let created = restore(&mut loaded.world, &state);
+        assert_eq!(created.len(), count);
+        assert_eq!(loaded.world.entities().join().count(), count);
+        assert!(!loaded.world.read::<Pos>().join().any(|p| p.x == 1.0 && p.y == 2.0));
+    }
+}
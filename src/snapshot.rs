@@ -0,0 +1,297 @@
+use specs::{Component, Entity, Join, World, WriteStorage};
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use systems::components::*;
+use systems::collision::{CollisionEvents, CollisionSync};
+use systems::particles::Emitter;
+use systems::query::CollisionQuery;
+
+// One entity's components, under the handle it had when captured.
+#[derive(Clone)]
+struct EntityState {
+    entity: Entity,
+    pos: Option<Pos>,
+    vel: Option<Vel>,
+    bounds: Option<Bounds>,
+    col: Option<CollisionObjectData>,
+    player: Option<Player>,
+    name: Option<Name>,
+    health: Option<Health>,
+    score: Option<Score>,
+    spells: Option<SpellBook>,
+    aim: Option<Aim>,
+    ai: Option<AiController>,
+    character: Option<CharacterController>,
+    zone: Option<Zone>,
+    projectile: Option<Projectile>,
+    // Shared with the previous snapshot while unchanged, which is nearly always.
+    terrain: Option<Rc<Terrain>>,
+    sprite: Option<Sprite>,
+    animation: Option<Animation>,
+    emitter: Option<Emitter>,
+}
+
+// The whole simulation at one instant, held in memory. Unlike a save this keeps entity
+// handles, contacts and the collision system's ids, so restoring one carries on exactly
+// where it left off. Cheap enough to take every tick.
+#[derive(Clone)]
+pub struct Snapshot {
+    entities: Vec<EntityState>,
+    turn: TurnState,
+    wind: Wind,
+    input: GameInput,
+    collision: CollisionSync,
+    events: CollisionEvents,
+    query: CollisionQuery,
+}
+
+pub fn capture(world: &World, previous: Option<&Snapshot>) -> Snapshot {
+    let entities = world.entities();
+    let (pos, vel, bounds, col) = (world.read::<Pos>(),
+                                   world.read::<Vel>(),
+                                   world.read::<Bounds>(),
+                                   world.read::<CollisionObjectData>());
+    let (player, name, health, score) = (world.read::<Player>(),
+                                         world.read::<Name>(),
+                                         world.read::<Health>(),
+                                         world.read::<Score>());
+    let (spells, aim, projectile, terrain) = (world.read::<SpellBook>(),
+                                              world.read::<Aim>(),
+                                              world.read::<Projectile>(),
+                                              world.read::<Terrain>());
+    let (sprite, animation, emitter) =
+        (world.read::<Sprite>(), world.read::<Animation>(), world.read::<Emitter>());
+    let ai = world.read::<AiController>();
+    let character = world.read::<CharacterController>();
+    let zone = world.read::<Zone>();
+
+    let share_terrain = |e: Entity, t: &Terrain| -> Rc<Terrain> {
+        let unchanged = previous.and_then(|p| p.entities.iter().find(|s| s.entity == e))
+            .and_then(|s| s.terrain.as_ref())
+            .and_then(|old| if old.revision == t.revision { Some(old.clone()) } else { None });
+        unchanged.unwrap_or_else(|| {
+            let mut copy = t.clone();
+            copy.changed.clear();
+            copy.repaint = false;
+            copy.edited = None;
+            Rc::new(copy)
+        })
+    };
+    let states = (&*entities)
+        .join()
+        .map(|e| {
+            EntityState {
+                entity: e,
+                pos: pos.get(e).cloned(),
+                vel: vel.get(e).cloned(),
+                bounds: bounds.get(e).cloned(),
+                col: col.get(e).cloned(),
+                player: player.get(e).cloned(),
+                name: name.get(e).cloned(),
+                health: health.get(e).cloned(),
+                score: score.get(e).cloned(),
+                spells: spells.get(e).cloned(),
+                aim: aim.get(e).cloned(),
+                ai: ai.get(e).cloned(),
+                character: character.get(e).cloned(),
+                zone: zone.get(e).cloned(),
+                projectile: projectile.get(e).cloned(),
+                terrain: terrain.get(e).map(|t| share_terrain(e, t)),
+                sprite: sprite.get(e).cloned(),
+                animation: animation.get(e).cloned(),
+                emitter: emitter.get(e).cloned(),
+            }
+        })
+        .collect();
+    Snapshot {
+        entities: states,
+        turn: world.read_resource::<TurnState>().clone(),
+        wind: world.read_resource::<Wind>().clone(),
+        input: world.read_resource::<GameInput>().clone(),
+        collision: world.read_resource::<CollisionSync>().clone(),
+        events: world.read_resource::<CollisionEvents>().clone(),
+        query: world.read_resource::<CollisionQuery>().clone(),
+    }
+}
+
+fn put<T: Component + Clone>(storage: &mut WriteStorage<T>, e: Entity, value: Option<&T>) {
+    match value {
+        Some(v) => {
+            storage.insert(e, v.clone());
+        }
+        None => {
+            storage.remove(e);
+        }
+    }
+}
+
+// Cells that look different between two versions of the same terrain.
+fn changed_cells(a: &Terrain, b: &Terrain) -> Vec<[usize; 2]> {
+    a.points
+        .symmetric_difference(&b.points)
+        .chain(a.scorched.symmetric_difference(&b.scorched))
+        .cloned()
+        .collect()
+}
+
+// Puts `world` back the way it was when `snapshot` was captured. Entities created since
+// are deleted; ones deleted since come back, under new handles.
+pub fn restore(world: &mut World, snapshot: &Snapshot) {
+    let keep: HashSet<Entity> = snapshot.entities.iter().map(|s| s.entity).collect();
+    {
+        let entities = world.entities();
+        for e in (&*entities).join() {
+            if !keep.contains(&e) {
+                entities.delete(e);
+            }
+        }
+    }
+    world.maintain();
+    let mut remap: HashMap<Entity, Entity> = HashMap::new();
+    for s in snapshot.entities.iter() {
+        if !world.entities().is_alive(s.entity) {
+            let e = world.create_entity().build();
+            remap.insert(s.entity, e);
+        }
+    }
+    let handle = |e: Entity| remap.get(&e).cloned().unwrap_or(e);
+    {
+        let mut pos = world.write::<Pos>();
+        let mut vel = world.write::<Vel>();
+        let mut bounds = world.write::<Bounds>();
+        let mut col = world.write::<CollisionObjectData>();
+        let mut player = world.write::<Player>();
+        let mut name = world.write::<Name>();
+        let mut health = world.write::<Health>();
+        let mut score = world.write::<Score>();
+        let mut spells = world.write::<SpellBook>();
+        let mut aim = world.write::<Aim>();
+        let mut ai = world.write::<AiController>();
+        let mut character = world.write::<CharacterController>();
+        let mut zone = world.write::<Zone>();
+        let mut projectile = world.write::<Projectile>();
+        let mut terrain = world.write::<Terrain>();
+        let mut sprite = world.write::<Sprite>();
+        let mut animation = world.write::<Animation>();
+        let mut emitter = world.write::<Emitter>();
+        for s in snapshot.entities.iter() {
+            let e = handle(s.entity);
+            put(&mut pos, e, s.pos.as_ref());
+            put(&mut vel, e, s.vel.as_ref());
+            put(&mut bounds, e, s.bounds.as_ref());
+            let c = s.col.as_ref().map(|c| {
+                CollisionObjectData {
+                    layer: c.layer,
+                    contacts: c.contacts
+                        .iter()
+                        .map(|(&other, ps)| (handle(other), ps.clone()))
+                        .collect(),
+                    sensor: c.sensor,
+                    overlaps: c.overlaps.iter().map(|&other| handle(other)).collect(),
+                    ignore: c.ignore.iter().map(|&(other, left)| (handle(other), left)).collect(),
+                    fast: c.fast,
+                    rest: c.rest,
+                    current_bounds: c.current_bounds.clone(),
+                }
+            });
+            put(&mut col, e, c.as_ref());
+            put(&mut player, e, s.player.as_ref());
+            put(&mut name, e, s.name.as_ref());
+            put(&mut health, e, s.health.as_ref());
+            put(&mut score, e, s.score.as_ref());
+            put(&mut spells, e, s.spells.as_ref());
+            put(&mut aim, e, s.aim.as_ref());
+            put(&mut ai, e, s.ai.as_ref());
+            put(&mut character, e, s.character.as_ref());
+            put(&mut zone, e, s.zone.as_ref());
+            let p = s.projectile.as_ref().map(|p| {
+                Projectile {
+                    caster: handle(p.caster),
+                    damage: p.damage,
+                    radius: p.radius,
+                }
+            });
+            put(&mut projectile, e, p.as_ref());
+            put(&mut sprite, e, s.sprite.as_ref());
+            put(&mut animation, e, s.animation.as_ref());
+            put(&mut emitter, e, s.emitter.as_ref());
+
+            // Only copy terrain back if it changed, and tell the renderer which cells did.
+            match s.terrain {
+                Some(ref t) => {
+                    let changed = match terrain.get(e) {
+                        Some(current) if current.revision == t.revision => None,
+                        Some(current) => Some(changed_cells(current, t)),
+                        None => Some(vec![]),
+                    };
+                    if let Some(changed) = changed {
+                        let mut copy = (**t).clone();
+                        for &p in changed.iter() {
+                            copy.mark_edited(p, p);
+                            copy.note_changed(p);
+                        }
+                        terrain.insert(e, copy);
+                    }
+                }
+                None => {
+                    terrain.remove(e);
+                }
+            }
+        }
+    }
+    *world.write_resource::<TurnState>() = snapshot.turn.clone();
+    *world.write_resource::<Wind>() = snapshot.wind.clone();
+    *world.write_resource::<GameInput>() = snapshot.input.clone();
+    let events = snapshot.events.0.iter().map(|event| event.remap(&handle)).collect();
+    *world.write_resource::<CollisionEvents>() = CollisionEvents(events);
+    *world.write_resource::<CollisionQuery>() = snapshot.query.remap(&handle);
+
+    // Entities that came back under a new index take their collision ids with them.
+    let moved: HashMap<usize, usize> = remap.iter()
+        .filter(|&(old, new)| old.id() != new.id())
+        .map(|(old, new)| (old.id() as usize, new.id() as usize))
+        .collect();
+    let mut collision = snapshot.collision.clone();
+    if !moved.is_empty() {
+        collision.ids.remap(|(eid, part)| (moved.get(&eid).cloned().unwrap_or(eid), part));
+    }
+    // The collision world may hold objects from after the snapshot; start it afresh.
+    collision.rebuild = true;
+    *world.write_resource::<CollisionSync>() = collision;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use level;
+    use net::{Simulation, TICK};
+    use Game;
+
+    // Every player holding a changing mix of actions, the same on every call.
+    fn inputs(tick: u64) -> HashMap<i32, Actions> {
+        (1..3).map(|p| (p, Actions(((tick / 20) as u8).wrapping_mul(37 + p as u8)))).collect()
+    }
+
+    #[test]
+    fn restoring_replays_identically() {
+        let level = level::load(Path::new("levels/default.level")).ok().unwrap();
+        let mut game = Game::new(&level);
+        for tick in 0..120 {
+            game.step(&inputs(tick), TICK);
+        }
+        let snapshot = capture(&game.world, None);
+        let mut sums = vec![];
+        for tick in 120..240 {
+            game.step(&inputs(tick), TICK);
+            sums.push(game.checksum());
+        }
+        restore(&mut game.world, &snapshot);
+        for (i, tick) in (120..240).enumerate() {
+            game.step(&inputs(tick), TICK);
+            assert_eq!(game.checksum(), sums[i], "diverged on tick {}", tick);
+        }
+    }
+}
@@ -0,0 +1,418 @@
+use specs::{ReadStorage, System, WriteStorage, Join, Fetch, FetchMut};
+
+use std::cmp::Ordering;
+use std::f64::consts::PI;
+
+use systems::character::STEP_UP;
+use systems::components::*;
+use systems::navigation::{NavGraph, NAV_SPACING};
+use systems::spells::{launch_reach, AIM_SPEED, BLAST_REACH, CHARGE_SPEED, GRAVITY};
+
+// How long it walks for before settling for whatever shot it has.
+const WALK_TIME: f64 = 3.0;
+// The horizontal distance it likes to shoot from.
+const MIN_RANGE: f64 = 120.0;
+const MAX_RANGE: f64 = 350.0;
+// How far ahead it looks for the ground while walking, how far above its feet a ledge
+// can be and still count as ground, and how far down it looks.
+const LOOK_AHEAD: f64 = 20.0;
+const STEP_HEIGHT: f64 = 60.0;
+const MAX_DROP: usize = 1000;
+// Keeps it out of its own blast when the shot lands a bit short.
+const BLAST_MARGIN: f64 = 1.2;
+// Flight times tried when solving for a shot.
+const MIN_FLIGHT: f64 = 0.1;
+const MAX_FLIGHT: f64 = 6.0;
+const FLIGHT_STEP: f64 = 0.02;
+// How far it can stray from its planned path before planning again.
+const OFF_PATH: f64 = 40.0;
+
+struct Tuning {
+    // Seconds before it starts moving on its turn.
+    reaction: f64,
+    // Standard deviations of the error added to a solved angle and power.
+    angle_error: f64,
+    power_error: f64,
+}
+
+fn tuning(difficulty: Difficulty) -> Tuning {
+    match difficulty {
+        Difficulty::Easy => {
+            Tuning {
+                reaction: 1.5,
+                angle_error: 0.12,
+                power_error: 0.12,
+            }
+        }
+        Difficulty::Normal => {
+            Tuning {
+                reaction: 0.8,
+                angle_error: 0.05,
+                power_error: 0.05,
+            }
+        }
+        Difficulty::Hard => {
+            Tuning {
+                reaction: 0.3,
+                angle_error: 0.01,
+                power_error: 0.01,
+            }
+        }
+    }
+}
+
+// Uniform in [0, 1), from a xorshift64* generator.
+fn random(seed: &mut u64) -> f64 {
+    let mut x = *seed;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    *seed = x;
+    (x.wrapping_mul(0x2545F4914F6CDD1D) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Roughly normal with a standard deviation of one.
+fn noise(seed: &mut u64) -> f64 {
+    (random(seed) + random(seed) + random(seed) - 1.5) * 2.0
+}
+
+fn distance(a: [f64; 2], b: [f64; 2]) -> f64 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)).sqrt()
+}
+
+// The launch angle and speed that carry a projectile from `from` through `to` under a
+// constant `accel`, taking the slowest such shot. None if that's still over `max_speed`.
+pub fn solve_shot(from: [f64; 2],
+                  to: [f64; 2],
+                  accel: [f64; 2],
+                  max_speed: f64)
+                  -> Option<(f64, f64)> {
+    let d = [to[0] - from[0], to[1] - from[1]];
+    let mut best: Option<([f64; 2], f64)> = None;
+    let mut t = MIN_FLIGHT;
+    while t <= MAX_FLIGHT {
+        let v = [d[0] / t - 0.5 * accel[0] * t, d[1] / t - 0.5 * accel[1] * t];
+        let speed = (v[0] * v[0] + v[1] * v[1]).sqrt();
+        if best.map_or(true, |(_, s)| speed < s) {
+            best = Some((v, speed));
+        }
+        t += FLIGHT_STEP;
+    }
+    best.and_then(|(v, speed)| if speed <= max_speed {
+        Some((v[1].atan2(v[0]), speed))
+    } else {
+        None
+    })
+}
+
+// The top of the ground in column `x`, looking down from a little above `feet`.
+fn ground(terrain: &[&Terrain], x: f64, feet: f64) -> Option<f64> {
+    if x < 0.0 {
+        return None;
+    }
+    let top = (feet - STEP_HEIGHT).max(0.0) as usize;
+    (top..top + MAX_DROP)
+        .find(|&y| terrain.iter().any(|t| t.points.contains(&[x as usize, y])))
+        .map(|y| y as f64)
+}
+
+struct Wizard<'b> {
+    player: i32,
+    pos: [f64; 2],
+    half_height: f64,
+    // How far out its spells are launched from.
+    reach: f64,
+    aim: &'b Aim,
+    book: &'b SpellBook,
+}
+
+struct Surroundings<'b> {
+    terrain: Vec<&'b Terrain>,
+    nav: &'b NavGraph,
+    // Every living wizard.
+    wizards: Vec<(i32, [f64; 2])>,
+    wind: f64,
+    delta: f64,
+    turn: u32,
+    // Goes up whenever any terrain is carved or painted.
+    terrain_revision: u64,
+}
+
+// Walks in `dir` (-1, 0 or 1), jumping up anything too tall to step onto.
+fn walk(me: &Wizard, around: &Surroundings, dir: f64) -> Actions {
+    let mut actions = Actions::default();
+    if dir < 0.0 {
+        actions.insert(action::LEFT);
+    } else if dir > 0.0 {
+        actions.insert(action::RIGHT);
+    }
+    let feet = me.pos[1] + me.half_height;
+    if let Some(y) = ground(&around.terrain, me.pos[0] + dir * LOOK_AHEAD, feet) {
+        // Anything taller than a step needs a jump.
+        if me.pos[1] - (y - me.half_height) > STEP_UP {
+            actions.insert(action::UP);
+        }
+    }
+    actions
+}
+
+// Which way to walk to get to `to`, following the navigation graph where there's a path.
+// The path is planned once a turn and kept until the target or the terrain changes, or
+// the wizard ends up well away from it.
+fn heading(ai: &mut AiController, me: &Wizard, around: &Surroundings, to: [f64; 2]) -> f64 {
+    let feet = [me.pos[0], me.pos[1] + me.half_height];
+    let key = (around.turn, ai.target.unwrap_or(0), around.terrain_revision);
+    let nearest = |path: &[[f64; 2]]| {
+        path.iter()
+            .map(|&p| distance(p, feet))
+            .enumerate()
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal))
+    };
+    let stray = nearest(&ai.path).map_or(true, |(_, d)| d > OFF_PATH);
+    if ai.path_for != Some(key) || stray {
+        ai.path = around.nav
+            .path(feet, [to[0], to[1] + me.half_height])
+            .map_or(vec![], |steps| steps.into_iter().map(|s| s.pos).collect());
+        ai.path_for = Some(key);
+    }
+    let next = nearest(&ai.path).and_then(|(k, _)| {
+        ai.path[k..]
+            .iter()
+            .map(|p| p[0])
+            .find(|&x| (x - me.pos[0]).abs() > NAV_SPACING as f64 / 2.0)
+    });
+    (next.unwrap_or(to[0]) - me.pos[0]).signum()
+}
+
+fn nearest_enemy(me: &Wizard, around: &Surroundings) -> Option<i32> {
+    around.wizards
+        .iter()
+        .filter(|&&(p, _)| p != me.player)
+        .min_by(|a, b| {
+            distance(me.pos, a.1).partial_cmp(&distance(me.pos, b.1)).unwrap_or(Ordering::Equal)
+        })
+        .map(|&(p, _)| p)
+}
+
+// The hardest hitting ready spell that reaches `target` without catching the caster,
+// with the angle and power to cast it at, plus however much the difficulty misses by.
+fn plan(ai: &mut AiController,
+        me: &Wizard,
+        around: &Surroundings,
+        target: [f64; 2])
+        -> Option<(usize, f64, f64)> {
+    let accel = [around.wind, GRAVITY];
+    let apart = distance(me.pos, target);
+    let mut best: Option<(usize, f64, f64)> = None;
+    for (i, spell) in me.book.spells.iter().enumerate() {
+        let ready = me.book.cooldowns.get(i).map_or(false, |&c| c <= 0.0);
+        if !ready || apart <= spell.radius * BLAST_REACH * BLAST_MARGIN {
+            continue;
+        }
+        if best.map_or(false, |(j, _, _)| me.book.spells[j].damage >= spell.damage) {
+            continue;
+        }
+        // Where it launches from depends on the angle, so solve again from there.
+        let mut solved = solve_shot(me.pos, target, accel, spell.speed);
+        if let Some((angle, _)) = solved {
+            let from = [me.pos[0] + angle.cos() * me.reach, me.pos[1] + angle.sin() * me.reach];
+            solved = solve_shot(from, target, accel, spell.speed);
+        }
+        if let Some((angle, speed)) = solved {
+            best = Some((i, angle, speed / spell.speed));
+        }
+    }
+    let tuning = tuning(ai.difficulty);
+    best.map(|(i, angle, power)| {
+        let angle = angle + noise(&mut ai.seed) * tuning.angle_error;
+        let power = power + noise(&mut ai.seed) * tuning.power_error;
+        (i, angle.max(-PI).min(PI), power.max(0.0).min(1.0))
+    })
+}
+
+// Picks the planned spell, turns to the planned angle, then charges and lets go.
+fn cast(ai: &mut AiController, me: &Wizard, around: &Surroundings, last: Actions) -> Actions {
+    let mut actions = Actions::default();
+    let (spell, angle, power) = match ai.shot {
+        Some(shot) => shot,
+        None => {
+            ai.phase = AiPhase::Done;
+            return actions;
+        }
+    };
+    if me.book.active != spell {
+        // The spell changes on the press, so let go in between.
+        if !last.contains(action::NEXT_SPELL) {
+            actions.insert(action::NEXT_SPELL);
+        }
+        return actions;
+    }
+    if !me.aim.charging {
+        let step = AIM_SPEED * around.delta / 2.0;
+        if me.aim.angle > angle + step {
+            actions.insert(action::AIM_UP);
+        } else if me.aim.angle < angle - step {
+            actions.insert(action::AIM_DOWN);
+        } else if me.book.ready() {
+            actions.insert(action::CAST);
+        }
+    } else if me.aim.power + CHARGE_SPEED * around.delta / 2.0 < power {
+        actions.insert(action::CAST);
+    } else {
+        debug!("ai cast player={} spell={} angle={:.3} power={:.3}",
+               me.player,
+               spell,
+               angle,
+               power);
+        ai.phase = AiPhase::Done;
+    }
+    actions
+}
+
+fn play(ai: &mut AiController, me: &Wizard, around: &Surroundings, last: Actions) -> Actions {
+    if ai.turn != around.turn {
+        ai.turn = around.turn;
+        ai.phase = AiPhase::Thinking;
+        ai.timer = tuning(ai.difficulty).reaction;
+        ai.shot = None;
+    }
+    if ai.target.map_or(true, |t| !around.wizards.iter().any(|&(p, _)| p == t)) {
+        ai.target = nearest_enemy(me, around);
+    }
+    let target = match ai.target.and_then(|t| around.wizards.iter().find(|&&(p, _)| p == t)) {
+        Some(&(_, at)) => at,
+        None => return walk(me, around, 0.0),
+    };
+    match ai.phase {
+        AiPhase::Thinking => {
+            ai.timer -= around.delta;
+            if ai.timer <= 0.0 {
+                ai.phase = AiPhase::Walking;
+                ai.timer = WALK_TIME;
+            }
+            walk(me, around, 0.0)
+        }
+        AiPhase::Walking => {
+            ai.timer -= around.delta;
+            let dx = target[0] - me.pos[0];
+            if (dx.abs() >= MIN_RANGE && dx.abs() <= MAX_RANGE) || ai.timer <= 0.0 {
+                let shot = plan(ai, me, around, target);
+                ai.shot = shot;
+                if shot.is_some() {
+                    ai.phase = AiPhase::Casting;
+                    return walk(me, around, 0.0);
+                }
+                if ai.timer <= 0.0 {
+                    debug!("ai found no shot player={} target={:?}", me.player, ai.target);
+                    ai.phase = AiPhase::Done;
+                    return walk(me, around, 0.0);
+                }
+            }
+            let dir = if dx.abs() < MIN_RANGE {
+                -dx.signum()
+            } else {
+                heading(ai, me, around, target)
+            };
+            walk(me, around, dir)
+        }
+        AiPhase::Casting => Actions(cast(ai, me, around, last).0 | walk(me, around, 0.0).0),
+        AiPhase::Done => walk(me, around, 0.0),
+    }
+}
+
+// Plays every wizard with an `AiController`, replacing whatever input its player had.
+// Runs inside the simulation, so every peer and spectator comes to the same decisions.
+pub struct AiSystem;
+
+impl<'a> System<'a> for AiSystem {
+    type SystemData = (ReadStorage<'a, Player>,
+     ReadStorage<'a, Pos>,
+     ReadStorage<'a, Bounds>,
+     ReadStorage<'a, Health>,
+     ReadStorage<'a, Aim>,
+     ReadStorage<'a, SpellBook>,
+     ReadStorage<'a, Terrain>,
+     WriteStorage<'a, AiController>,
+     Fetch<'a, NavGraph>,
+     Fetch<'a, TurnState>,
+     Fetch<'a, Wind>,
+     Fetch<'a, Delta>,
+     FetchMut<'a, GameInput>);
+    fn run(&mut self,
+           (player, pos, bounds, health, aim, books, terrain, mut ai, nav, turn, wind, delta,
+            mut input): Self::SystemData) {
+        let around = Surroundings {
+            terrain: (&terrain).join().collect(),
+            nav: &nav,
+            wizards: (&player, &pos, &health)
+                .join()
+                .filter(|&(_, _, h)| h.alive())
+                .map(|(p, pos, _)| (p.0, [pos.x, pos.y]))
+                .collect(),
+            wind: wind.speed,
+            delta: delta.0,
+            turn: turn.turn,
+            terrain_revision: (&terrain).join().map(|t| t.revision).sum(),
+        };
+        for (p, pos, bounds, health, aim, book, ai) in
+            (&player, &pos, &bounds, &health, &aim, &books, &mut ai).join() {
+            let actions = if health.alive() && turn.active == p.0 {
+                let me = Wizard {
+                    player: p.0,
+                    pos: [pos.x, pos.y],
+                    half_height: bounds.half_extents()[1],
+                    reach: launch_reach(Some(bounds)),
+                    aim: aim,
+                    book: book,
+                };
+                play(ai, &me, &around, input.last(p.0))
+            } else {
+                Actions::default()
+            };
+            input.actions.insert(p.0, actions);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use net::TICK;
+    use systems::spells::PROJECTILE_RADIUS;
+
+    // How close a shot launched at `angle` and `speed` from `from` passes to `to`, stepped
+    // the way the projectile and position systems move it.
+    fn miss(from: [f64; 2], to: [f64; 2], accel: [f64; 2], angle: f64, speed: f64) -> f64 {
+        let (mut p, mut v) = (from, [angle.cos() * speed, angle.sin() * speed]);
+        let mut closest = distance(p, to);
+        for _ in 0..(MAX_FLIGHT / TICK) as usize {
+            v = [v[0] + accel[0] * TICK, v[1] + accel[1] * TICK];
+            let next = [p[0] + v[0] * TICK, p[1] + v[1] * TICK];
+            // Nearest point to the target along this step.
+            let step = [next[0] - p[0], next[1] - p[1]];
+            let along = ((to[0] - p[0]) * step[0] + (to[1] - p[1]) * step[1]) /
+                        (step[0] * step[0] + step[1] * step[1]);
+            let along = along.max(0.0).min(1.0);
+            closest = closest.min(distance([p[0] + step[0] * along, p[1] + step[1] * along], to));
+            p = next;
+        }
+        closest
+    }
+
+    #[test]
+    fn solved_shots_hit_the_target() {
+        for &(to, wind) in [([400.0, 300.0], 30.0), ([250.0, 150.0], -40.0), ([20.0, 340.0], 0.0)]
+            .iter() {
+            let (from, accel) = ([100.0, 300.0], [wind, GRAVITY]);
+            let (angle, speed) = solve_shot(from, to, accel, 400.0).unwrap();
+            assert!(speed <= 400.0);
+            let off = miss(from, to, accel, angle, speed);
+            assert!(off < PROJECTILE_RADIUS, "missed {:?} by {} in wind {}", to, off, wind);
+        }
+    }
+
+    #[test]
+    fn shots_too_fast_to_cast_are_refused() {
+        assert!(solve_shot([100.0, 300.0], [400.0, 300.0], [0.0, GRAVITY], 50.0).is_none());
+    }
+}
@@ -0,0 +1,102 @@
+use specs::{ReadStorage, System, WriteStorage, Join, Fetch, FetchMut, Entities};
+
+use systems::components::*;
+use systems::particles::*;
+use std::iter::*;
+
+pub struct AnimationSystem;
+
+const MOVING: f64 = 1.0;
+
+fn locomotion(vel: Option<&Vel>, grounded: bool) -> AnimState {
+    match vel {
+        Some(v) if !grounded && v.y.abs() > MOVING => AnimState::Jump,
+        Some(v) if v.x.abs() > MOVING => AnimState::Walk,
+        _ => AnimState::Idle,
+    }
+}
+
+fn next_state(anim: &Animation, events: &[AnimEvent], vel: Option<&Vel>, grounded: bool) -> AnimState {
+    if anim.state == AnimState::Death {
+        return AnimState::Death;
+    }
+    if events.contains(&AnimEvent::Death) {
+        return AnimState::Death;
+    }
+    if events.contains(&AnimEvent::Hurt) {
+        return AnimState::Hurt;
+    }
+    if events.contains(&AnimEvent::Cast) && anim.state != AnimState::Hurt {
+        return AnimState::Cast;
+    }
+    match anim.state {
+        AnimState::Cast | AnimState::Hurt if !anim.finished() => anim.state,
+        _ => locomotion(vel, grounded),
+    }
+}
+
+fn advance(anim: &mut Animation, dt: f64) {
+    let clip = match anim.clip() {
+        Some(c) => *c,
+        None => return,
+    };
+    anim.timer += dt;
+    while clip.frame_time > 0.0 && anim.timer >= clip.frame_time {
+        anim.timer -= clip.frame_time;
+        if anim.frame + 1 < clip.frames {
+            anim.frame += 1;
+        } else if clip.looping {
+            anim.frame = 0;
+        } else {
+            anim.timer = 0.0;
+            break;
+        }
+    }
+}
+
+impl<'a> System<'a> for AnimationSystem {
+    type SystemData = (Entities<'a>,
+     WriteStorage<'a, Animation>,
+     WriteStorage<'a, Sprite>,
+     ReadStorage<'a, Vel>,
+     ReadStorage<'a, Pos>,
+     ReadStorage<'a, Bounds>,
+     ReadStorage<'a, CollisionObjectData>,
+     FetchMut<'a, AnimationEvents>,
+     FetchMut<'a, Bursts>,
+     Fetch<'a, Delta>);
+    fn run(&mut self,
+           (ent, mut anim, mut sprite, vel, pos, bounds, col, mut events, mut bursts, delta):
+               Self::SystemData) {
+        let events = events.0.drain(..).collect::<Vec<_>>();
+        for (e, anim) in (&*ent, &mut anim).join() {
+            let mine = Vec::from_iter(events.iter().filter(|&&(t, _)| t == e).map(|&(_, ev)| ev));
+            let grounded = col.get(e).map_or(false, |c| c.contacts.values().any(|v| !v.is_empty()));
+            let state = next_state(anim, &mine, vel.get(e), grounded);
+            let last_frame = anim.frame;
+            if state != anim.state {
+                anim.state = state;
+                anim.frame = 0;
+                anim.timer = 0.0;
+            } else {
+                advance(anim, delta.0);
+            }
+            if let Some(pos) = pos.get(e) {
+                let feet = bounds.get(e).map_or(0.0, |b| b.half_extents()[1]);
+                if mine.contains(&AnimEvent::Cast) {
+                    bursts.push([pos.x, pos.y], 20, EmitterConfig::spell_cast());
+                }
+                let stepped = anim.frame != last_frame && anim.frame % 2 == 0;
+                if anim.state == AnimState::Walk && grounded && stepped {
+                    bursts.push([pos.x, pos.y + feet], 4, EmitterConfig::footstep());
+                }
+            }
+            if let (Some(sprite), Some(clip)) = (sprite.get_mut(e), anim.clip()) {
+                sprite.frame = clip.first + anim.frame;
+            }
+            if anim.despawn_when_done && anim.finished() {
+                ent.delete(e);
+            }
+        }
+    }
+}
@@ -1,52 +1,154 @@
 use specs::{ReadStorage, System, VecStorage, World,
-            WriteStorage, Join, Fetch, HashMapStorage};
-use piston_window::Button::Keyboard;
-use piston_window::Button;
-use piston_window::Key::*;
+            WriteStorage, Join, Fetch, HashMapStorage, Entities};
 
 use systems::components::*;
+use systems::collision::CollisionLayers;
+use systems::query::{CollisionQuery, QueryFilter};
 
-use std::collections::HashSet;
+// How far past the point of impact a swept object is left, so the collision system sees
+// it touching.
+const CCD_OVERLAP: f64 = 0.1;
 
 pub struct UpdatePositionSystem;
 
 impl<'a> System<'a> for UpdatePositionSystem {
-    type SystemData = (WriteStorage<'a, Pos>, ReadStorage<'a, Vel>, Fetch<'a, Delta>);
-    fn run(&mut self, (mut pos, vel, delta): Self::SystemData) {
-        for (pos, vel) in (&mut pos, &vel).join() {
-            pos.x += vel.x * delta.0;
-            pos.y += vel.y * delta.0;
+    type SystemData = (Entities<'a>,
+     WriteStorage<'a, Pos>,
+     ReadStorage<'a, Vel>,
+     ReadStorage<'a, Bounds>,
+     ReadStorage<'a, CollisionObjectData>,
+     Fetch<'a, CollisionQuery>,
+     Fetch<'a, CollisionLayers>,
+     Fetch<'a, Delta>);
+    fn run(&mut self,
+           (ent, mut pos, vel, bounds, col, query, layers, delta): Self::SystemData) {
+        for (e, pos, vel) in (&*ent, &mut pos, &vel).join() {
+            let to = [pos.x + vel.x * delta.0, pos.y + vel.y * delta.0];
+            // Fast objects stop where they'd first touch something they collide with.
+            // The query holds the world as of the last collision run, which is what
+            // anything else is compared against until the next one.
+            let hit = match (col.get(e), bounds.get(e)) {
+                (Some(c), Some(b)) if c.fast && !c.sensor => {
+                    let mut solid = layers.colliding_with(c.layer);
+                    solid.retain(|&l| l != Layer::Sensors);
+                    let mut filter = QueryFilter::layers(&solid).excluding(e);
+                    for &(other, _) in c.ignore.iter() {
+                        filter = filter.excluding(other);
+                    }
+                    query.sweep(b, [pos.x, pos.y], to, &filter)
+                }
+                _ => None,
+            };
+            match hit {
+                Some(hit) => {
+                    let travel = vel.x.hypot(vel.y) * delta.0;
+                    let t = ((hit.distance + CCD_OVERLAP) / travel).min(1.0);
+                    trace!("swept entity={} stopped at distance={} by={}",
+                           e.id(),
+                           hit.distance,
+                           hit.entity.id());
+                    pos.x += vel.x * delta.0 * t;
+                    pos.y += vel.y * delta.0 * t;
+                }
+                None => {
+                    pos.x = to[0];
+                    pos.y = to[1];
+                }
+            }
         }
     }
 }
 
 pub struct UpdateControlSystem;
-fn get_vel(pid: i32, buttons: &HashSet<Button>) -> Vel {
+fn get_vel(actions: Actions) -> Vel {
     let mut vel = Vel { x: 0.0, y: 0.0 };
-    let (up, down, left, right) = match pid {
-        2 => (Up, Down, Left, Right),
-        _ => (W, S, A, D),
-    };
     let speed = 50.0;
-    if buttons.contains(&Keyboard(up)) {
+    if actions.contains(action::UP) {
         vel.y = -speed;
     }
-    if buttons.contains(&Keyboard(down)) {
+    if actions.contains(action::DOWN) {
         vel.y = speed;
     }
-    if buttons.contains(&Keyboard(left)) {
+    if actions.contains(action::LEFT) {
         vel.x = -speed;
     }
-    if buttons.contains(&Keyboard(right)) {
+    if actions.contains(action::RIGHT) {
         vel.x = speed;
     }
     vel
 }
+// Flies players without a `CharacterController` straight where the keys say.
 impl<'a> System<'a> for UpdateControlSystem {
-    type SystemData = (ReadStorage<'a, Player>, WriteStorage<'a, Vel>, Fetch<'a, GameInput>);
-    fn run(&mut self, (player, mut vel, gi): Self::SystemData) {
-        for (p, mut vel) in (&player, &mut vel).join() {
-            *vel = get_vel(p.0, &gi.0);
+    type SystemData = (Entities<'a>,
+     ReadStorage<'a, Player>,
+     ReadStorage<'a, Health>,
+     ReadStorage<'a, CharacterController>,
+     WriteStorage<'a, Vel>,
+     Fetch<'a, GameInput>);
+    fn run(&mut self, (ent, player, health, controller, mut vel, gi): Self::SystemData) {
+        for (e, p, mut vel) in (&*ent, &player, &mut vel).join() {
+            if controller.get(e).is_some() {
+                continue;
+            }
+            *vel = if health.get(e).map_or(true, |h| h.alive()) {
+                get_vel(gi.get(p.0))
+            } else {
+                Vel { x: 0.0, y: 0.0 }
+            };
         }
     }
-}
\ No newline at end of file
+}
+pub struct SpriteFacingSystem;
+
+impl<'a> System<'a> for SpriteFacingSystem {
+    type SystemData = (WriteStorage<'a, Sprite>, ReadStorage<'a, Vel>);
+    fn run(&mut self, (mut sprite, vel): Self::SystemData) {
+        for (sprite, vel) in (&mut sprite, &vel).join() {
+            if vel.x < 0.0 {
+                sprite.flip_x = true;
+            } else if vel.x > 0.0 {
+                sprite.flip_x = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use Game;
+    use level;
+    use net::TICK;
+    use systems::spells::PROJECTILE_RADIUS;
+
+    #[test]
+    fn fast_projectile_stops_at_a_thin_wall() {
+        // A wall one cell thick, and a projectile crossing a hundred cells a step. Left
+        // without the rest of a spell so it carries on pressing against the wall.
+        let source = "terrain rect 400 0 1 400 rock\nspawn 100 450\nspawn 700 450\n";
+        let mut game = Game::new(&level::parse(source).ok().unwrap());
+        let idle = HashMap::new();
+        game.step(&idle, TICK);
+        let shot = game.world
+            .create_entity()
+            .with(Pos { x: 200.0, y: 200.0 })
+            .with(Vel { x: 6000.0, y: 0.0 })
+            .with(Bounds::Circle(PROJECTILE_RADIUS))
+            .with(CollisionObjectData {
+                fast: true,
+                ..CollisionObjectData::new(Layer::Projectiles)
+            })
+            .build();
+        for tick in 0..10 {
+            game.step(&idle, TICK);
+            let x = game.world.read::<Pos>().get(shot).unwrap().x;
+            assert!(x < 400.0 - PROJECTILE_RADIUS + 1.0,
+                    "through the wall to x={} on tick {}",
+                    x,
+                    tick);
+        }
+        let x = game.world.read::<Pos>().get(shot).unwrap().x;
+        assert!((x - (400.0 - PROJECTILE_RADIUS)).abs() < 1.0);
+    }
+}
@@ -0,0 +1,265 @@
+use specs::{Entities, Fetch, Join, ReadStorage, System, WriteStorage};
+
+use std::f64::consts::PI;
+
+use systems::components::*;
+use systems::query::{CollisionQuery, QueryFilter, QueryHit};
+use systems::spells::GRAVITY;
+
+// Speed along the ground when walking, and how quickly velocity turns towards it in the
+// air, per second.
+const WALK_SPEED: f64 = 50.0;
+const AIR_CONTROL: f64 = 3.0;
+const JUMP_SPEED: f64 = 160.0;
+const MAX_FALL_SPEED: f64 = 400.0;
+// How long after walking off an edge a jump still works.
+const COYOTE_TIME: f64 = 0.1;
+// Steepest ground that can be stood on, from flat.
+const MAX_SLOPE: f64 = PI / 4.0;
+// How far below its feet ground still counts as underfoot, and how far in from its
+// sides it looks for ground.
+const GROUND_PROBE: f64 = 2.0;
+const GROUND_INSET: f64 = 1.0;
+// Tallest ledge it walks up without jumping, and how far ahead it looks for one.
+pub const STEP_UP: f64 = 8.0;
+const STEP_AHEAD: f64 = 4.0;
+// Speed away from the ground above which it's leaving it rather than standing on it.
+const RISING: f64 = 1.0;
+// Seconds a blast takes control away for.
+pub const KNOCKBACK_TIME: f64 = 0.6;
+
+// How far the shape reaches below its position, `dx` along from it.
+fn reach_below(bounds: &Bounds, dx: f64) -> f64 {
+    match bounds {
+        &Bounds::Circle(r) => (r * r - dx * dx).max(0.0).sqrt(),
+        _ => bounds.half_extents()[1],
+    }
+}
+
+// The flattest walkable ground under the middle and either side of it. The sides are
+// looked under too since on a slope it rests on one of its bottom corners, well above the
+// ground under its middle.
+fn find_ground(query: &CollisionQuery,
+               filter: &QueryFilter,
+               pos: [f64; 2],
+               bounds: &Bounds)
+               -> Option<QueryHit> {
+    let half = bounds.half_extents();
+    let walkable = MAX_SLOPE.cos();
+    let mut best: Option<QueryHit> = None;
+    let side = (half[0] - GROUND_INSET).max(0.0);
+    for &dx in [0.0, -side, side].iter() {
+        let reach = reach_below(bounds, dx) + GROUND_PROBE;
+        if let Some(hit) = query.raycast([pos[0] + dx, pos[1]], [0.0, 1.0], reach, filter) {
+            if -hit.normal[1] >= walkable &&
+               best.map_or(true, |b| hit.normal[1] < b.normal[1]) {
+                best = Some(hit);
+            }
+        }
+    }
+    best
+}
+
+// How far to lift it so it walks up a ledge in front of it, if there's one low enough.
+// Slopes it can walk up aren't ledges; the collision system carries it up those.
+fn step_up(query: &CollisionQuery,
+           filter: &QueryFilter,
+           pos: [f64; 2],
+           bounds: &Bounds,
+           dir: f64)
+           -> Option<f64> {
+    let half = bounds.half_extents();
+    let feet = pos[1] + reach_below(bounds, 0.0);
+    let ahead = half[0] + STEP_AHEAD;
+    let walkable = MAX_SLOPE.cos();
+    let blocked = query.raycast([pos[0], feet - 1.0], [dir, 0.0], ahead, filter)
+        .and_then(|wall| if -wall.normal[1] < walkable { Some(wall) } else { None });
+    blocked.and_then(|wall| {
+        let top = [wall.point[0] + dir, feet - STEP_UP - 1.0];
+        query.raycast(top, [0.0, 1.0], STEP_UP + 1.0, filter)
+            .map(|ledge| feet - ledge.point[1])
+            .and_then(|lift| if lift > 0.0 && lift <= STEP_UP { Some(lift) } else { None })
+    })
+}
+
+// Moves wizards like a platformer: walking along the ground, up small ledges and slopes
+// that aren't too steep, jumping, and falling with a little steering in the air. While
+// knocked back it only falls.
+pub struct CharacterSystem;
+
+impl<'a> System<'a> for CharacterSystem {
+    type SystemData = (Entities<'a>,
+     ReadStorage<'a, Player>,
+     ReadStorage<'a, Health>,
+     ReadStorage<'a, Bounds>,
+     WriteStorage<'a, CharacterController>,
+     WriteStorage<'a, Pos>,
+     WriteStorage<'a, Vel>,
+     Fetch<'a, CollisionQuery>,
+     Fetch<'a, GameInput>,
+     Fetch<'a, Delta>);
+    fn run(&mut self,
+           (ent, player, health, bounds, mut controller, mut pos, mut vel, query, input, delta)
+           : Self::SystemData) {
+        let dt = delta.0;
+        for (e, p, health, bounds, cc, pos, vel) in
+            (&*ent, &player, &health, &bounds, &mut controller, &mut pos, &mut vel).join() {
+            let filter = QueryFilter::layers(&[Layer::Terrain, Layer::Players]).excluding(e);
+            let ground = find_ground(&query, &filter, [pos.x, pos.y], bounds);
+            // Still rising from a jump or a blast isn't standing on anything. Measured
+            // against the ground's normal, since walking up a slope moves upwards too.
+            let (vx, vy) = (vel.x, vel.y);
+            cc.grounded = ground.map_or(false, |g| vx * g.normal[0] + vy * g.normal[1] <= RISING);
+            cc.ground_normal = ground.map_or([0.0, -1.0], |g| g.normal);
+            cc.air_time = if cc.grounded { 0.0 } else { cc.air_time + dt };
+            cc.knockback = (cc.knockback - dt).max(0.0);
+
+            let actions = if health.alive() && cc.knockback == 0.0 {
+                input.get(p.0)
+            } else {
+                Actions::default()
+            };
+            let mut dir = 0.0;
+            if actions.contains(action::LEFT) {
+                dir -= 1.0;
+            }
+            if actions.contains(action::RIGHT) {
+                dir += 1.0;
+            }
+
+            if cc.knockback > 0.0 {
+                // Carried along by the blast.
+            } else if cc.grounded {
+                // Along the ground rather than into or off it.
+                let n = cc.ground_normal;
+                vel.x = dir * WALK_SPEED * -n[1];
+                vel.y = dir * WALK_SPEED * n[0];
+                if dir != 0.0 {
+                    if let Some(lift) = step_up(&query, &filter, [pos.x, pos.y], bounds, dir) {
+                        pos.y -= lift;
+                    }
+                }
+            } else {
+                vel.x += (dir * WALK_SPEED - vel.x) * (AIR_CONTROL * dt).min(1.0);
+            }
+
+            let jump = actions.contains(action::UP);
+            if jump && !cc.jump_held && cc.air_time < COYOTE_TIME {
+                vel.y = -JUMP_SPEED;
+                cc.grounded = false;
+                cc.air_time = COYOTE_TIME;
+            }
+            cc.jump_held = jump;
+            vel.y = (vel.y + GRAVITY * dt).min(MAX_FALL_SPEED);
+            if cc.grounded {
+                // The ground holds it up, so nothing is left pressing into it. A wizard
+                // standing still is then still, and the collision system can let it sleep.
+                let n = cc.ground_normal;
+                let into = vel.x * n[0] + vel.y * n[1];
+                if into < 0.0 {
+                    vel.x -= into * n[0];
+                    vel.y -= into * n[1];
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use Game;
+    use level;
+    use net::TICK;
+
+    // A hill whose sides get steeper all the way down, from flat at the top (400, 400) to
+    // past `MAX_SLOPE` left of x = 188, and a ledge (100..300, 400) away from it.
+    const HILL: &'static str = "terrain rect 0 900 10 10 rock\nprop circle 400 700 300\n";
+    const LEDGE: &'static str = "terrain rect 100 400 200 100 rock\n";
+
+    fn game(source: &str, first: [f64; 2], second: [f64; 2]) -> Game<'static> {
+        let source = format!("{}spawn {} {}\nspawn {} {}\n",
+                             source,
+                             first[0],
+                             first[1],
+                             second[0],
+                             second[1]);
+        Game::new(&level::parse(&source).ok().unwrap())
+    }
+
+    // Steps with the first player holding `held`, and returns where it ended up, how fast
+    // it's going and whether it's on the ground.
+    fn step(game: &mut Game, held: u8) -> ([f64; 2], [f64; 2], bool) {
+        let inputs: HashMap<i32, Actions> = vec![(1, Actions(held))].into_iter().collect();
+        game.step(&inputs, TICK);
+        let (player, pos, vel, cc) = (game.world.read::<Player>(),
+                                      game.world.read::<Pos>(),
+                                      game.world.read::<Vel>(),
+                                      game.world.read::<CharacterController>());
+        let (_, pos, vel, cc) = (&player, &pos, &vel, &cc)
+            .join()
+            .find(|&(p, _, _, _)| p.0 == 1)
+            .unwrap();
+        ([pos.x, pos.y], [vel.x, vel.y], cc.grounded)
+    }
+
+    #[test]
+    fn walks_up_a_slope_it_can_stand_on() {
+        // Dropped onto the hill where it's about 35 degrees, resting on its right corner.
+        let mut game = game(HILL, [200.0, 427.0], [900.0, 0.0]);
+        for _ in 0..30 {
+            step(&mut game, 0);
+        }
+        let (start, _, grounded) = step(&mut game, 0);
+        assert!(grounded);
+        let mut end = start;
+        for tick in 0..120 {
+            let (pos, _, grounded) = step(&mut game, action::RIGHT);
+            assert!(grounded, "left the ground on tick {} at {:?}", tick, pos);
+            end = pos;
+        }
+        assert!(end[0] > start[0] + 60.0);
+        assert!(end[1] < start[1] - 30.0);
+    }
+
+    #[test]
+    fn slides_down_a_slope_too_steep_to_stand_on() {
+        // Dropped onto the hill where it's about 55 degrees, and trying to climb it.
+        let mut game = game(HILL, [130.0, 498.0], [900.0, 0.0]);
+        let (start, _, _) = step(&mut game, action::RIGHT);
+        let mut end = start;
+        for tick in 0..60 {
+            let (pos, _, grounded) = step(&mut game, action::RIGHT);
+            assert!(!grounded, "stood on the slope on tick {} at {:?}", tick, pos);
+            end = pos;
+        }
+        assert!(end[0] < start[0]);
+        assert!(end[1] > start[1] + 10.0);
+    }
+
+    // Walks the first player off the right of the ledge, then jumps `late` ticks after
+    // the ground went from under it. Returns its vertical speed just after.
+    fn jump_off_ledge(late: usize) -> f64 {
+        let mut game = game(LEDGE, [200.0, 370.0], [130.0, 370.0]);
+        for _ in 0..30 {
+            step(&mut game, 0);
+        }
+        let mut ticks = 0;
+        while step(&mut game, action::RIGHT).2 {
+            ticks += 1;
+            assert!(ticks < 300, "never walked off the ledge");
+        }
+        for _ in 0..late {
+            step(&mut game, action::RIGHT);
+        }
+        step(&mut game, action::RIGHT | action::UP).1[1]
+    }
+
+    #[test]
+    fn jumps_just_after_walking_off_a_ledge() {
+        assert!(jump_off_ledge(2) < -JUMP_SPEED / 2.0);
+        let late = (COYOTE_TIME / TICK) as usize + 2;
+        assert!(jump_off_ledge(late) > 0.0);
+    }
+}
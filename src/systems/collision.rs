@@ -1,26 +1,271 @@
 use specs::{Component, DispatcherBuilder, Dispatcher, ReadStorage, System, VecStorage, World,
-            WriteStorage, Join, Fetch, HashMapStorage, Entities, Entity};
+            WriteStorage, Join, Fetch, FetchMut, HashMapStorage, Entities, Entity};
 
 use ncollide::world::*;
+use ncollide::query::{self, Proximity};
 use ncollide::shape::*;
 use nalgebra as na;
 use nalgebra::*;
 use systems::components::*;
 use std;
 use std::iter::*;
+use std::cmp::Ordering;
 use std::sync::Arc;
 use ncollide::partitioning::*;
 use ncollide::bounding_volume::*;
 use std::collections::HashSet;
 use std::collections::HashMap;
+use std::collections::BTreeMap;
+use systems::decompose::{contains, convex_pieces, nearest_on};
 use systems::id_store::*;
-pub struct CollisionSystem(CollisionWorld2<f64, Entity>, IdMap<(usize, usize)>);
+use systems::profile::Profiler;
+use systems::query::CollisionQuery;
+// The last field is the `CollisionLayers` revision the world's objects were added under.
+pub struct CollisionSystem(CollisionWorld2<f64, Entity>, IdMap<(usize, usize)>, Profiler, u64);
+
+// Which layers collide with which, always symmetric. Changing it rebuilds the collision
+// world on the next run, since objects take their groups when they're added.
+#[derive(Clone)]
+pub struct CollisionLayers {
+    interacts: [[bool; LAYERS]; LAYERS],
+    pub revision: u64,
+}
+
+impl CollisionLayers {
+    pub fn new() -> CollisionLayers {
+        let mut layers = CollisionLayers {
+            interacts: [[false; LAYERS]; LAYERS],
+            revision: 0,
+        };
+        for &(a, b) in [(Layer::Players, Layer::Players),
+                        (Layer::Players, Layer::Projectiles),
+                        (Layer::Players, Layer::Terrain),
+                        (Layer::Players, Layer::Sensors),
+                        (Layer::Projectiles, Layer::Terrain),
+                        (Layer::Projectiles, Layer::Sensors),
+                        (Layer::Debris, Layer::Terrain)]
+            .iter() {
+            layers.set(a, b, true);
+        }
+        layers.revision = 0;
+        layers
+    }
+
+    pub fn interacts(&self, a: Layer, b: Layer) -> bool {
+        self.interacts[a.index()][b.index()]
+    }
+
+    // The layers a `layer` object collides with.
+    pub fn colliding_with(&self, layer: Layer) -> Vec<Layer> {
+        Layer::all().iter().cloned().filter(|&other| self.interacts(layer, other)).collect()
+    }
+
+    pub fn set(&mut self, a: Layer, b: Layer, on: bool) {
+        self.interacts[a.index()][b.index()] = on;
+        self.interacts[b.index()][a.index()] = on;
+        self.revision += 1;
+    }
+
+    fn groups(&self, layer: Layer) -> CollisionGroups {
+        let whitelist: Vec<usize> = Layer::all()
+            .iter()
+            .filter(|&&other| self.interacts(layer, other))
+            .map(|l| l.index())
+            .collect();
+        let mut cg = CollisionGroups::new();
+        cg.set_membership(&[layer.index()]);
+        cg.set_whitelist(&whitelist);
+        cg
+    }
+}
+
+// Depth contacts are left at, so resting bodies stay touching from one run to the next
+// instead of being pushed clear and falling back in.
+const SLOP: f64 = 0.05;
+// Share of the remaining depth each solver pass corrects.
+const CORRECTION: f64 = 0.8;
+// Share of a pair's push in the last run that the first pass may apply straight away.
+const WARM_START: f64 = 0.8;
+const SOLVER_PASSES: usize = 10;
+// A body slower than this, and pushed no further than `SLEEP_MOTION` a run, for
+// `SLEEP_TIME` seconds falls asleep: the solver treats it as static until it's woken,
+// which speeding up again does straight away.
+const SLEEP_SPEED: f64 = 1.0;
+const SLEEP_MOTION: f64 = 0.1;
+const SLEEP_TIME: f64 = 0.5;
+
+fn moving(vel: &ReadStorage<Vel>, e: Entity) -> bool {
+    vel.get(e).map_or(false, |v| v.x.hypot(v.y) > SLEEP_SPEED)
+}
+
+// Whether either entity is passing through the other.
+fn ignored(col: &WriteStorage<CollisionObjectData>, a: Entity, b: Entity) -> bool {
+    col.get(a).map_or(false, |c| c.ignores(b)) || col.get(b).map_or(false, |c| c.ignores(a))
+}
+
+// The collision system's id allocation, copied out after every run so that world
+// snapshots can include it. Setting `rebuild` makes the system start its next run from
+// an empty collision world and these ids, re-adding objects from the current bounds.
+// Contacts only depend on where objects are, so a rebuilt world carries on exactly as
+// the old one would have.
+#[derive(Clone)]
+pub struct CollisionSync {
+    pub ids: IdMap<(usize, usize)>,
+    pub rebuild: bool,
+}
+
+impl CollisionSync {
+    pub fn new() -> CollisionSync {
+        CollisionSync {
+            ids: IdMap::new(),
+            rebuild: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Contact {
+    pub entities: (Entity, Entity),
+    // Matching points on the first and second entity, in world coordinates.
+    pub points: Vec<([f64; 2], [f64; 2])>,
+    // Of the deepest point, pointing from the first entity towards the second.
+    pub normal: [f64; 2],
+    pub depth: f64,
+    // How far the solver moved the pair apart this run, over all its passes. A distance
+    // rather than an impulse: the solver corrects positions and leaves velocities be.
+    pub correction: f64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Overlap {
+    pub sensor: Entity,
+    pub other: Entity,
+}
+
+#[derive(Clone, Debug)]
+pub enum CollisionEvent {
+    ContactStarted(Contact),
+    ContactPersisted(Contact),
+    ContactEnded(Contact),
+    SensorEntered(Overlap),
+    SensorExited(Overlap),
+}
+
+impl CollisionEvent {
+    pub fn contact(&self) -> Option<&Contact> {
+        match self {
+            &CollisionEvent::ContactStarted(ref c) |
+            &CollisionEvent::ContactPersisted(ref c) |
+            &CollisionEvent::ContactEnded(ref c) => Some(c),
+            _ => None,
+        }
+    }
+
+    pub fn entities(&self) -> (Entity, Entity) {
+        match self {
+            &CollisionEvent::ContactStarted(ref c) |
+            &CollisionEvent::ContactPersisted(ref c) |
+            &CollisionEvent::ContactEnded(ref c) => c.entities,
+            &CollisionEvent::SensorEntered(o) |
+            &CollisionEvent::SensorExited(o) => (o.sensor, o.other),
+        }
+    }
+
+    pub fn involves(&self, e: Entity) -> bool {
+        let (a, b) = self.entities();
+        a == e || b == e
+    }
+
+    // The same event with every entity passed through `f`.
+    pub fn remap<F: Fn(Entity) -> Entity>(&self, f: F) -> CollisionEvent {
+        let contact = |c: &Contact| {
+            Contact {
+                entities: (f(c.entities.0), f(c.entities.1)),
+                ..c.clone()
+            }
+        };
+        let overlap = |o: Overlap| {
+            Overlap {
+                sensor: f(o.sensor),
+                other: f(o.other),
+            }
+        };
+        match self {
+            &CollisionEvent::ContactStarted(ref c) => CollisionEvent::ContactStarted(contact(c)),
+            &CollisionEvent::ContactPersisted(ref c) => CollisionEvent::ContactPersisted(contact(c)),
+            &CollisionEvent::ContactEnded(ref c) => CollisionEvent::ContactEnded(contact(c)),
+            &CollisionEvent::SensorEntered(o) => CollisionEvent::SensorEntered(overlap(o)),
+            &CollisionEvent::SensorExited(o) => CollisionEvent::SensorExited(overlap(o)),
+        }
+    }
+}
+
+// What changed between the last two collision runs, in a fixed order. Replaced on every
+// run, so systems in the dispatcher see the events of the tick before, as they do
+// `CollisionObjectData::contacts`.
+#[derive(Clone, Default)]
+pub struct CollisionEvents(pub Vec<CollisionEvent>);
+
+impl CollisionEvents {
+    // Contacts that were touching as of the last run.
+    pub fn touching(&self) -> Vec<&Contact> {
+        self.0
+            .iter()
+            .filter_map(|e| match e {
+                &CollisionEvent::ContactStarted(ref c) |
+                &CollisionEvent::ContactPersisted(ref c) => Some(c),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+type PairKey = (u32, u32);
+
+// Pairs are keyed by entity index so they come out in the same order on every peer.
+fn pair_key(a: Entity, b: Entity) -> PairKey {
+    if a.id() <= b.id() { (a.id(), b.id()) } else { (b.id(), a.id()) }
+}
+
+fn contact_events(previous: &BTreeMap<PairKey, Contact>,
+                  current: BTreeMap<PairKey, Contact>)
+                  -> Vec<CollisionEvent> {
+    let mut events = vec![];
+    for (key, contact) in previous.iter() {
+        if !current.contains_key(key) {
+            events.push(CollisionEvent::ContactEnded(Contact {
+                correction: 0.0,
+                ..contact.clone()
+            }));
+        }
+    }
+    for (key, contact) in current {
+        events.push(if previous.contains_key(&key) {
+            CollisionEvent::ContactPersisted(contact)
+        } else {
+            CollisionEvent::ContactStarted(contact)
+        });
+    }
+    events
+}
+
+fn overlap_events(previous: &BTreeMap<PairKey, Overlap>,
+                  current: &BTreeMap<PairKey, Overlap>)
+                  -> Vec<CollisionEvent> {
+    let exited = previous.iter()
+        .filter(|&(key, _)| !current.contains_key(key))
+        .map(|(_, &o)| CollisionEvent::SensorExited(o));
+    let entered = current.iter()
+        .filter(|&(key, _)| !previous.contains_key(key))
+        .map(|(_, &o)| CollisionEvent::SensorEntered(o));
+    exited.chain(entered).collect()
+}
 
 trait UpdateableCollision {
     fn get_current_part_ids<F>(&self, &mut F) -> HashMap<usize, usize>
         where F: FnMut(usize) -> usize;
     fn get_shape_handle(&self, usize) -> Option<ShapeHandle2<f64>>;
-    fn get_position_for_part(&self, usize) -> Option<[usize; 2]>;
+    fn get_position_for_part(&self, usize) -> Option<([f64; 2], f64)>;
     fn parts_changed(&self, &Self) -> HashSet<usize>;
 }
 fn get_point_grid_id(w: usize, h: usize, x: usize, y: usize) -> usize {
@@ -45,10 +290,14 @@ impl UpdateableCollision for Bounds {
     fn get_current_part_ids<F>(&self, get: &mut F) -> HashMap<usize, usize>
         where F: FnMut(usize) -> usize
     {
+        let count = match self {
+            &Bounds::Compound(_) => self.parts().len(),
+            _ => 1,
+        };
         let mut map = HashMap::new();
-        let i = get(0);
-        map.insert(i, 0);
-
+        for part in 0..count {
+            map.insert(get(part), part);
+        }
         map
     }
 
@@ -56,28 +305,149 @@ impl UpdateableCollision for Bounds {
         match self {
             &Bounds::Rectangle(x, y) => Some(ShapeHandle::new(create_rectangle(x, y))),
             &Bounds::Circle(r) => Some(ShapeHandle::new(create_circle(r))),
-            &Bounds::Polygon(ref ps) => Some(ShapeHandle::new(create_polygon(ps))),
+            &Bounds::Polygon(ref ps) => Some(create_solid(ps)),
+            &Bounds::Compound(_) => {
+                self.parts().get(index).and_then(|part| part.shape.get_shape_handle(0))
+            }
         }
     }
 
-    fn get_position_for_part(&self, index: usize) -> Option<[usize; 2]> {
-
-        Some([0, 0])
-
+    fn get_position_for_part(&self, index: usize) -> Option<([f64; 2], f64)> {
+        match self {
+            &Bounds::Compound(_) => self.parts().get(index).map(|part| (part.offset, part.angle)),
+            _ => Some(([0.0, 0.0], 0.0)),
+        }
     }
-    fn parts_changed(&self, old: &Self) -> HashSet<usize> {
 
+    fn parts_changed(&self, old: &Self) -> HashSet<usize> {
         let mut h = HashSet::new();
-        if *self != *old {
-            h.insert(0);
+        match (self, old) {
+            (&Bounds::Compound(_), _) |
+            (_, &Bounds::Compound(_)) => {
+                let (new, old) = (self.parts(), old.parts());
+                for k in 0..new.len().max(old.len()) {
+                    if new.get(k) != old.get(k) {
+                        h.insert(k);
+                    }
+                }
+            }
+            _ => {
+                if *self != *old {
+                    h.insert(0);
+                }
+            }
         }
         h
     }
 }
+
+// One shape for the whole of `bounds`, as the collision world would place its parts.
+pub fn shape_of(bounds: &Bounds) -> Option<ShapeHandle2<f64>> {
+    match bounds {
+        &Bounds::Compound(_) => {
+            let parts: Vec<(Isometry2<f64>, ShapeHandle2<f64>)> = bounds.parts()
+                .iter()
+                .filter_map(|part| {
+                    let at = Isometry2::new(Vector2::new(part.offset[0], part.offset[1]),
+                                            part.angle);
+                    part.shape.get_shape_handle(0).map(|shape| (at, shape))
+                })
+                .collect();
+            if parts.is_empty() {
+                None
+            } else {
+                Some(ShapeHandle::new(Compound::new(parts)))
+            }
+        }
+        _ => bounds.get_shape_handle(0),
+    }
+}
+
+// Where two objects touch: matching points on each, the normal from the first towards
+// the second, and how deep.
+struct Touch {
+    points: ([f64; 2], [f64; 2]),
+    normal: [f64; 2],
+    depth: f64,
+}
+
+// The deepest contact between two objects, worked out from where they are now. The
+// world's own contact manifolds keep points from earlier runs, which a rebuilt world
+// doesn't have, so they're only used to find the pairs worth asking about.
+fn touch(co1: &CollisionObject2<f64, Entity>,
+         co2: &CollisionObject2<f64, Entity>)
+         -> Option<Touch> {
+    query::contact(&co1.position,
+                   co1.shape.as_ref(),
+                   &co2.position,
+                   co2.shape.as_ref(),
+                   0.0)
+        .map(|c| {
+            Touch {
+                points: ([c.world1[0], c.world1[1]], [c.world2[0], c.world2[1]]),
+                normal: [c.normal[0], c.normal[1]],
+                depth: c.depth,
+            }
+        })
+}
+
+// Every pair of entities that's touching, with the points from all of their parts and
+// the deepest part's normal, turned from the lower entity index towards the higher. The
+// world hands pairs out in no particular order, so they're sorted before being merged.
+// Parts of one entity never touch each other.
+fn contacts(world: &CollisionWorld2<f64, Entity>,
+            col: &WriteStorage<CollisionObjectData>)
+            -> BTreeMap<PairKey, Contact> {
+    let mut found = vec![];
+    for (co1, co2, _) in world.contact_pairs() {
+        let (e1, e2) = (co1.data, co2.data);
+        if e1 == e2 || ignored(col, e1, e2) {
+            continue;
+        }
+        if let Some(t) = touch(co1, co2) {
+            if e1.id() <= e2.id() {
+                found.push((pair_key(e1, e2), e1, e2, t));
+            } else {
+                let flipped = Touch {
+                    points: (t.points.1, t.points.0),
+                    normal: [-t.normal[0], -t.normal[1]],
+                    depth: t.depth,
+                };
+                found.push((pair_key(e1, e2), e2, e1, flipped));
+            }
+        }
+    }
+    found.sort_by(|a, b| {
+        let (pa, pb) = ((a.3.points.0, a.3.points.1), (b.3.points.0, b.3.points.1));
+        a.0.cmp(&b.0).then_with(|| pa.partial_cmp(&pb).unwrap_or(Ordering::Equal))
+    });
+    let mut merged: BTreeMap<PairKey, Contact> = BTreeMap::new();
+    for (key, e1, e2, t) in found {
+        let contact = merged.entry(key).or_insert_with(|| {
+            Contact {
+                entities: (e1, e2),
+                points: vec![],
+                normal: t.normal,
+                depth: t.depth,
+                correction: 0.0,
+            }
+        });
+        if t.depth > contact.depth {
+            contact.normal = t.normal;
+            contact.depth = t.depth;
+        }
+        contact.points.push(t.points);
+    }
+    merged
+}
+
+fn new_world() -> CollisionWorld2<f64, Entity> {
+    CollisionWorld::new(0.02, false)
+}
+
 impl CollisionSystem {
-    pub fn new() -> Self {
-        let world = CollisionWorld::new(0.02, false);
-        CollisionSystem(world, IdMap::new())
+    pub fn new(profiler: &Profiler) -> Self {
+        CollisionSystem(new_world(), IdMap::new(), profiler.clone(), 0)
     }
 }
 
@@ -87,6 +457,26 @@ fn create_circle(r: f64) -> Ball2<f64> {
 fn create_rectangle(x: f64, y: f64) -> Cuboid2<f64> {
     Cuboid::new(Vector2::new(x / 2.0, y / 2.0))
 }
+// Outlines are cut into convex pieces so anything that gets inside is pushed back out,
+// keeping just the line around them only if they can't be cut up.
+fn create_solid(ps: &Box<Vec<[f64; 2]>>) -> ShapeHandle2<f64> {
+    match convex_pieces(ps) {
+        Some(pieces) => {
+            let parts: Vec<(Isometry2<f64>, ShapeHandle2<f64>)> = pieces.into_iter()
+                .map(|piece| {
+                    let points = piece.iter().map(|p| Point2::new(p[0], p[1])).collect();
+                    (Isometry2::identity(), ShapeHandle::new(ConvexHull::new(points)))
+                })
+                .collect();
+            debug!("split outline vertices={} pieces={}", ps.len(), parts.len());
+            ShapeHandle::new(Compound::new(parts))
+        }
+        None => {
+            warn!("outline could not be split into convex pieces vertices={}", ps.len());
+            ShapeHandle::new(create_polygon(ps))
+        }
+    }
+}
 fn create_polygon(ps: &Box<Vec<[f64; 2]>>) -> Polyline2<f64> {
     let max_index = ps.len();
     let points = Vec::from_iter(ps[..].into_iter().map(|p| Point2::new(p[0], p[1])));
@@ -109,9 +499,15 @@ impl<'a> CollisionSystem {
             match &col.current_bounds {
                 &Some(ref b) => {
                     for p in bounds.parts_changed(b) {
+                        // Parts can come and go, e.g. as terrain is carved into islands.
                         let id = idmap.get((eid, p));
-                        println!("Removing entity {:?}", id);
-                        world.deferred_remove(id);
+                        if world.collision_object(id).is_some() {
+                            debug!("removing collision object entity={} part={} id={}",
+                                   eid,
+                                   p,
+                                   id);
+                            world.deferred_remove(id);
+                        }
                         idmap.release((eid, p));
                     }
                 }
@@ -120,34 +516,61 @@ impl<'a> CollisionSystem {
         }
         world.update();
     }
+    // Removes the objects of entities that were deleted or lost their `Bounds` or
+    // `CollisionObjectData`, and gives their ids back. An entity index that was reused
+    // since counts as deleted, since the world's objects hold the old handle.
+    fn remove_dead(&mut self,
+                   ent: &Entities<'a>,
+                   col: &WriteStorage<'a, CollisionObjectData>,
+                   bounds: &ReadStorage<'a, Bounds>) {
+        let world = &mut self.0;
+        let idmap = &mut self.1;
+        let live: HashSet<usize> =
+            (&**ent, col, bounds).join().map(|(e, _, _)| e.id() as usize).collect();
+        for ((eid, part), id) in idmap.entries() {
+            let stale = match world.collision_object(id) {
+                Some(co) => !ent.is_alive(co.data) || !live.contains(&eid),
+                None => !live.contains(&eid),
+            };
+            if stale {
+                if world.collision_object(id).is_some() {
+                    debug!("removing collision object of removed entity={} part={} id={}",
+                           eid,
+                           part,
+                           id);
+                    world.deferred_remove(id);
+                }
+                idmap.release((eid, part));
+            }
+        }
+        world.update();
+    }
     fn update_collisions(&mut self,
                          ent: &Entities<'a>,
                          pos: &WriteStorage<'a, Pos>,
                          col: &mut WriteStorage<'a, CollisionObjectData>,
-                         bounds: &ReadStorage<'a, Bounds>) {
+                         bounds: &ReadStorage<'a, Bounds>,
+                         layers: &CollisionLayers) {
         let world = &mut self.0;
         let idmap = &mut self.1;
         for (ent, pos, col, bounds) in (&**ent, pos, col, bounds).join() {
             let eid = ent.id() as usize;
             for (&id, &part) in bounds.get_current_part_ids(&mut |x| idmap.get((eid, x))).iter() {
-                if let Some(p) = bounds.get_position_for_part(part) {
+                if let Some((offset, angle)) = bounds.get_position_for_part(part) {
                     {
-                        let p = Isometry2::new(Vector2::new(pos.x + (p[0] as f64),
-                                                            pos.y + (p[1] as f64)),
-                                               na::zero());
+                        let p = Isometry2::new(Vector2::new(pos.x + offset[0], pos.y + offset[1]),
+                                               angle);
                         if let Some(_) = world.collision_object(id) {
                             world.deferred_set_position(id, p)
                         } else {
                             if let Some(shape) = bounds.get_shape_handle(part) {
-                                let mut cg = CollisionGroups::new();
-                                cg.set_membership(&[col.group_id]);
-                                cg.set_blacklist(&[col.group_id]);
-                                world.deferred_add(id,
-                                                   p,
-                                                   shape,
-                                                   cg,
-                                                   GeometricQueryType::Contacts(0.0),
-                                                   ent);
+                                let cg = layers.groups(col.layer);
+                                let query = if col.sensor {
+                                    GeometricQueryType::Proximity(0.0)
+                                } else {
+                                    GeometricQueryType::Contacts(0.0)
+                                };
+                                world.deferred_add(id, p, shape, cg, query, ent);
                                 let b = (*bounds).clone();
                                 col.current_bounds = Some(b);
                             }
@@ -159,69 +582,236 @@ impl<'a> CollisionSystem {
         world.update();
     }
 
-    fn basic_physics(&mut self,
-                     pos: &mut WriteStorage<'a, Pos>,
-                     vel: &ReadStorage<'a, Vel>)
-                     -> bool {
-        let world = &mut self.0;
+    // Moves bodies whose middle has ended up inside a solid outline out to the nearest
+    // point on it. Contacts can't be trusted that deep: the convex piece it's in may be
+    // nearest an edge inside the outline rather than its surface.
+    fn eject_buried(pos: &mut WriteStorage<'a, Pos>,
+                    vel: &ReadStorage<'a, Vel>,
+                    bounds: &ReadStorage<'a, Bounds>,
+                    col: &WriteStorage<'a, CollisionObjectData>,
+                    ent: &Entities<'a>,
+                    layers: &CollisionLayers) {
+        let mut solids = vec![];
+        for (e, p, b, c) in (&**ent, &*pos, bounds, col).join() {
+            if vel.get(e).is_some() || c.sensor {
+                continue;
+            }
+            // Every outline counts, islands included. Holes are joined on to their
+            // outlines, so whatever is in a cave stays there. Turned parts are left to
+            // the contacts.
+            for part in b.parts().into_iter().filter(|part| part.angle == 0.0) {
+                if let Bounds::Polygon(outline) = part.shape {
+                    let origin = [p.x + part.offset[0], p.y + part.offset[1]];
+                    solids.push((e, origin, outline, c.layer));
+                }
+            }
+        }
+        for (e, p, _, b, c) in (&**ent, pos, vel, bounds, col).join() {
+            if c.sensor {
+                continue;
+            }
+            for &(solid, origin, ref outline, layer) in solids.iter() {
+                if !layers.interacts(c.layer, layer) || ignored(col, e, solid) {
+                    continue;
+                }
+                let local = [p.x - origin[0], p.y - origin[1]];
+                if !contains(outline, local) {
+                    continue;
+                }
+                if let Some(q) = nearest_on(outline, local) {
+                    let (dx, dy) = (q[0] - local[0], q[1] - local[1]);
+                    let d = dx.hypot(dy);
+                    let out = if d > 0.0 { [dx / d, dy / d] } else { [0.0, -1.0] };
+                    let half = b.half_extents();
+                    let clear = match b {
+                        &Bounds::Circle(r) => r,
+                        _ => half[0] * out[0].abs() + half[1] * out[1].abs(),
+                    };
+                    debug!("ejecting buried entity={} from={} distance={:.1}",
+                           e.id(),
+                           solid.id(),
+                           d + clear);
+                    p.x = origin[0] + q[0] + out[0] * clear;
+                    p.y = origin[1] + q[1] + out[1] * clear;
+                }
+            }
+        }
+    }
+
+    // One pass of the contact solver over the pairs the world last found. Each pair is
+    // pushed apart along its deepest contact, split between the two bodies by inverse
+    // mass, so static and sleeping bodies don't move at all. `warm` holds last run's push
+    // for each pair, which the first pass may repeat in one go.
+    fn solve(&mut self,
+             pos: &mut WriteStorage<'a, Pos>,
+             vel: &ReadStorage<'a, Vel>,
+             bounds: &ReadStorage<'a, Bounds>,
+             col: &WriteStorage<'a, CollisionObjectData>,
+             asleep: &HashSet<Entity>,
+             warm: Option<&BTreeMap<PairKey, f64>>,
+             pushed: &mut BTreeMap<PairKey, f64>,
+             moved: &mut HashMap<Entity, f64>)
+             -> bool {
+        let world = &self.0;
+        let pairs: Vec<_> = contacts(world, col)
+            .into_iter()
+            .map(|(key, c)| (key, c.entities.0, c.entities.1, c.normal, c.depth))
+            .collect();
+
+        let inverse_mass = |e: Entity| if asleep.contains(&e) || vel.get(e).is_none() {
+            0.0
+        } else {
+            1.0 / bounds.get(e).map_or(1.0, |b| b.area().max(1.0))
+        };
         let mut dirty = false;
-        for (e1, e2, ca) in world.contact_pairs() {
-            let mut contacts = std::vec::Vec::new();
-            ca.contacts(&mut contacts);
-            for contact in contacts {
-                let mut move_vec = contact.normal * contact.depth * -0.5;
-                {
-                    if let Some(p1) = pos.get_mut(e1.data) {
-                        if let Some(_) = vel.get(e1.data) {
-                            dirty = true;
-                            p1.x += move_vec.x;
-                            p1.y += move_vec.y;
-                        }
-                    }
+        for (key, e1, e2, normal, depth) in pairs {
+            let (w1, w2) = (inverse_mass(e1), inverse_mass(e2));
+            if w1 + w2 == 0.0 {
+                continue;
+            }
+            let target = (depth - SLOP).max(0.0);
+            let mut push = target * CORRECTION;
+            if let Some(&last) = warm.and_then(|w| w.get(&key)) {
+                push = push.max((last * WARM_START).min(target));
+            }
+            if push <= 0.0 {
+                continue;
+            }
+            dirty = true;
+            *pushed.entry(key).or_insert(0.0) += push;
+            for &(e, share, sign) in [(e1, w1 / (w1 + w2), -1.0), (e2, w2 / (w1 + w2), 1.0)]
+                .iter() {
+                if share == 0.0 {
+                    continue;
                 }
-                move_vec *= -1.0;
-                {
-                    if let Some(p2) = pos.get_mut(e2.data) {
-                        if let Some(_) = vel.get(e2.data) {
-                            dirty = true;
-                            p2.x += move_vec.x;
-                            p2.y += move_vec.y;
-                        }
-                    }
+                if let Some(p) = pos.get_mut(e) {
+                    p.x += normal[0] * push * share * sign;
+                    p.y += normal[1] * push * share * sign;
+                    *moved.entry(e).or_insert(0.0) += push * share;
                 }
             }
         }
-        return dirty;
+        dirty
+    }
 
+    // Counts how long each body has been still. Bodies that moved, were pushed, or are
+    // touching something that's moving start counting again from zero.
+    fn update_sleep(ent: &Entities<'a>,
+                    col: &mut WriteStorage<'a, CollisionObjectData>,
+                    vel: &ReadStorage<'a, Vel>,
+                    moved: &HashMap<Entity, f64>,
+                    touching: &BTreeMap<PairKey, Contact>,
+                    dt: f64) {
+        let mut woken: HashSet<Entity> = HashSet::new();
+        for contact in touching.values() {
+            let (a, b) = contact.entities;
+            if moving(vel, a) {
+                woken.insert(b);
+            }
+            if moving(vel, b) {
+                woken.insert(a);
+            }
+        }
+        for (e, col) in (&**ent, col).join() {
+            if vel.get(e).is_none() {
+                continue;
+            }
+            let still = !moving(vel, e) &&
+                        moved.get(&e).cloned().unwrap_or(0.0) <= SLEEP_MOTION &&
+                        !woken.contains(&e);
+            col.rest = if still { col.rest + dt } else { 0.0 };
+        }
     }
-    fn clear_collision_objects(col: &mut WriteStorage<'a, CollisionObjectData>) {
+    fn clear_collision_objects(col: &mut WriteStorage<'a, CollisionObjectData>, dt: f64) {
         for mut col in col.join() {
             col.contacts.clear();
+            col.overlaps.clear();
+            for ignore in col.ignore.iter_mut() {
+                ignore.1 -= dt;
+            }
+            col.ignore.retain(|&(_, left)| left > 0.0);
         }
     }
-    fn update_collision_objects(&mut self, col: &mut WriteStorage<'a, CollisionObjectData>) {
-        let world = &mut self.0;
 
-        for (e1, e2, ca) in world.contact_pairs() {
-            let mut contacts = std::vec::Vec::new();
-            ca.contacts(&mut contacts);
-            let mut p1 = vec![];
-            let mut p2 = vec![];
-            for contact in contacts {
-                p1.push([contact.world1[0], contact.world1[1]]);
-                p2.push([contact.world2[0], contact.world2[1]]);
-            }
-            if let Some(col) = col.get_mut(e1.data) {
-                if !p1.is_empty() {
-                    println!("e1 contacts {:?}", p1);
-                }
-                col.contacts.insert(e2.data, p1);
+    // Sensor overlaps as the last run left them.
+    fn current_overlaps(ent: &Entities<'a>,
+                        col: &WriteStorage<'a, CollisionObjectData>)
+                        -> BTreeMap<PairKey, Overlap> {
+        let mut overlaps = BTreeMap::new();
+        for (sensor, col) in (&**ent, col).join().filter(|&(_, c)| c.sensor) {
+            for &other in col.overlaps.iter() {
+                overlaps.insert(pair_key(sensor, other),
+                                Overlap {
+                                    sensor: sensor,
+                                    other: other,
+                                });
             }
-            if let Some(col) = col.get_mut(e2.data) {
-                if !p2.is_empty() {
-                    println!("e2 contacts {:?}", p2);
+        }
+        overlaps
+    }
+
+    fn update_overlaps(&self, col: &mut WriteStorage<'a, CollisionObjectData>) {
+        let world = &self.0;
+        for (o1, o2, detector) in world.proximity_pairs() {
+            if detector.proximity() != Proximity::Intersecting {
+                continue;
+            }
+            let (a, b) = (o1.data, o2.data);
+            if a == b || ignored(col, a, b) {
+                continue;
+            }
+            if let Some(col) = col.get_mut(a) {
+                col.overlaps.insert(b);
+            }
+            if let Some(col) = col.get_mut(b) {
+                col.overlaps.insert(a);
+            }
+        }
+    }
+    fn update_collision_objects(&mut self,
+                                col: &mut WriteStorage<'a, CollisionObjectData>,
+                                touching: &mut BTreeMap<PairKey, Contact>) {
+        for (key, contact) in contacts(&self.0, col) {
+            let (e1, e2) = contact.entities;
+            trace!("contact e1={:?} e2={:?} depth={:.3}", e1, e2, contact.depth);
+            if let Some(col) = col.get_mut(e1) {
+                col.contacts
+                    .entry(e2)
+                    .or_insert_with(Vec::new)
+                    .extend(contact.points.iter().map(|p| p.0));
+            }
+            if let Some(col) = col.get_mut(e2) {
+                col.contacts
+                    .entry(e1)
+                    .or_insert_with(Vec::new)
+                    .extend(contact.points.iter().map(|p| p.1));
+            }
+            touching.insert(key, contact);
+        }
+    }
+}
+
+impl<'a> CollisionSystem {
+    fn export_debug(&self, overlay: &DebugOverlay, debug: &mut CollisionDebug) {
+        let world = &self.0;
+        debug.contacts.clear();
+        debug.aabbs.clear();
+        if overlay.contacts {
+            for (e1, e2, _) in world.contact_pairs() {
+                if let Some(t) = touch(e1, e2) {
+                    debug.contacts.push(DebugContact {
+                        point: t.points.0,
+                        normal: t.normal,
+                        depth: t.depth,
+                    });
                 }
-                col.contacts.insert(e1.data, p2);
+            }
+        }
+        if overlay.aabbs {
+            for co in world.collision_objects() {
+                let aabb = co.shape.as_ref().aabb(&co.position);
+                let (min, max) = (aabb.mins(), aabb.maxs());
+                debug.aabbs.push([min[0], min[1], max[0] - min[0], max[1] - min[1]]);
             }
         }
     }
@@ -232,29 +822,339 @@ impl<'a> System<'a> for CollisionSystem {
      WriteStorage<'a, Pos>,
      WriteStorage<'a, CollisionObjectData>,
      ReadStorage<'a, Bounds>,
-     ReadStorage<'a, Vel>);
-    fn run(&mut self, (ent, mut pos, mut col, bounds, vel): Self::SystemData) {
+     ReadStorage<'a, Vel>,
+     Fetch<'a, DebugOverlay>,
+     FetchMut<'a, CollisionDebug>,
+     FetchMut<'a, CollisionSync>,
+     FetchMut<'a, CollisionEvents>,
+     Fetch<'a, CollisionLayers>,
+     Fetch<'a, Delta>,
+     FetchMut<'a, CollisionQuery>);
+    fn run(&mut self,
+           (ent, mut pos, mut col, bounds, vel, overlay, mut debug, mut sync, mut events,
+            layers, delta, mut query): Self::SystemData) {
         let mut dirty = true;
         let mut i = 0;
 
-        Self::clear_collision_objects(&mut col);
+        if sync.rebuild {
+            debug!("rebuilding collision world");
+            self.0 = new_world();
+            self.1 = sync.ids.clone();
+            sync.rebuild = false;
+        } else if layers.revision != self.3 {
+            debug!("collision layers changed, rebuilding collision world");
+            self.0 = new_world();
+        }
+        self.3 = layers.revision;
+
+        let previous: BTreeMap<PairKey, Contact> = events.touching()
+            .into_iter()
+            .map(|c| (pair_key(c.entities.0, c.entities.1), c.clone()))
+            .collect();
+        let warm: BTreeMap<PairKey, f64> =
+            previous.iter().map(|(&key, c)| (key, c.correction)).collect();
+        let mut touching = BTreeMap::new();
+        let mut pushed = BTreeMap::new();
+        let mut moved = HashMap::new();
+
+        let was_overlapping = Self::current_overlaps(&ent, &col);
+        Self::clear_collision_objects(&mut col, delta.0);
+        self.remove_dead(&ent, &col, &bounds);
         self.remove_changed(&ent, &mut col, &bounds);
-        while i < 10 && dirty {
+        Self::eject_buried(&mut pos, &vel, &bounds, &col, &ent, &layers);
+        let asleep: HashSet<Entity> = (&*ent, &col)
+            .join()
+            .filter(|&(e, c)| c.rest >= SLEEP_TIME && !moving(&vel, e))
+            .map(|(e, _)| e)
+            .collect();
+        while i < SOLVER_PASSES && dirty {
             {
-                self.update_collisions(&ent, &pos, &mut col, &bounds);
+                self.update_collisions(&ent, &pos, &mut col, &bounds, &layers);
             }
             {
-                dirty = self.basic_physics(&mut pos, &vel);
+                let warm = if i == 0 { Some(&warm) } else { None };
+                dirty = self.solve(&mut pos,
+                                   &vel,
+                                   &bounds,
+                                   &col,
+                                   &asleep,
+                                   warm,
+                                   &mut pushed,
+                                   &mut moved);
             }
             {
                 if i == 0 {
-                    self.update_collision_objects(&mut col)
+                    self.update_collision_objects(&mut col, &mut touching)
                 }
             }
             i += 1;
         }
-        {
+        for (key, contact) in touching.iter_mut() {
+            contact.correction = pushed.get(key).cloned().unwrap_or(0.0);
+        }
+        Self::update_sleep(&ent, &mut col, &vel, &moved, &touching, delta.0);
+        events.0 = contact_events(&previous, touching);
+        self.update_overlaps(&mut col);
+        let overlapping = Self::current_overlaps(&ent, &col);
+        events.0.extend(overlap_events(&was_overlapping, &overlapping));
+        self.2.collision_iterations(i);
+        if dirty {
+            debug!("collision still penetrating after iterations={}", i);
+        }
+        self.export_debug(&overlay, &mut debug);
+        *query = CollisionQuery::capture(&self.0, &|e| col.get(e).map(|c| c.layer));
+        sync.ids = self.1.clone();
+
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Game;
+    use level;
+    use net::TICK;
+
+    fn world() -> World {
+        let mut world = World::new();
+        world.add_resource(Delta(1.0 / 60.0));
+        world.add_resource(DebugOverlay::default());
+        world.add_resource(CollisionDebug::default());
+        world.add_resource(CollisionSync::new());
+        world.add_resource(CollisionEvents::default());
+        world.add_resource(CollisionLayers::new());
+        world.add_resource(CollisionQuery::default());
+        world.register::<Pos>();
+        world.register::<Vel>();
+        world.register::<Bounds>();
+        world.register::<CollisionObjectData>();
+        world
+    }
+
+    fn spawn(world: &mut World, i: usize) -> Entity {
+        world.create_entity()
+            .with(Pos {
+                x: i as f64 * 100.0,
+                y: 0.0,
+            })
+            .with(Vel { x: 0.0, y: 0.0 })
+            .with(Bounds::Circle(10.0))
+            .with(CollisionObjectData::new(Layer::Players))
+            .build()
+    }
+
+    fn run(system: &mut CollisionSystem, world: &mut World) {
+        system.run((world.entities(),
+                    world.write::<Pos>(),
+                    world.write::<CollisionObjectData>(),
+                    world.read::<Bounds>(),
+                    world.read::<Vel>(),
+                    world.read_resource::<DebugOverlay>(),
+                    world.write_resource::<CollisionDebug>(),
+                    world.write_resource::<CollisionSync>(),
+                    world.write_resource::<CollisionEvents>(),
+                    world.read_resource::<CollisionLayers>(),
+                    world.read_resource::<Delta>(),
+                    world.write_resource::<CollisionQuery>()));
+        world.maintain();
+    }
+
+    // The collision world holds exactly one object, and the id map one id, for each
+    // living entity with both `Bounds` and `CollisionObjectData`.
+    fn check(system: &CollisionSystem, world: &World) {
+        let entities = world.entities();
+        let (bounds, col) = (world.read::<Bounds>(), world.read::<CollisionObjectData>());
+        let mut live: Vec<u32> =
+            (&*entities, &bounds, &col).join().map(|(e, _, _)| e.id()).collect();
+        live.sort();
+        let mut objects: Vec<u32> = system.0
+            .collision_objects()
+            .map(|co| {
+                assert!(entities.is_alive(co.data), "object of dead entity {:?}", co.data);
+                co.data.id()
+            })
+            .collect();
+        objects.sort();
+        let mut ids: Vec<u32> =
+            system.1.entries().into_iter().map(|((eid, _), _)| eid as u32).collect();
+        ids.sort();
+        assert_eq!(objects, live);
+        assert_eq!(ids, live);
+    }
+
+    #[test]
+    fn objects_follow_entities_through_churn() {
+        let mut world = world();
+        let mut system = CollisionSystem::new(&Profiler::new());
+        let mut spawned: Vec<Entity> = (0..20).map(|i| spawn(&mut world, i)).collect();
+        run(&mut system, &mut world);
+        check(&system, &world);
+
+        for (_, &e) in spawned.iter().enumerate().filter(|&(i, _)| i % 3 == 0) {
+            world.entities().delete(e);
+        }
+        world.maintain();
+        run(&mut system, &mut world);
+        check(&system, &world);
+
+        // New entities take the freed indices under new generations.
+        spawned = spawned.into_iter().filter(|&e| world.entities().is_alive(e)).collect();
+        for i in 20..27 {
+            spawned.push(spawn(&mut world, i));
         }
+        run(&mut system, &mut world);
+        check(&system, &world);
+
+        world.write::<Bounds>().remove(spawned[0]);
+        world.write::<Bounds>().remove(spawned[1]);
+        world.write::<CollisionObjectData>().remove(spawned[2]);
+        world.write::<CollisionObjectData>().remove(spawned[3]);
+        run(&mut system, &mut world);
+        check(&system, &world);
 
+        // Deleted the same run they lost a component, then their indices reused.
+        world.entities().delete(spawned[4]);
+        world.write::<Bounds>().remove(spawned[5]);
+        world.entities().delete(spawned[5]);
+        world.maintain();
+        spawn(&mut world, 30);
+        spawn(&mut world, 31);
+        run(&mut system, &mut world);
+        check(&system, &world);
+
+        // Components put back come back as objects.
+        world.write::<Bounds>().insert(spawned[0], Bounds::Rectangle(20.0, 20.0));
+        world.write::<CollisionObjectData>()
+            .insert(spawned[2], CollisionObjectData::new(Layer::Players));
+        run(&mut system, &mut world);
+        check(&system, &world);
+    }
+
+    #[test]
+    fn compound_parts_share_one_contact() {
+        let mut world = world();
+        let mut system = CollisionSystem::new(&Profiler::new());
+        // Two overlapping halves, and a ball resting across both. Neither moves, so the
+        // solver leaves them where they are.
+        let part = |x: f64| {
+            BoundsPart {
+                offset: [x, 0.0],
+                angle: 0.0,
+                shape: Bounds::Rectangle(20.0, 20.0),
+            }
+        };
+        let compound = world.create_entity()
+            .with(Pos { x: 0.0, y: 0.0 })
+            .with(Bounds::Compound(vec![part(-8.0), part(8.0)]))
+            .with(CollisionObjectData::new(Layer::Players))
+            .build();
+        let ball = world.create_entity()
+            .with(Pos { x: 0.0, y: 15.0 })
+            .with(Bounds::Circle(10.0))
+            .with(CollisionObjectData::new(Layer::Players))
+            .build();
+        run(&mut system, &mut world);
+        run(&mut system, &mut world);
+
+        let col = world.read::<CollisionObjectData>();
+        let (c, b) = (col.get(compound).unwrap(), col.get(ball).unwrap());
+        assert_eq!(c.contacts.keys().collect::<Vec<_>>(), vec![&ball]);
+        assert_eq!(c.contacts[&ball].len(), 2);
+        assert_eq!(b.contacts[&compound].len(), 2);
+        assert!(c.overlaps.is_empty());
+        let events = world.read_resource::<CollisionEvents>();
+        let touching = events.touching();
+        assert_eq!(touching.len(), 1);
+        assert_eq!(touching[0].entities, (compound, ball));
+        assert_eq!(touching[0].points.len(), 2);
+    }
+
+    // The wizards' positions and how long each has been still, in spawn order.
+    fn wizards(game: &Game) -> Vec<([f64; 2], f64)> {
+        let (player, pos, col) = (game.world.read::<Player>(),
+                                  game.world.read::<Pos>(),
+                                  game.world.read::<CollisionObjectData>());
+        let mut wizards: Vec<(i32, [f64; 2], f64)> = (&player, &pos, &col)
+            .join()
+            .map(|(p, pos, c)| (p.0, [pos.x, pos.y], c.rest))
+            .collect();
+        wizards.sort_by_key(|w| w.0);
+        wizards.into_iter().map(|(_, p, rest)| (p, rest)).collect()
+    }
+
+    // Leaves a level's wizards alone for long enough to land and fall asleep, checks
+    // they have, then that they stay put. Returns where they settled.
+    fn settle(source: &str) -> Vec<[f64; 2]> {
+        let level = level::parse(source).ok().unwrap();
+        let mut game = Game::new(&level);
+        let idle = HashMap::new();
+        for _ in 0..180 {
+            game.step(&idle, TICK);
+        }
+        let settled = wizards(&game);
+        for &(p, rest) in settled.iter() {
+            assert!(rest >= SLEEP_TIME, "wizard at {:?} still awake", p);
+        }
+        for _ in 0..60 {
+            game.step(&idle, TICK);
+        }
+        for (&(p, rest), &(q, _)) in wizards(&game).iter().zip(settled.iter()) {
+            assert!(rest >= SLEEP_TIME, "wizard at {:?} woke up", p);
+            assert!((p[0] - q[0]).abs() < 1e-6 && (p[1] - q[1]).abs() < 1e-6,
+                    "wizard drifted from {:?} to {:?}",
+                    q,
+                    p);
+        }
+        settled.into_iter().map(|(p, _)| p).collect()
+    }
+
+    #[test]
+    fn resting_wizard_falls_asleep() {
+        let settled = settle("terrain rect 100 400 600 50 rock\nspawn 200 360\nspawn 500 360\n");
+        for p in settled {
+            assert!((p[1] + 25.0 - 400.0).abs() <= SLOP * 2.0);
+        }
+    }
+
+    #[test]
+    fn stacked_wizards_fall_asleep() {
+        let settled = settle("terrain rect 100 400 600 50 rock\nspawn 300 360\nspawn 300 290\n");
+        assert!((settled[0][1] + 25.0 - 400.0).abs() <= SLOP * 2.0);
+        assert!((settled[1][1] + 25.0 - (settled[0][1] - 25.0)).abs() <= SLOP * 2.0);
+        assert!((settled[1][0] - settled[0][0]).abs() < 1e-6);
+    }
+
+    #[test]
+    fn passing_through_a_sensor_enters_and_exits_once() {
+        let mut world = world();
+        let mut system = CollisionSystem::new(&Profiler::new());
+        let sensor = world.create_entity()
+            .with(Pos { x: 0.0, y: 0.0 })
+            .with(Bounds::Rectangle(40.0, 40.0))
+            .with(CollisionObjectData::sensor())
+            .build();
+        let body = spawn(&mut world, 0);
+        let (mut entered, mut exited) = (0, 0);
+        for step in 0..25 {
+            let x = -60.0 + step as f64 * 5.0;
+            *world.write::<Pos>().get_mut(body).unwrap() = Pos { x: x, y: 0.0 };
+            run(&mut system, &mut world);
+            let p = *world.read::<Pos>().get(body).unwrap();
+            assert!(p.x == x && p.y == 0.0, "pushed to {:?} from x={}", (p.x, p.y), x);
+            for event in world.read_resource::<CollisionEvents>().0.iter() {
+                match *event {
+                    CollisionEvent::SensorEntered(o) => {
+                        assert_eq!((o.sensor, o.other), (sensor, body));
+                        entered += 1;
+                    }
+                    CollisionEvent::SensorExited(o) => {
+                        assert_eq!((o.sensor, o.other), (sensor, body));
+                        exited += 1;
+                    }
+                    _ => panic!("{:?} between a sensor and a body", event),
+                }
+            }
+        }
+        assert_eq!((entered, exited), (1, 1));
     }
 }
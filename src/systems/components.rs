@@ -3,13 +3,100 @@ use specs::{Entity, Component, DispatcherBuilder, Dispatcher, ReadStorage, Syste
 
 use std::collections::HashSet;
 use std::collections::HashMap;
-use piston_window::Button;
+use piston_window::{Button, Key};
 use std::boxed;
+use std::f64::consts::PI;
 
 pub struct Delta(pub f64);
-pub struct GameInput(pub HashSet<Button>);
+
+// What one player asked for on one tick. Kept as a bitset so it is cheap to send
+// over the network every tick.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[derive(Serialize, Deserialize)]
+pub struct Actions(pub u8);
+
+// Bits of `Actions`.
+pub mod action {
+    pub const UP: u8 = 1 << 0;
+    pub const DOWN: u8 = 1 << 1;
+    pub const LEFT: u8 = 1 << 2;
+    pub const RIGHT: u8 = 1 << 3;
+    pub const AIM_UP: u8 = 1 << 4;
+    pub const AIM_DOWN: u8 = 1 << 5;
+    pub const CAST: u8 = 1 << 6;
+    pub const NEXT_SPELL: u8 = 1 << 7;
+}
+
+impl Actions {
+    pub fn contains(&self, action: u8) -> bool {
+        self.0 & action != 0
+    }
+
+    pub fn insert(&mut self, action: u8) {
+        self.0 |= action;
+    }
+}
+
+// The actions of every player for the current tick and the one before, keyed by
+// player number. The previous tick is kept here rather than in the systems so that
+// snapshots of the world include it.
+#[derive(Clone, Default)]
+pub struct GameInput {
+    pub actions: HashMap<i32, Actions>,
+    pub last: HashMap<i32, Actions>,
+}
+
+impl GameInput {
+    pub fn get(&self, player: i32) -> Actions {
+        self.actions.get(&player).cloned().unwrap_or_default()
+    }
+
+    pub fn last(&self, player: i32) -> Actions {
+        self.last.get(&player).cloned().unwrap_or_default()
+    }
+}
+
+#[derive(Default)]
+pub struct DebugOverlay {
+    pub shapes: bool,
+    pub contacts: bool,
+    pub aabbs: bool,
+    pub velocities: bool,
+    pub chunks: bool,
+}
+
+impl DebugOverlay {
+    // Flips the layer bound to `key`, returning false if the key isn't a debug key.
+    pub fn toggle(&mut self, key: Key) -> bool {
+        let flag = match key {
+            Key::F1 => &mut self.shapes,
+            Key::F2 => &mut self.contacts,
+            Key::F3 => &mut self.aabbs,
+            Key::F4 => &mut self.velocities,
+            Key::F5 => &mut self.chunks,
+            _ => return false,
+        };
+        *flag = !*flag;
+        true
+    }
+}
+
+pub struct DebugContact {
+    pub point: [f64; 2],
+    pub normal: [f64; 2],
+    pub depth: f64,
+}
+
+// Filled in by the collision system only while the matching overlay layer is on.
+#[derive(Default)]
+pub struct CollisionDebug {
+    pub contacts: Vec<DebugContact>,
+    pub aabbs: Vec<[f64; 4]>,
+}
 
 #[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
 pub struct Pos {
     pub x: f64,
     pub y: f64,
@@ -22,23 +109,370 @@ impl Component for Pos {
 
 #[derive(PartialEq)]
 #[derive(Clone)]
+#[derive(Serialize, Deserialize)]
 pub enum Bounds {
     Rectangle(f64, f64),
     Circle(f64),
-    Polygon(Box<Vec<[f64; 2]>>)
+    Polygon(Box<Vec<[f64; 2]>>),
+    // Several shapes moving together, each a collision object of its own.
+    Compound(Vec<BoundsPart>),
+}
+
+// One shape of a compound, moved and turned (in radians) from the entity's position.
+#[derive(PartialEq)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct BoundsPart {
+    pub offset: [f64; 2],
+    pub angle: f64,
+    pub shape: Bounds,
+}
+
+impl Bounds {
+    // Half width and half height of the shape around its position.
+    pub fn half_extents(&self) -> [f64; 2] {
+        match self {
+            &Bounds::Rectangle(x, y) => [x / 2.0, y / 2.0],
+            &Bounds::Circle(r) => [r, r],
+            &Bounds::Polygon(ref ps) => {
+                ps.iter().fold([0.0, 0.0], |e, p| [e[0].max(p[0].abs()), e[1].max(p[1].abs())])
+            }
+            &Bounds::Compound(_) => {
+                self.parts().iter().fold([0.0, 0.0], |e, part| {
+                    let h = part.shape.half_extents();
+                    let (sin, cos) = (part.angle.sin().abs(), part.angle.cos().abs());
+                    [e[0].max(part.offset[0].abs() + cos * h[0] + sin * h[1]),
+                     e[1].max(part.offset[1].abs() + sin * h[0] + cos * h[1])]
+                })
+            }
+        }
+    }
+
+    // Outlines are measured by the box around them.
+    pub fn area(&self) -> f64 {
+        match self {
+            &Bounds::Rectangle(x, y) => x * y,
+            &Bounds::Circle(r) => PI * r * r,
+            &Bounds::Polygon(_) => {
+                let e = self.half_extents();
+                4.0 * e[0] * e[1]
+            }
+            &Bounds::Compound(ref parts) => parts.iter().map(|p| p.shape.area()).sum(),
+        }
+    }
+
+    // The plain shapes these bounds are made of, compounds inside compounds included.
+    // Anything but a compound is its own single part.
+    pub fn parts(&self) -> Vec<BoundsPart> {
+        match self {
+            &Bounds::Compound(ref parts) => {
+                parts.iter()
+                    .flat_map(|outer| {
+                        let (sin, cos) = (outer.angle.sin(), outer.angle.cos());
+                        outer.shape.parts().into_iter().map(move |inner| {
+                            let o = inner.offset;
+                            BoundsPart {
+                                offset: [outer.offset[0] + o[0] * cos - o[1] * sin,
+                                         outer.offset[1] + o[0] * sin + o[1] * cos],
+                                angle: outer.angle + inner.angle,
+                                shape: inner.shape,
+                            }
+                        })
+                    })
+                    .collect()
+            }
+            _ => {
+                vec![BoundsPart {
+                         offset: [0.0, 0.0],
+                         angle: 0.0,
+                         shape: self.clone(),
+                     }]
+            }
+        }
+    }
 }
 
 impl Component for Bounds {
     type Storage = VecStorage<Self>;
 }
 
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct Player(pub i32);
 impl Component for Player {
     type Storage = HashMapStorage<Self>;
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Name(pub String);
+impl Component for Name {
+    type Storage = HashMapStorage<Self>;
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Health {
+    pub current: f64,
+    pub max: f64,
+}
+
+impl Health {
+    pub fn new(max: f64) -> Health {
+        Health {
+            current: max,
+            max: max,
+        }
+    }
+
+    pub fn alive(&self) -> bool {
+        self.current > 0.0
+    }
+}
+
+impl Component for Health {
+    type Storage = VecStorage<Self>;
+}
+
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Score {
+    pub damage: f64,
+    pub kills: u32,
+}
+
+impl Component for Score {
+    type Storage = HashMapStorage<Self>;
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Spell {
+    pub name: String,
+    pub cooldown: f64,
+    pub speed: f64,
+    pub damage: f64,
+    pub radius: f64,
+}
+
+impl Spell {
+    pub fn new(name: &str, cooldown: f64, speed: f64, damage: f64, radius: f64) -> Spell {
+        Spell {
+            name: name.to_string(),
+            cooldown: cooldown,
+            speed: speed,
+            damage: damage,
+            radius: radius,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SpellBook {
+    pub spells: Vec<Spell>,
+    pub cooldowns: Vec<f64>,
+    pub active: usize,
+}
+
+impl SpellBook {
+    pub fn new(spells: Vec<Spell>) -> SpellBook {
+        let cooldowns = vec![0.0; spells.len()];
+        SpellBook {
+            spells: spells,
+            cooldowns: cooldowns,
+            active: 0,
+        }
+    }
+
+    pub fn standard() -> SpellBook {
+        SpellBook::new(vec![Spell::new("fireball", 2.0, 300.0, 40.0, 30.0),
+                            Spell::new("bolt", 0.5, 500.0, 10.0, 8.0),
+                            Spell::new("meteor", 6.0, 200.0, 70.0, 50.0)])
+    }
+
+    pub fn active_spell(&self) -> Option<&Spell> {
+        self.spells.get(self.active)
+    }
+
+    pub fn ready(&self) -> bool {
+        self.cooldowns.get(self.active).map_or(false, |&c| c <= 0.0)
+    }
+
+    pub fn next(&mut self) {
+        if !self.spells.is_empty() {
+            self.active = (self.active + 1) % self.spells.len();
+        }
+    }
+}
+
+impl Component for SpellBook {
+    type Storage = HashMapStorage<Self>;
+}
+
+// Angle in radians (0 is facing right, negative is up) and charge between 0 and 1.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Aim {
+    pub angle: f64,
+    pub power: f64,
+    pub charging: bool,
+}
+
+impl Aim {
+    pub fn new() -> Aim {
+        Aim {
+            angle: -0.5,
+            power: 0.0,
+            charging: false,
+        }
+    }
+}
+
+impl Component for Aim {
+    type Storage = HashMapStorage<Self>;
+}
+
+#[derive(Clone)]
+pub struct Projectile {
+    pub caster: Entity,
+    pub damage: f64,
+    pub radius: f64,
+}
+
+impl Component for Projectile {
+    type Storage = HashMapStorage<Self>;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum AiPhase {
+    // Waiting out its reaction time at the start of a turn.
+    Thinking,
+    Walking,
+    // Picking the spell, turning to the angle and charging up.
+    Casting,
+    Done,
+}
+
+// Makes a player's wizard play itself. It writes into `GameInput` just as a keyboard
+// would, so everything downstream treats it like any other player.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AiController {
+    pub difficulty: Difficulty,
+    pub target: Option<i32>,
+    // The turn the phase belongs to.
+    pub turn: u32,
+    pub phase: AiPhase,
+    pub timer: f64,
+    // Spell index, angle and power it has settled on this turn.
+    pub shot: Option<(usize, f64, f64)>,
+    // Aim error comes from here, so every peer makes the same mistakes.
+    pub seed: u64,
+    // Where it's walking to, and the turn, target and terrain revision that was planned
+    // for.
+    pub path: Vec<[f64; 2]>,
+    pub path_for: Option<(u32, i32, u64)>,
+}
+
+impl AiController {
+    pub fn new(player: i32, difficulty: Difficulty) -> AiController {
+        AiController {
+            difficulty: difficulty,
+            target: None,
+            turn: u32::max_value(),
+            phase: AiPhase::Done,
+            timer: 0.0,
+            shot: None,
+            seed: 0x2545F4914F6CDD1D ^ (player as u64).wrapping_mul(0x9E3779B97F4A7C15),
+            path: vec![],
+            path_for: None,
+        }
+    }
+}
+
+impl Component for AiController {
+    type Storage = HashMapStorage<Self>;
+}
+
+// Walking, jumping and being knocked about; see `CharacterSystem`.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct CharacterController {
+    pub grounded: bool,
+    // Of the ground underfoot, pointing up out of it.
+    pub ground_normal: [f64; 2],
+    // Seconds since it last stood on something.
+    pub air_time: f64,
+    // Seconds until it can be controlled again after a blast.
+    pub knockback: f64,
+    // So holding jump down only jumps once.
+    pub jump_held: bool,
+}
+
+impl CharacterController {
+    pub fn new() -> CharacterController {
+        CharacterController {
+            grounded: false,
+            ground_normal: [0.0, -1.0],
+            air_time: 0.0,
+            knockback: 0.0,
+            jump_held: false,
+        }
+    }
+}
+impl Component for CharacterController {
+    type Storage = HashMapStorage<Self>;
+}
+
+// What a sensor does to the wizards inside it. Pickups and spawn protection will be kinds
+// of zone too, once there's something to pick up and a spawn timer to protect; the enter
+// and exit events are all they need from the collision system.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Zone {
+    // Kills any wizard that enters.
+    Kill,
+    // Hurts the wizards in it by this much a second.
+    Water(f64),
+}
+
+impl Component for Zone {
+    type Storage = HashMapStorage<Self>;
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TurnState {
+    pub active: i32,
+    pub time_left: f64,
+    pub turn_length: f64,
+    pub turn: u32,
+}
+
+impl TurnState {
+    pub fn new(first: i32, turn_length: f64) -> TurnState {
+        TurnState {
+            active: first,
+            time_left: turn_length,
+            turn_length: turn_length,
+            turn: 0,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Wind {
+    pub speed: f64,
+    pub range: [f64; 2],
+}
+
+// Per level presentation that isn't attached to any entity.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct LevelInfo {
+    pub background: Option<String>,
+    pub water: Option<f64>,
+}
+
 #[derive(Clone)]
 #[derive(Copy)]
+#[derive(Serialize, Deserialize)]
 pub struct Vel {
     pub x: f64,
     pub y: f64,
@@ -48,42 +482,299 @@ impl Component for Vel {
     type Storage = VecStorage<Self>;
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Sprite {
+    pub texture: String,
+    pub frame_size: [f64; 2],
+    pub columns: usize,
+    pub frame: usize,
+    pub flip_x: bool,
+    pub z: i32,
+}
+
+impl Sprite {
+    pub fn new(texture: &str, frame_size: [f64; 2], columns: usize, z: i32) -> Sprite {
+        Sprite {
+            texture: texture.to_string(),
+            frame_size: frame_size,
+            columns: columns,
+            frame: 0,
+            flip_x: false,
+            z: z,
+        }
+    }
+
+    pub fn source_rect(&self) -> [f64; 4] {
+        let columns = if self.columns == 0 { 1 } else { self.columns };
+        let col = self.frame % columns;
+        let row = self.frame / columns;
+        [col as f64 * self.frame_size[0],
+         row as f64 * self.frame_size[1],
+         self.frame_size[0],
+         self.frame_size[1]]
+    }
+}
+
+impl Component for Sprite {
+    type Storage = VecStorage<Self>;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum AnimState {
+    Idle,
+    Walk,
+    Jump,
+    Cast,
+    Hurt,
+    Death,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Clip {
+    pub first: usize,
+    pub frames: usize,
+    pub frame_time: f64,
+    pub looping: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Animation {
+    pub clips: HashMap<AnimState, Clip>,
+    pub state: AnimState,
+    pub frame: usize,
+    pub timer: f64,
+    pub despawn_when_done: bool,
+}
+
+impl Animation {
+    pub fn new() -> Animation {
+        Animation {
+            clips: HashMap::new(),
+            state: AnimState::Idle,
+            frame: 0,
+            timer: 0.0,
+            despawn_when_done: false,
+        }
+    }
+
+    pub fn with_clip(mut self, state: AnimState, clip: Clip) -> Animation {
+        self.clips.insert(state, clip);
+        self
+    }
+
+    // One row per state on the wizard sheet, four frames each.
+    pub fn wizard() -> Animation {
+        let clip = |row: usize, frame_time: f64, looping: bool| {
+            Clip {
+                first: row * 4,
+                frames: 4,
+                frame_time: frame_time,
+                looping: looping,
+            }
+        };
+        Animation::new()
+            .with_clip(AnimState::Idle, clip(0, 0.3, true))
+            .with_clip(AnimState::Walk, clip(1, 0.12, true))
+            .with_clip(AnimState::Jump, clip(2, 0.15, true))
+            .with_clip(AnimState::Cast, clip(3, 0.08, false))
+            .with_clip(AnimState::Hurt, clip(4, 0.1, false))
+            .with_clip(AnimState::Death, clip(5, 0.2, false))
+    }
+
+    // States without a clip of their own play the idle clip.
+    pub fn clip(&self) -> Option<&Clip> {
+        self.clips.get(&self.state).or_else(|| self.clips.get(&AnimState::Idle))
+    }
+
+    pub fn finished(&self) -> bool {
+        self.clip().map_or(true, |c| !c.looping && self.frame + 1 >= c.frames)
+    }
+}
+
+impl Component for Animation {
+    type Storage = VecStorage<Self>;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AnimEvent {
+    Cast,
+    Hurt,
+    Death,
+}
+
+pub struct AnimationEvents(pub Vec<(Entity, AnimEvent)>);
+
+// What kind of thing a collision object is. `CollisionLayers` says which kinds collide.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Layer {
+    Players,
+    Projectiles,
+    Terrain,
+    Debris,
+    Sensors,
+}
+
+pub const LAYERS: usize = 5;
+
+impl Layer {
+    pub fn all() -> [Layer; LAYERS] {
+        [Layer::Players, Layer::Projectiles, Layer::Terrain, Layer::Debris, Layer::Sensors]
+    }
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Clone)]
 pub struct CollisionObjectData {
-    pub group_id: usize,
+    pub layer: Layer,
     pub contacts: HashMap<Entity, Vec<[f64; 2]>>,
+    // Sensors only report overlaps: they make no contacts and push nothing out.
+    pub sensor: bool,
+    // The entities overlapping this sensor, or the sensors this entity is in.
+    pub overlaps: HashSet<Entity>,
+    // Entities passed through for this many more seconds, whatever the layers say.
+    pub ignore: Vec<(Entity, f64)>,
+    // Swept along its path each step, so moving fast can't carry it through thin shapes.
+    pub fast: bool,
+    // Seconds it's been still. Once it's long enough the contact solver stops moving it.
+    pub rest: f64,
     pub current_bounds: Option<Bounds>,
 }
 
 impl CollisionObjectData {
-    pub fn new(id: usize) -> CollisionObjectData {
+    pub fn new(layer: Layer) -> CollisionObjectData {
         CollisionObjectData {
-            group_id: id,
+            layer: layer,
             contacts: HashMap::new(),
+            sensor: false,
+            overlaps: HashSet::new(),
+            ignore: vec![],
+            fast: false,
+            rest: 0.0,
             current_bounds: None,
         }
     }
+
+    pub fn sensor() -> CollisionObjectData {
+        CollisionObjectData { sensor: true, ..CollisionObjectData::new(Layer::Sensors) }
+    }
+
+    pub fn ignores(&self, e: Entity) -> bool {
+        self.ignore.iter().any(|&(other, _)| other == e)
+    }
 }
 impl Component for CollisionObjectData {
     type Storage = VecStorage<CollisionObjectData>;
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum Material {
+    Dirt,
+    Rock,
+    Sand,
+}
+
+// Changed cells remembered before the renderer is asked to repaint everything.
+pub const MAX_CHANGED: usize = 4096;
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Terrain {
     pub dirty: bool,
     pub points: HashSet<[usize; 2]>,
+    // Cells missing from here are dirt.
+    pub materials: HashMap<[usize; 2], Material>,
+    pub scorched: HashSet<[usize; 2]>,
+    // Cells carved or recoloured since the renderer last looked. Past `MAX_CHANGED` they're
+    // forgotten and `repaint` set instead, so nothing grows while nobody's drawing.
+    #[serde(skip)]
+    pub changed: Vec<[usize; 2]>,
+    #[serde(skip)]
+    pub repaint: bool,
+    // Bumped on every edit, so snapshots can tell whether they need a fresh copy.
+    #[serde(skip)]
+    pub revision: u64,
+    // Bounding box (min x, min y, max x, max y) of the cells edited since the navigation
+    // graph last looked.
+    #[serde(skip)]
+    pub edited: Option<[usize; 4]>,
 }
 
 impl Terrain {
     pub fn new(x: usize, y: usize, width: usize, height: usize) -> Terrain {
-        let mut ps = HashSet::new();
+        let mut terrain = Terrain::empty();
+        terrain.fill(x, y, width, height, Material::Dirt);
+        terrain
+    }
+
+    pub fn empty() -> Terrain {
+        Terrain {
+            dirty: true,
+            points: HashSet::new(),
+            materials: HashMap::new(),
+            scorched: HashSet::new(),
+            changed: vec![],
+            repaint: false,
+            revision: 0,
+            edited: None,
+        }
+    }
+
+    pub fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, material: Material) {
         for x in x..(x + width) {
             for y in y..(y + height) {
-                ps.insert([x, y]);
+                self.set(&[x, y], Some(material));
             }
         }
-        Terrain {
-            dirty: true,
-            points: ps,
+        self.dirty = true;
+    }
+
+    // Sets a single cell to `material`, or clears it with `None`.
+    pub fn set(&mut self, p: &[usize; 2], material: Option<Material>) {
+        match material {
+            Some(m) => {
+                self.points.insert(*p);
+                if m == Material::Dirt {
+                    self.materials.remove(p);
+                } else {
+                    self.materials.insert(*p, m);
+                }
+            }
+            None => {
+                self.points.remove(p);
+                self.materials.remove(p);
+                self.scorched.remove(p);
+            }
         }
+        self.note_changed(*p);
+        self.revision += 1;
+        self.mark_edited(*p, *p);
+    }
+
+    // Tells the renderer `p` looks different.
+    pub fn note_changed(&mut self, p: [usize; 2]) {
+        if self.repaint {
+            return;
+        }
+        if self.changed.len() >= MAX_CHANGED {
+            self.changed.clear();
+            self.repaint = true;
+        } else {
+            self.changed.push(p);
+        }
+    }
+
+    // Grows `edited` to cover every cell from `min` to `max`.
+    pub fn mark_edited(&mut self, min: [usize; 2], max: [usize; 2]) {
+        self.edited = Some(match self.edited {
+            Some(e) => [e[0].min(min[0]), e[1].min(min[1]), e[2].max(max[0]), e[3].max(max[1])],
+            None => [min[0], min[1], max[0], max[1]],
+        });
+    }
+
+    pub fn material_at(&self, p: [usize; 2]) -> Material {
+        self.materials.get(&p).cloned().unwrap_or(Material::Dirt)
     }
 }
 
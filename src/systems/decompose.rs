@@ -0,0 +1,439 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+// Splitting a traced outline into convex pieces, so the collision world can treat the
+// terrain as solid rather than as a thin line around it.
+
+fn cross(o: [f64; 2], a: [f64; 2], b: [f64; 2]) -> f64 {
+    (a[0] - o[0]) * (b[1] - o[1]) - (a[1] - o[1]) * (b[0] - o[0])
+}
+
+fn signed_area(ps: &[[f64; 2]]) -> f64 {
+    let n = ps.len();
+    (0..n).map(|i| {
+            let (a, b) = (ps[i], ps[(i + 1) % n]);
+            a[0] * b[1] - b[0] * a[1]
+        })
+        .sum::<f64>() / 2.0
+}
+
+// Whether `p` is inside or on the triangle, other than at one of its corners.
+fn in_triangle(p: [f64; 2], a: [f64; 2], b: [f64; 2], c: [f64; 2]) -> bool {
+    if p == a || p == b || p == c {
+        return false;
+    }
+    cross(a, b, p) >= 0.0 && cross(b, c, p) >= 0.0 && cross(c, a, p) >= 0.0
+}
+
+// Ear clipping. Takes indices into `ps`, wound so the area is positive; None if it gets
+// stuck, which a self-touching outline can make it do.
+fn triangulate(ps: &[[f64; 2]], mut left: Vec<usize>) -> Option<Vec<Vec<usize>>> {
+    let mut triangles = vec![];
+    let mut i = 0;
+    let mut tried = 0;
+    while left.len() > 3 {
+        let n = left.len();
+        if tried > n {
+            return None;
+        }
+        let (a, b, c) = (left[(i + n - 1) % n], left[i % n], left[(i + 1) % n]);
+        let turn = cross(ps[a], ps[b], ps[c]);
+        if turn == 0.0 {
+            // Nothing to cut off, just a point along a straight edge.
+            left.remove(i % n);
+            tried = 0;
+            continue;
+        }
+        let ear = {
+            let reflex = |j: usize| {
+                cross(ps[left[(j + n - 1) % n]], ps[left[j]], ps[left[(j + 1) % n]]) <= 0.0
+            };
+            turn > 0.0 &&
+            !(0..n).any(|j| {
+                let k = left[j];
+                k != a && k != b && k != c && reflex(j) && in_triangle(ps[k], ps[a], ps[b], ps[c])
+            })
+        };
+        if ear {
+            triangles.push(vec![a, b, c]);
+            left.remove(i % n);
+            tried = 0;
+        } else {
+            i += 1;
+            tried += 1;
+        }
+        i %= left.len();
+    }
+    if cross(ps[left[0]], ps[left[1]], ps[left[2]]) > 0.0 {
+        triangles.push(left);
+    }
+    Some(triangles)
+}
+
+fn convex(ps: &[[f64; 2]], piece: &[usize]) -> bool {
+    let n = piece.len();
+    (0..n).all(|i| cross(ps[piece[i]], ps[piece[(i + 1) % n]], ps[piece[(i + 2) % n]]) >= 0.0)
+}
+
+// Joins `a` and `b` across their shared edge `from`-`to`, as `a` winds it.
+fn join(a: &[usize], b: &[usize], from: usize, to: usize) -> Vec<usize> {
+    let start = a.iter().position(|&v| v == to).unwrap();
+    let mut out: Vec<usize> = (0..a.len()).map(|k| a[(start + k) % a.len()]).collect();
+    let after = b.iter().position(|&v| v == from).unwrap();
+    out.extend((1..b.len() - 1).map(|k| b[(after + k) % b.len()]));
+    out
+}
+
+// Merges neighbouring triangles for as long as the result stays convex.
+fn merge(ps: &[[f64; 2]], triangles: Vec<Vec<usize>>) -> Vec<Vec<usize>> {
+    let mut pieces: Vec<Option<Vec<usize>>> = triangles.into_iter().map(Some).collect();
+    let mut owner: HashMap<(usize, usize), usize> = HashMap::new();
+    for (i, piece) in pieces.iter().enumerate() {
+        let piece = piece.as_ref().unwrap();
+        for k in 0..piece.len() {
+            owner.insert((piece[k], piece[(k + 1) % piece.len()]), i);
+        }
+    }
+    for i in 0..pieces.len() {
+        let mut k = 0;
+        while let Some(len) = pieces[i].as_ref().map(|p| p.len()) {
+            if k >= len {
+                break;
+            }
+            let (from, to) = {
+                let p = pieces[i].as_ref().unwrap();
+                (p[k], p[(k + 1) % len])
+            };
+            let other = owner.get(&(to, from)).cloned();
+            let merged = other.and_then(|j| if j == i {
+                None
+            } else {
+                let joined = join(pieces[i].as_ref().unwrap(),
+                                  pieces[j].as_ref().unwrap(),
+                                  from,
+                                  to);
+                if convex(ps, &joined) { Some((j, joined)) } else { None }
+            });
+            match merged {
+                Some((j, joined)) => {
+                    for n in 0..joined.len() {
+                        owner.insert((joined[n], joined[(n + 1) % joined.len()]), i);
+                    }
+                    owner.remove(&(from, to));
+                    owner.remove(&(to, from));
+                    pieces[j] = None;
+                    pieces[i] = Some(joined);
+                    k = 0;
+                }
+                None => k += 1,
+            }
+        }
+    }
+    pieces.into_iter().filter_map(|p| p).collect()
+}
+
+// Whether any two edges pass through each other. Edges meeting at a point, or running
+// along one another as the cut to a hole does, don't count.
+fn crosses(ps: &[[f64; 2]]) -> bool {
+    let n = ps.len();
+    let apart = |d1: f64, d2: f64| (d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0);
+    (0..n).any(|i| {
+        let (a, b) = (ps[i], ps[(i + 1) % n]);
+        (i + 1..n).any(|j| {
+            let (c, d) = (ps[j], ps[(j + 1) % n]);
+            apart(cross(c, d, a), cross(c, d, b)) && apart(cross(a, b, c), cross(a, b, d))
+        })
+    })
+}
+
+// The outline cut apart wherever it comes back to a point it has already passed through,
+// leaving loops that don't touch themselves. Loops with nothing inside are dropped.
+fn simple_loops(outline: &[[f64; 2]]) -> Vec<Vec<[f64; 2]>> {
+    let mut rest = outline.to_vec();
+    let mut loops = vec![];
+    loop {
+        let repeat = (0..rest.len())
+            .filter_map(|j| rest[..j].iter().position(|&p| p == rest[j]).map(|i| (i, j)))
+            .next();
+        match repeat {
+            Some((i, j)) => {
+                let cut: Vec<[f64; 2]> = rest.drain(i..j).collect();
+                loops.push(cut);
+            }
+            None => break,
+        }
+    }
+    loops.push(rest);
+    loops.into_iter().filter(|l| l.len() >= 3 && signed_area(l) != 0.0).collect()
+}
+
+// Each loop wound the positive way with the loops wound the other way that sit inside it,
+// which are its holes. Solid is on the left of every edge, so a hole's inside is just to
+// the right of its first edge. Smallest first, so a hole goes to the innermost loop.
+pub fn nest(loops: Vec<Vec<[f64; 2]>>) -> Vec<(Vec<[f64; 2]>, Vec<Vec<[f64; 2]>>)> {
+    let (mut solids, holes): (Vec<_>, Vec<_>) =
+        loops.into_iter().partition(|l| signed_area(l) > 0.0);
+    solids.sort_by(|a, b| signed_area(a).partial_cmp(&signed_area(b)).unwrap_or(Ordering::Equal));
+    let mut nested: Vec<(Vec<[f64; 2]>, Vec<Vec<[f64; 2]>>)> =
+        solids.into_iter().map(|s| (s, vec![])).collect();
+    for hole in holes {
+        let (a, b) = (hole[0], hole[1]);
+        let inside = [(a[0] + b[0]) / 2.0 + (b[1] - a[1]) / 64.0,
+                      (a[1] + b[1]) / 2.0 - (b[0] - a[0]) / 64.0];
+        if let Some(n) = nested.iter_mut().find(|n| contains(&n.0, inside)) {
+            n.1.push(hole);
+        }
+    }
+    nested
+}
+
+// The rightmost point, the highest of any that tie.
+fn rightmost(ps: &[[f64; 2]]) -> usize {
+    (0..ps.len()).fold(0, |best, i| if (ps[i][0], -ps[i][1]) > (ps[best][0], -ps[best][1]) {
+        i
+    } else {
+        best
+    })
+}
+
+// Whether `towards` is inside the corner the outline turns at `at`.
+fn in_corner(before: [f64; 2], at: [f64; 2], after: [f64; 2], towards: [f64; 2]) -> bool {
+    if cross(at, after, before) >= 0.0 {
+        cross(at, after, towards) > 0.0 && cross(at, towards, before) > 0.0
+    } else {
+        !(cross(at, before, towards) >= 0.0 && cross(at, towards, after) >= 0.0)
+    }
+}
+
+// One outline with its holes joined on, each by a cut from its rightmost point straight
+// across to the nearest edge to the right and back. The cuts run both ways, so they
+// cancel out for `contains`, and the outline can be cut into pieces in one go. Holes
+// further right are joined first, so a cut never crosses a hole still to come.
+pub fn bridge(outline: &[[f64; 2]], holes: &[Vec<[f64; 2]>]) -> Vec<[f64; 2]> {
+    let mut order: Vec<(usize, usize)> =
+        holes.iter().enumerate().map(|(k, h)| (k, rightmost(h))).collect();
+    order.sort_by(|&(a, i), &(b, j)| {
+        let (p, q) = (holes[a][i], holes[b][j]);
+        q[0].partial_cmp(&p[0])
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| p[1].partial_cmp(&q[1]).unwrap_or(Ordering::Equal))
+    });
+
+    let mut ps = outline.to_vec();
+    for (k, v) in order {
+        let hole = &holes[k];
+        let from = hole[v];
+        // The nearest point to the right: an x, the edge or point it's on, and whether it
+        // falls between the ends of the edge.
+        let mut nearest: Option<(f64, usize, bool)> = None;
+        let n = ps.len();
+        for i in 0..n {
+            let (a, b) = (ps[i], ps[(i + 1) % n]);
+            let hit = if from[1] == a[1] {
+                (a[0], i, false)
+            } else if from[1] == b[1] {
+                (b[0], (i + 1) % n, false)
+            } else if a[1].min(b[1]) < from[1] && from[1] < a[1].max(b[1]) {
+                (a[0] + (from[1] - a[1]) / (b[1] - a[1]) * (b[0] - a[0]), i, true)
+            } else {
+                continue;
+            };
+            if hit.0 >= from[0] && nearest.map_or(true, |best| hit.0 < best.0) {
+                nearest = Some(hit);
+            }
+        }
+        let m = match nearest {
+            Some((x, i, true)) => {
+                ps.insert(i + 1, [x, from[1]]);
+                i + 1
+            }
+            Some((_, i, false)) => {
+                // A point the outline passes more than once; leave from the visit that
+                // faces the hole.
+                let to = ps[i];
+                let n = ps.len();
+                (0..n)
+                    .find(|&j| {
+                        ps[j] == to && to != from &&
+                        in_corner(ps[(j + n - 1) % n], to, ps[(j + 1) % n], from)
+                    })
+                    .unwrap_or(i)
+            }
+            None => continue,
+        };
+        let to = ps[m];
+        let mut joined: Vec<[f64; 2]> = ps[..m + 1].to_vec();
+        if to != from {
+            joined.push(from);
+        }
+        joined.extend((1..hole.len()).map(|j| hole[(v + j) % hole.len()]));
+        joined.push(from);
+        if to != from {
+            joined.push(to);
+        }
+        joined.extend_from_slice(&ps[m + 1..]);
+        ps = joined;
+    }
+    ps
+}
+
+// The outline cut into convex polygons, each wound the same way. An outline that comes
+// back to a point it has passed before is split there, and the parts wound the other way
+// are holes, such as those `bridge` joins on. None if edges cross.
+pub fn convex_pieces(outline: &[[f64; 2]]) -> Option<Vec<Vec<[f64; 2]>>> {
+    if outline.len() < 3 || crosses(outline) {
+        return None;
+    }
+    let mut outline = outline.to_vec();
+    if signed_area(&outline) < 0.0 {
+        outline.reverse();
+    }
+    let mut pieces = vec![];
+    for (solid, holes) in nest(simple_loops(&outline)) {
+        let ps = bridge(&solid, &holes);
+        let triangles = match triangulate(&ps, (0..ps.len()).collect()) {
+            Some(triangles) => triangles,
+            None => return None,
+        };
+        pieces.extend(merge(&ps, triangles)
+            .into_iter()
+            .map(|piece| piece.into_iter().map(|i| ps[i]).collect::<Vec<_>>()));
+    }
+    if pieces.is_empty() { None } else { Some(pieces) }
+}
+
+// Whether `p` is inside the outline, by counting the edges a ray to the right crosses.
+pub fn contains(outline: &[[f64; 2]], p: [f64; 2]) -> bool {
+    let n = outline.len();
+    let mut inside = false;
+    for i in 0..n {
+        let (a, b) = (outline[i], outline[(i + 1) % n]);
+        if (a[1] > p[1]) != (b[1] > p[1]) &&
+           p[0] < a[0] + (p[1] - a[1]) / (b[1] - a[1]) * (b[0] - a[0]) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+// The outline's edges, less the cuts `bridge` joins holes on with, which run through
+// solid ground.
+pub fn edges(outline: &[[f64; 2]]) -> Vec<([f64; 2], [f64; 2])> {
+    let n = outline.len();
+    let edge = |i: usize| (outline[i], outline[(i + 1) % n]);
+    (0..n)
+        .map(&edge)
+        .filter(|&(a, b)| !(0..n).any(|j| edge(j) == (b, a)))
+        .collect()
+}
+
+// The point on the outline closest to `p`.
+pub fn nearest_on(outline: &[[f64; 2]], p: [f64; 2]) -> Option<[f64; 2]> {
+    let mut best: Option<([f64; 2], f64)> = None;
+    for (a, b) in edges(outline) {
+        let ab = [b[0] - a[0], b[1] - a[1]];
+        let length = ab[0] * ab[0] + ab[1] * ab[1];
+        let t = if length == 0.0 {
+            0.0
+        } else {
+            (((p[0] - a[0]) * ab[0] + (p[1] - a[1]) * ab[1]) / length).max(0.0).min(1.0)
+        };
+        let q = [a[0] + ab[0] * t, a[1] + ab[1] * t];
+        let d = (q[0] - p[0]).powi(2) + (q[1] - p[1]).powi(2);
+        if best.map_or(true, |(_, b)| d < b) {
+            best = Some((q, d));
+        }
+    }
+    best.map(|(q, _)| q)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A square of four cells with one cut out of a corner.
+    const L: [[f64; 2]; 6] = [[0.0, 0.0], [2.0, 0.0], [2.0, 1.0], [1.0, 1.0], [1.0, 2.0],
+                              [0.0, 2.0]];
+
+    fn area(pieces: &[Vec<[f64; 2]>]) -> f64 {
+        pieces.iter().map(|p| signed_area(p).abs()).sum()
+    }
+
+    fn all_convex(pieces: &[Vec<[f64; 2]>]) -> bool {
+        pieces.iter().all(|p| {
+            let n = p.len();
+            (0..n).all(|i| cross(p[i], p[(i + 1) % n], p[(i + 2) % n]) >= 0.0)
+        })
+    }
+
+    #[test]
+    fn concave_outline_is_cut_into_convex_pieces() {
+        for outline in [L.to_vec(), L.iter().rev().cloned().collect()].iter() {
+            let pieces = convex_pieces(outline).unwrap();
+            assert_eq!(pieces.len(), 2);
+            assert!(all_convex(&pieces));
+            assert_eq!(area(&pieces), 3.0);
+        }
+    }
+
+    #[test]
+    fn collinear_runs_stay_in_one_piece() {
+        let outline = [[0.0, 0.0], [1.0, 0.0], [2.0, 0.0], [3.0, 0.0], [3.0, 1.0], [3.0, 2.0],
+                       [2.0, 2.0], [1.0, 2.0], [0.0, 2.0], [0.0, 1.0]];
+        let pieces = convex_pieces(&outline).unwrap();
+        assert_eq!(pieces.len(), 1);
+        assert!(all_convex(&pieces));
+        assert_eq!(area(&pieces), 6.0);
+    }
+
+    #[test]
+    fn touching_outlines_are_split_where_they_touch() {
+        // Two squares meeting at a corner, traced in one go.
+        let touching = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [2.0, 1.0], [2.0, 2.0], [1.0, 2.0],
+                        [1.0, 1.0], [0.0, 1.0]];
+        let pieces = convex_pieces(&touching).unwrap();
+        assert_eq!(pieces.len(), 2);
+        assert!(all_convex(&pieces));
+        assert_eq!(area(&pieces), 2.0);
+    }
+
+    #[test]
+    fn crossing_outlines_are_refused() {
+        let crossing = [[0.0, 0.0], [2.0, 2.0], [2.0, 0.0], [0.0, 2.0]];
+        assert!(convex_pieces(&crossing).is_none());
+        assert!(convex_pieces(&L[..2]).is_none());
+    }
+
+    #[test]
+    fn holes_are_left_out() {
+        // A square of nine cells with the middle one empty.
+        let square = [[0.0, 0.0], [3.0, 0.0], [3.0, 3.0], [0.0, 3.0]];
+        let hole = vec![[1.0, 1.0], [1.0, 2.0], [2.0, 2.0], [2.0, 1.0]];
+        let outline = bridge(&square, &[hole]);
+        let pieces = convex_pieces(&outline).unwrap();
+        assert!(all_convex(&pieces));
+        assert_eq!(area(&pieces), 8.0);
+        assert!(contains(&outline, [0.5, 1.5]));
+        assert!(contains(&outline, [2.5, 1.5]));
+        assert!(!contains(&outline, [1.5, 1.5]));
+        // The cut joining the hole on runs through the ground, so it's no way out.
+        assert_eq!(nearest_on(&outline, [2.25, 1.125]), Some([2.0, 1.125]));
+    }
+
+    #[test]
+    fn contains_leaves_out_the_notch() {
+        assert!(contains(&L, [0.5, 1.5]));
+        assert!(contains(&L, [1.5, 0.5]));
+        assert!(!contains(&L, [1.5, 1.5]));
+        assert!(!contains(&L, [3.0, 0.5]));
+        assert!(!contains(&L, [-0.5, 0.5]));
+    }
+
+    #[test]
+    fn nearest_on_finds_the_closest_edge() {
+        assert_eq!(nearest_on(&L, [1.25, 1.5]), Some([1.0, 1.5]));
+        assert_eq!(nearest_on(&L, [0.5, 0.25]), Some([0.5, 0.0]));
+        assert_eq!(nearest_on(&L, [3.0, 0.5]), Some([2.0, 0.5]));
+        assert_eq!(nearest_on(&[], [0.0, 0.0]), None);
+    }
+}
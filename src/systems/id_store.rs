@@ -4,6 +4,7 @@ use std::collections::HashMap;
 use std::hash::Hash;
 use std::cmp::min;
 
+#[derive(Clone)]
 pub struct IdStore(HashSet<usize>, usize);
 
 impl IdStore {
@@ -30,6 +31,7 @@ impl IdStore {
     }
 }
 
+#[derive(Clone)]
 pub struct IdMap<T>(IdStore, HashMap<T, usize>);
 
 impl<T> IdMap<T>
@@ -51,4 +53,19 @@ impl<T> IdMap<T>
             self.0.release(i);
         }
     }
+
+    // Every key with the id it maps to, lowest id first.
+    pub fn entries(&self) -> Vec<(T, usize)> {
+        let mut entries: Vec<(T, usize)> = self.1.iter().map(|(&k, &i)| (k, i)).collect();
+        entries.sort_by_key(|&(_, i)| i);
+        entries
+    }
+
+    // Renames every key, keeping the ids they map to.
+    pub fn remap<F>(&mut self, f: F)
+        where F: Fn(T) -> T
+    {
+        let renamed = self.1.drain().map(|(k, i)| (f(k), i)).collect();
+        self.1 = renamed;
+    }
 }
\ No newline at end of file
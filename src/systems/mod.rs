@@ -1,5 +1,15 @@
+pub mod ai;
+pub mod animation;
 pub mod assorted;
+pub mod character;
 pub mod collision;
 pub mod components;
+pub mod decompose;
 pub mod terrain;
-pub mod id_store;
\ No newline at end of file
+pub mod zones;
+pub mod id_store;
+pub mod navigation;
+pub mod particles;
+pub mod profile;
+pub mod query;
+pub mod spells;
\ No newline at end of file
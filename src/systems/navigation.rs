@@ -0,0 +1,392 @@
+use specs::{System, WriteStorage, Join, FetchMut};
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+
+use systems::components::*;
+
+// Columns of cells between the ones the graph samples.
+pub const NAV_SPACING: usize = 8;
+// Empty cells a wizard needs above a surface to stand on it.
+const CLEARANCE: usize = 50;
+// Height change that can be walked between neighbouring samples, and the most that can
+// be jumped up or fallen down.
+const CLIMB: usize = 12;
+const JUMP_HEIGHT: usize = 40;
+const MAX_FALL: usize = 300;
+// Samples a jump can cover, gap included.
+const JUMP_SPAN: usize = 5;
+// Extra cost of the moves that are riskier than walking.
+const JUMP_COST: f64 = 20.0;
+const FALL_COST: f64 = 10.0;
+// Nodes the search may expand before it gives up.
+const MAX_SEARCH: usize = 20000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NavLink {
+    Walk,
+    Jump,
+    Fall,
+}
+
+// One point on a path, and how to get there from the one before.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NavStep {
+    pub pos: [f64; 2],
+    pub link: NavLink,
+}
+
+// Where wizards can stand on the terrain and how they can get between those places.
+// Every `NAV_SPACING`th column is scanned for the tops of its solid spans; each top with
+// room to stand above it is a node. Links are worked out from neighbouring columns when
+// they're asked for, so an edit only means scanning the columns it touched again.
+pub struct NavGraph {
+    // Solid spans (top, bottom) of each sampled column, top first.
+    columns: BTreeMap<usize, Vec<[usize; 2]>>,
+    // Rows scanned in each column.
+    height: usize,
+    built: bool,
+}
+
+fn scan_column(terrain: &Terrain, x: usize, height: usize) -> Vec<[usize; 2]> {
+    let mut spans = vec![];
+    let mut top = None;
+    for y in 0..height + 1 {
+        match (top, y < height && terrain.points.contains(&[x, y])) {
+            (None, true) => top = Some(y),
+            (Some(t), false) => {
+                spans.push([t, y - 1]);
+                top = None;
+            }
+            _ => {}
+        }
+    }
+    spans
+}
+
+// Tops of the spans with room to stand on.
+fn surfaces(spans: &[[usize; 2]]) -> Vec<usize> {
+    let mut out = vec![];
+    let mut ceiling = None;
+    for span in spans {
+        if ceiling.map_or(span[0], |c: usize| span[0] - c - 1) >= CLEARANCE {
+            out.push(span[0]);
+        }
+        ceiling = Some(span[1]);
+    }
+    out
+}
+
+fn distance(a: [usize; 2], b: [usize; 2]) -> f64 {
+    let dx = a[0] as f64 - b[0] as f64;
+    let dy = a[1] as f64 - b[1] as f64;
+    (dx * dx + dy * dy).sqrt()
+}
+
+fn cost(from: [usize; 2], to: [usize; 2], link: NavLink) -> f64 {
+    distance(from, to) +
+    match link {
+        NavLink::Walk => 0.0,
+        NavLink::Jump => JUMP_COST,
+        NavLink::Fall => FALL_COST,
+    }
+}
+
+fn world_pos(node: [usize; 2]) -> [f64; 2] {
+    [node[0] as f64, node[1] as f64]
+}
+
+#[derive(PartialEq)]
+struct Open {
+    estimate: f64,
+    node: [usize; 2],
+}
+
+impl Eq for Open {}
+
+// Reversed, so the heap hands out the cheapest estimate first.
+impl Ord for Open {
+    fn cmp(&self, other: &Open) -> Ordering {
+        other.estimate
+            .partial_cmp(&self.estimate)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| self.node.cmp(&other.node))
+    }
+}
+
+impl PartialOrd for Open {
+    fn partial_cmp(&self, other: &Open) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl NavGraph {
+    pub fn new() -> NavGraph {
+        NavGraph {
+            columns: BTreeMap::new(),
+            height: 0,
+            built: false,
+        }
+    }
+
+    pub fn built(&self) -> bool {
+        self.built
+    }
+
+    pub fn rebuild(&mut self, terrain: &Terrain) {
+        self.columns.clear();
+        self.height = terrain.points.iter().map(|p| p[1] + 1).max().unwrap_or(0);
+        let width = terrain.points.iter().map(|p| p[0] + 1).max().unwrap_or(0);
+        for column in 0..(width + NAV_SPACING - 1) / NAV_SPACING {
+            self.scan(terrain, column);
+        }
+        self.built = true;
+        debug!("built navigation graph columns={} nodes={}",
+               self.columns.len(),
+               self.nodes().len());
+    }
+
+    // Scans again the columns that cross `cells`, a box as in `Terrain::edited`.
+    pub fn update(&mut self, terrain: &Terrain, cells: [usize; 4]) {
+        self.height = self.height.max(cells[3] + 1);
+        let first = (cells[0] + NAV_SPACING - 1) / NAV_SPACING;
+        let last = cells[2] / NAV_SPACING;
+        for column in first..last + 1 {
+            self.scan(terrain, column);
+        }
+    }
+
+    fn scan(&mut self, terrain: &Terrain, column: usize) {
+        let spans = scan_column(terrain, column * NAV_SPACING, self.height);
+        if spans.is_empty() {
+            self.columns.remove(&column);
+        } else {
+            self.columns.insert(column, spans);
+        }
+    }
+
+    fn surfaces(&self, column: usize) -> Vec<usize> {
+        self.columns.get(&column).map_or(vec![], |spans| surfaces(spans))
+    }
+
+    // Every place to stand, in world coordinates.
+    pub fn nodes(&self) -> Vec<[f64; 2]> {
+        self.columns
+            .keys()
+            .flat_map(|&c| {
+                self.surfaces(c).into_iter().map(move |y| world_pos([c * NAV_SPACING, y]))
+            })
+            .collect()
+    }
+
+    // Whether a column has nothing solid in the rows a wizard standing at `y` would move
+    // through, so it can only be crossed with a jump.
+    fn gap(&self, column: usize, y: usize) -> bool {
+        let band = [y.saturating_sub(JUMP_HEIGHT + CLEARANCE), y + CLIMB];
+        self.columns
+            .get(&column)
+            .map_or(true, |spans| spans.iter().all(|s| s[1] < band[0] || s[0] > band[1]))
+    }
+
+    // The nodes reachable in one move from `node`.
+    pub fn links(&self, node: [usize; 2]) -> Vec<([usize; 2], NavLink)> {
+        let column = node[0] / NAV_SPACING;
+        let y = node[1];
+        let mut out = vec![];
+        for &dir in [-1i64, 1].iter() {
+            for k in 1..JUMP_SPAN + 1 {
+                let c = column as i64 + dir * k as i64;
+                if c < 0 {
+                    break;
+                }
+                let c = c as usize;
+                for to in self.surfaces(c) {
+                    let link = if k > 1 {
+                        if (to as i64 - y as i64).abs() as usize > JUMP_HEIGHT {
+                            continue;
+                        }
+                        NavLink::Jump
+                    } else if (to as i64 - y as i64).abs() as usize <= CLIMB {
+                        NavLink::Walk
+                    } else if to > y && to - y <= MAX_FALL {
+                        NavLink::Fall
+                    } else if to < y && y - to <= JUMP_HEIGHT {
+                        NavLink::Jump
+                    } else {
+                        continue;
+                    };
+                    out.push(([c * NAV_SPACING, to], link));
+                }
+                // Only jump further while there's nothing to land on or run into.
+                if !self.gap(c, y) {
+                    break;
+                }
+            }
+        }
+        out
+    }
+
+    // The node closest to a world point.
+    pub fn nearest(&self, point: [f64; 2]) -> Option<[usize; 2]> {
+        let column = (point[0].max(0.0) as usize + NAV_SPACING / 2) / NAV_SPACING;
+        let p = [point[0].max(0.0) as usize, point[1].max(0.0) as usize];
+        let mut best: Option<([usize; 2], f64)> = None;
+        for c in column.saturating_sub(JUMP_SPAN)..column + JUMP_SPAN + 1 {
+            for y in self.surfaces(c) {
+                let node = [c * NAV_SPACING, y];
+                let d = distance(node, p);
+                if best.map_or(true, |(_, b)| d < b) {
+                    best = Some((node, d));
+                }
+            }
+        }
+        best.map(|(node, _)| node)
+    }
+
+    // A* from the node nearest `from` to the node nearest `to`. The first step is the
+    // start node itself; None if there's no way there.
+    pub fn path(&self, from: [f64; 2], to: [f64; 2]) -> Option<Vec<NavStep>> {
+        let (start, goal) = match (self.nearest(from), self.nearest(to)) {
+            (Some(s), Some(g)) => (s, g),
+            _ => return None,
+        };
+        let mut open = BinaryHeap::new();
+        let mut best: HashMap<[usize; 2], f64> = HashMap::new();
+        let mut came_from: HashMap<[usize; 2], ([usize; 2], NavLink)> = HashMap::new();
+        best.insert(start, 0.0);
+        open.push(Open {
+            estimate: distance(start, goal),
+            node: start,
+        });
+        let mut expanded = 0;
+        while let Some(Open { node, .. }) = open.pop() {
+            if node == goal {
+                let mut steps = vec![];
+                let mut at = goal;
+                while let Some(&(previous, link)) = came_from.get(&at) {
+                    steps.push(NavStep {
+                        pos: world_pos(at),
+                        link: link,
+                    });
+                    at = previous;
+                }
+                steps.push(NavStep {
+                    pos: world_pos(start),
+                    link: NavLink::Walk,
+                });
+                steps.reverse();
+                return Some(steps);
+            }
+            expanded += 1;
+            if expanded > MAX_SEARCH {
+                warn!("gave up on path from={:?} to={:?} expanded={}", start, goal, expanded);
+                return None;
+            }
+            let so_far = best.get(&node).cloned().unwrap_or(0.0);
+            for (next, link) in self.links(node) {
+                let through = so_far + cost(node, next, link);
+                if best.get(&next).map_or(false, |&b| b <= through) {
+                    continue;
+                }
+                best.insert(next, through);
+                came_from.insert(next, (node, link));
+                open.push(Open {
+                    estimate: through + distance(next, goal),
+                    node: next,
+                });
+            }
+        }
+        None
+    }
+}
+
+// Keeps the `NavGraph` resource in step with the level's terrain.
+pub struct NavSystem;
+
+impl<'a> System<'a> for NavSystem {
+    type SystemData = (WriteStorage<'a, Terrain>, FetchMut<'a, NavGraph>);
+    fn run(&mut self, (mut terrain, mut nav): Self::SystemData) {
+        for terrain in (&mut terrain).join() {
+            if !nav.built() {
+                nav.rebuild(terrain);
+                terrain.edited = None;
+            } else if let Some(cells) = terrain.edited.take() {
+                nav.update(terrain, cells);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use level::{build_terrain, TerrainSource};
+    use systems::terrain::carve;
+
+    // Two ledges 50 cells thick with their tops at y = 200, the left one ending at x = 96
+    // and the right one starting at `right`.
+    fn ledges(right: usize) -> Terrain {
+        let ledge = |x: usize, width: usize| {
+            TerrainSource::Rect {
+                x: x,
+                y: 200,
+                width: width,
+                height: 50,
+                material: Material::Rock,
+            }
+        };
+        build_terrain(&[ledge(0, 96), ledge(right, 96)])
+    }
+
+    fn graph(terrain: &Terrain) -> NavGraph {
+        let mut nav = NavGraph::new();
+        nav.rebuild(terrain);
+        nav
+    }
+
+    fn jumps(path: &[NavStep]) -> usize {
+        path.iter().filter(|s| s.link == NavLink::Jump).count()
+    }
+
+    #[test]
+    fn links_walk_along_and_jump_across() {
+        let nav = graph(&ledges(128));
+        let links = nav.links([88, 200]);
+        assert!(links.contains(&([80, 200], NavLink::Walk)));
+        assert!(links.contains(&([128, 200], NavLink::Jump)));
+        assert!(!links.iter().any(|&(to, _)| to[0] > 128));
+        assert!(nav.links([40, 200]).iter().all(|&(_, link)| link == NavLink::Walk));
+    }
+
+    #[test]
+    fn gap_is_only_where_theres_nothing_to_stand_on() {
+        let nav = graph(&ledges(128));
+        assert!(!nav.gap(11, 200));
+        for column in 12..16 {
+            assert!(nav.gap(column, 200));
+        }
+        assert!(!nav.gap(16, 200));
+    }
+
+    #[test]
+    fn path_jumps_a_narrow_gap_but_not_a_wide_one() {
+        let path = graph(&ledges(128)).path([40.0, 190.0], [184.0, 190.0]).unwrap();
+        assert_eq!(path[0].pos, [40.0, 200.0]);
+        assert_eq!(path[path.len() - 1].pos, [184.0, 200.0]);
+        assert_eq!(jumps(&path), 1);
+        assert!(graph(&ledges(200)).path([40.0, 190.0], [250.0, 190.0]).is_none());
+    }
+
+    #[test]
+    fn update_after_carving_matches_a_rebuild() {
+        let mut terrain = ledges(128);
+        let mut nav = graph(&terrain);
+        terrain.edited = None;
+        // Takes the near end of the right ledge away, leaving a gap too wide to jump.
+        carve(&mut terrain, [150.0, 225.0], 60.0);
+        assert!(nav.path([40.0, 190.0], [216.0, 190.0]).is_some());
+        nav.update(&terrain, terrain.edited.unwrap());
+        assert_eq!(nav.nodes(), graph(&terrain).nodes());
+        assert!(nav.path([40.0, 190.0], [216.0, 190.0]).is_none());
+    }
+}
@@ -0,0 +1,268 @@
+use specs::{ReadStorage, System, WriteStorage, Join, Fetch, FetchMut, Component, VecStorage};
+
+use systems::components::*;
+use std::f64::consts::PI;
+
+#[derive(Clone, Copy)]
+pub struct Particle {
+    pub pos: [f64; 2],
+    pub vel: [f64; 2],
+    pub age: f64,
+    pub lifetime: f64,
+    pub gravity: f64,
+    pub size: f64,
+    pub colour_start: [f32; 4],
+    pub colour_end: [f32; 4],
+}
+
+impl Particle {
+    pub fn colour(&self) -> [f32; 4] {
+        let t = (self.age / self.lifetime).min(1.0) as f32;
+        let mut c = [0.0; 4];
+        for i in 0..4 {
+            c[i] = self.colour_start[i] + (self.colour_end[i] - self.colour_start[i]) * t;
+        }
+        c
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct EmitterConfig {
+    // Particles per second for continuous emitters.
+    pub rate: f64,
+    pub lifetime: f64,
+    pub speed: [f64; 2],
+    pub direction: f64,
+    pub spread: f64,
+    pub gravity: f64,
+    pub size: f64,
+    pub colour_start: [f32; 4],
+    pub colour_end: [f32; 4],
+}
+
+impl EmitterConfig {
+    pub fn explosion() -> EmitterConfig {
+        EmitterConfig {
+            rate: 0.0,
+            lifetime: 0.6,
+            speed: [40.0, 160.0],
+            direction: 0.0,
+            spread: PI,
+            gravity: 60.0,
+            size: 4.0,
+            colour_start: [1.0, 0.85, 0.3, 1.0],
+            colour_end: [0.4, 0.1, 0.0, 0.0],
+        }
+    }
+
+    pub fn spell_cast() -> EmitterConfig {
+        EmitterConfig {
+            rate: 0.0,
+            lifetime: 0.4,
+            speed: [20.0, 60.0],
+            direction: -PI / 2.0,
+            spread: PI / 3.0,
+            gravity: -20.0,
+            size: 3.0,
+            colour_start: [0.7, 0.6, 1.0, 1.0],
+            colour_end: [0.3, 0.2, 0.9, 0.0],
+        }
+    }
+
+    pub fn debris(material: Material) -> EmitterConfig {
+        let colour = match material {
+            Material::Dirt => [0.47, 0.33, 0.2, 1.0],
+            Material::Rock => [0.4, 0.4, 0.44, 1.0],
+            Material::Sand => [0.82, 0.74, 0.47, 1.0],
+        };
+        EmitterConfig {
+            rate: 0.0,
+            lifetime: 0.8,
+            speed: [30.0, 90.0],
+            direction: -PI / 2.0,
+            spread: PI / 2.0,
+            gravity: 200.0,
+            size: 2.0,
+            colour_start: colour,
+            colour_end: [colour[0], colour[1], colour[2], 0.0],
+        }
+    }
+
+    pub fn trail() -> EmitterConfig {
+        EmitterConfig {
+            rate: 60.0,
+            lifetime: 0.3,
+            speed: [0.0, 10.0],
+            direction: 0.0,
+            spread: PI,
+            gravity: 0.0,
+            size: 3.0,
+            colour_start: [1.0, 0.6, 0.2, 0.8],
+            colour_end: [0.5, 0.5, 0.5, 0.0],
+        }
+    }
+
+    pub fn footstep() -> EmitterConfig {
+        EmitterConfig {
+            rate: 0.0,
+            lifetime: 0.3,
+            speed: [5.0, 20.0],
+            direction: -PI / 2.0,
+            spread: PI / 2.0,
+            gravity: 20.0,
+            size: 2.0,
+            colour_start: [0.6, 0.5, 0.4, 0.6],
+            colour_end: [0.6, 0.5, 0.4, 0.0],
+        }
+    }
+}
+
+// A continuous emitter that follows its entity.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Emitter {
+    pub config: EmitterConfig,
+    pub offset: [f64; 2],
+    accumulator: f64,
+}
+
+impl Emitter {
+    pub fn new(config: EmitterConfig) -> Emitter {
+        Emitter {
+            config: config,
+            offset: [0.0, 0.0],
+            accumulator: 0.0,
+        }
+    }
+}
+
+impl Component for Emitter {
+    type Storage = VecStorage<Self>;
+}
+
+pub struct Burst {
+    pub pos: [f64; 2],
+    pub count: usize,
+    pub config: EmitterConfig,
+}
+
+// One-shot effects requested by other systems, spawned on the next particle update.
+pub struct Bursts(pub Vec<Burst>);
+
+impl Bursts {
+    pub fn push(&mut self, pos: [f64; 2], count: usize, config: EmitterConfig) {
+        self.0.push(Burst {
+            pos: pos,
+            count: count,
+            config: config,
+        });
+    }
+}
+
+// Cheap xorshift so effects don't need an rng crate and stay reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % 1_000_000) as f64 / 1_000_000.0
+    }
+
+    fn range(&mut self, min: f64, max: f64) -> f64 {
+        min + (max - min) * self.next()
+    }
+}
+
+// Fixed size pool; dead particles are recycled through the free list rather than reallocated.
+pub struct Particles {
+    pub particles: Vec<Particle>,
+    pub alive: Vec<bool>,
+    free: Vec<usize>,
+    rng: Rng,
+}
+
+pub const MAX_PARTICLES: usize = 4096;
+
+impl Particles {
+    pub fn new() -> Particles {
+        Particles {
+            particles: Vec::with_capacity(MAX_PARTICLES),
+            alive: Vec::with_capacity(MAX_PARTICLES),
+            free: vec![],
+            rng: Rng(0x2545F4914F6CDD1D),
+        }
+    }
+
+    fn emit(&mut self, pos: [f64; 2], config: &EmitterConfig) {
+        let angle = config.direction + self.rng.range(-config.spread, config.spread);
+        let speed = self.rng.range(config.speed[0], config.speed[1]);
+        let particle = Particle {
+            pos: pos,
+            vel: [angle.cos() * speed, angle.sin() * speed],
+            age: 0.0,
+            lifetime: config.lifetime * self.rng.range(0.7, 1.3),
+            gravity: config.gravity,
+            size: config.size,
+            colour_start: config.colour_start,
+            colour_end: config.colour_end,
+        };
+        if let Some(i) = self.free.pop() {
+            self.particles[i] = particle;
+            self.alive[i] = true;
+        } else if self.particles.len() < MAX_PARTICLES {
+            self.particles.push(particle);
+            self.alive.push(true);
+        }
+    }
+
+    fn step(&mut self, dt: f64) {
+        for i in 0..self.particles.len() {
+            if !self.alive[i] {
+                continue;
+            }
+            let expired = {
+                let p = &mut self.particles[i];
+                p.age += dt;
+                p.vel[1] += p.gravity * dt;
+                p.pos[0] += p.vel[0] * dt;
+                p.pos[1] += p.vel[1] * dt;
+                p.age >= p.lifetime
+            };
+            if expired {
+                self.alive[i] = false;
+                self.free.push(i);
+            }
+        }
+    }
+
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item = &'a Particle> + 'a> {
+        Box::new(self.particles.iter().zip(self.alive.iter()).filter(|&(_, &a)| a).map(|(p, _)| p))
+    }
+}
+
+pub struct ParticleSystem;
+
+impl<'a> System<'a> for ParticleSystem {
+    type SystemData = (WriteStorage<'a, Emitter>,
+     ReadStorage<'a, Pos>,
+     FetchMut<'a, Particles>,
+     FetchMut<'a, Bursts>,
+     Fetch<'a, Delta>);
+    fn run(&mut self, (mut emitters, pos, mut particles, mut bursts, delta): Self::SystemData) {
+        for burst in bursts.0.drain(..) {
+            for _ in 0..burst.count {
+                particles.emit(burst.pos, &burst.config);
+            }
+        }
+        for (emitter, pos) in (&mut emitters, &pos).join() {
+            emitter.accumulator += emitter.config.rate * delta.0;
+            let at = [pos.x + emitter.offset[0], pos.y + emitter.offset[1]];
+            while emitter.accumulator >= 1.0 {
+                emitter.accumulator -= 1.0;
+                particles.emit(at, &emitter.config);
+            }
+        }
+        particles.step(delta.0);
+    }
+}
@@ -0,0 +1,130 @@
+use specs::System;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const HISTORY: usize = 600;
+
+pub fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1e6
+}
+
+#[derive(Clone, Default)]
+pub struct FrameSample {
+    pub frame_ms: f64,
+    pub systems: Vec<(&'static str, f64)>,
+    pub collision_iterations: usize,
+    pub terrain_retrace_ms: f64,
+}
+
+#[derive(Default)]
+struct ProfileData {
+    current: FrameSample,
+    history: VecDeque<FrameSample>,
+}
+
+// Shared between the dispatcher's systems, which may run on any thread, and the game loop.
+#[derive(Clone)]
+pub struct Profiler(Arc<Mutex<ProfileData>>);
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler(Arc::new(Mutex::new(ProfileData::default())))
+    }
+
+    pub fn record(&self, name: &'static str, ms: f64) {
+        if let Ok(mut data) = self.0.lock() {
+            data.current.systems.push((name, ms));
+        }
+    }
+
+    pub fn collision_iterations(&self, iterations: usize) {
+        if let Ok(mut data) = self.0.lock() {
+            data.current.collision_iterations = iterations;
+        }
+    }
+
+    pub fn terrain_retrace(&self, ms: f64) {
+        if let Ok(mut data) = self.0.lock() {
+            data.current.terrain_retrace_ms += ms;
+        }
+    }
+
+    pub fn end_frame(&self, frame_ms: f64) {
+        if let Ok(mut data) = self.0.lock() {
+            let mut sample = ::std::mem::replace(&mut data.current, FrameSample::default());
+            sample.frame_ms = frame_ms;
+            sample.systems.sort_by(|a, b| a.0.cmp(b.0));
+            data.history.push_back(sample);
+            while data.history.len() > HISTORY {
+                data.history.pop_front();
+            }
+        }
+    }
+
+    pub fn latest(&self) -> Option<FrameSample> {
+        self.0.lock().ok().and_then(|data| data.history.back().cloned())
+    }
+
+    // Writes the recorded history as csv, one row per frame and one column per system.
+    pub fn dump(&self, path: &Path) -> io::Result<()> {
+        let data = match self.0.lock() {
+            Ok(data) => data,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "profiler lock poisoned")),
+        };
+        let mut file = try!(File::create(path));
+        let names: Vec<&'static str> = data.history
+            .back()
+            .map_or(vec![], |s| s.systems.iter().map(|&(n, _)| n).collect());
+        try!(write!(file, "frame_ms,collision_iterations,terrain_retrace_ms"));
+        for name in names.iter() {
+            try!(write!(file, ",{}", name));
+        }
+        try!(writeln!(file, ""));
+        for sample in data.history.iter() {
+            try!(write!(file,
+                        "{:.3},{},{:.3}",
+                        sample.frame_ms,
+                        sample.collision_iterations,
+                        sample.terrain_retrace_ms));
+            for name in names.iter() {
+                let ms = sample.systems.iter().find(|&&(n, _)| n == *name).map_or(0.0, |&(_, ms)| ms);
+                try!(write!(file, ",{:.3}", ms));
+            }
+            try!(writeln!(file, ""));
+        }
+        Ok(())
+    }
+}
+
+// Wraps a system so every run is timed into the profiler under `name`.
+pub struct Timed<S> {
+    system: S,
+    name: &'static str,
+    profiler: Profiler,
+}
+
+impl<S> Timed<S> {
+    pub fn new(system: S, name: &'static str, profiler: &Profiler) -> Timed<S> {
+        Timed {
+            system: system,
+            name: name,
+            profiler: profiler.clone(),
+        }
+    }
+}
+
+impl<'a, S> System<'a> for Timed<S>
+    where S: System<'a>
+{
+    type SystemData = S::SystemData;
+    fn run(&mut self, data: Self::SystemData) {
+        let start = Instant::now();
+        self.system.run(data);
+        self.profiler.record(self.name, millis(start.elapsed()));
+    }
+}
@@ -0,0 +1,345 @@
+use specs::Entity;
+
+use std::cmp::Ordering;
+
+use ncollide::query::{self, PointQuery, Ray, RayCast};
+use ncollide::shape::*;
+use ncollide::bounding_volume::*;
+use ncollide::world::CollisionWorld2;
+use nalgebra as na;
+use nalgebra::*;
+use systems::components::*;
+use systems::collision::shape_of;
+
+// How far apart two shapes may be and still report a contact when working out where a
+// sweep touched.
+const SWEEP_PREDICTION: f64 = 0.5;
+
+#[derive(Clone)]
+struct QueryObject {
+    entity: Entity,
+    layer: Layer,
+    shape: ShapeHandle2<f64>,
+    position: Isometry2<f64>,
+}
+
+// What a query ran into. `normal` faces away from the surface that was hit, and
+// `distance` is how far along the ray or sweep it was.
+#[derive(Clone, Copy, Debug)]
+pub struct QueryHit {
+    pub entity: Entity,
+    pub point: [f64; 2],
+    pub normal: [f64; 2],
+    pub distance: f64,
+}
+
+// Which objects a query looks at.
+#[derive(Clone, Debug)]
+pub struct QueryFilter {
+    layers: [bool; LAYERS],
+    exclude: Vec<Entity>,
+}
+
+impl QueryFilter {
+    // Everything solid, which leaves out sensors.
+    pub fn solid() -> QueryFilter {
+        let mut filter = QueryFilter::layers(&Layer::all());
+        filter.layers[Layer::Sensors.index()] = false;
+        filter
+    }
+
+    pub fn layers(layers: &[Layer]) -> QueryFilter {
+        let mut on = [false; LAYERS];
+        for layer in layers {
+            on[layer.index()] = true;
+        }
+        QueryFilter {
+            layers: on,
+            exclude: vec![],
+        }
+    }
+
+    pub fn excluding(mut self, entity: Entity) -> QueryFilter {
+        self.exclude.push(entity);
+        self
+    }
+
+    fn accepts(&self, object: &QueryObject) -> bool {
+        self.layers[object.layer.index()] && !self.exclude.contains(&object.entity)
+    }
+}
+
+// A copy of the collision world's shapes as the collision system left them, so other
+// systems can ask what's where. Refreshed at the end of every collision run; anything
+// that moved since then is found where it was.
+#[derive(Clone, Default)]
+pub struct CollisionQuery {
+    objects: Vec<QueryObject>,
+}
+
+fn hit(entity: Entity, point: Point2<f64>, normal: Vector2<f64>, distance: f64) -> QueryHit {
+    QueryHit {
+        entity: entity,
+        point: [point[0], point[1]],
+        normal: [normal[0], normal[1]],
+        distance: distance,
+    }
+}
+
+fn dedup(mut entities: Vec<Entity>) -> Vec<Entity> {
+    entities.sort_by_key(|e| e.id());
+    entities.dedup();
+    entities
+}
+
+impl CollisionQuery {
+    pub fn capture(world: &CollisionWorld2<f64, Entity>,
+                   layer_of: &Fn(Entity) -> Option<Layer>)
+                   -> CollisionQuery {
+        let mut objects: Vec<QueryObject> = world.collision_objects()
+            .filter_map(|co| {
+                layer_of(co.data).map(|layer| {
+                    QueryObject {
+                        entity: co.data,
+                        layer: layer,
+                        shape: co.shape.clone(),
+                        position: co.position,
+                    }
+                })
+            })
+            .collect();
+        // The world hands objects out in no particular order; keep ties between hits
+        // the same on every peer.
+        objects.sort_by(|a, b| {
+            a.entity
+                .id()
+                .cmp(&b.entity.id())
+                .then_with(|| {
+                    let (pa, pb) = (a.position.translation.vector, b.position.translation.vector);
+                    pa[0].partial_cmp(&pb[0])
+                        .unwrap_or(Ordering::Equal)
+                        .then_with(|| pa[1].partial_cmp(&pb[1]).unwrap_or(Ordering::Equal))
+                })
+        });
+        CollisionQuery { objects: objects }
+    }
+
+    pub fn remap<F>(&self, f: F) -> CollisionQuery
+        where F: Fn(Entity) -> Entity
+    {
+        CollisionQuery {
+            objects: self.objects
+                .iter()
+                .map(|o| QueryObject { entity: f(o.entity), ..o.clone() })
+                .collect(),
+        }
+    }
+
+    // The first thing a ray from `origin` along `dir` hits within `max_distance`. A ray
+    // starting inside a shape hits it straight away.
+    pub fn raycast(&self,
+                   origin: [f64; 2],
+                   dir: [f64; 2],
+                   max_distance: f64,
+                   filter: &QueryFilter)
+                   -> Option<QueryHit> {
+        self.raycast_all(origin, dir, max_distance, filter).into_iter().next()
+    }
+
+    // Everything a ray hits within `max_distance`, nearest first, one hit per entity.
+    pub fn raycast_all(&self,
+                       origin: [f64; 2],
+                       dir: [f64; 2],
+                       max_distance: f64,
+                       filter: &QueryFilter)
+                       -> Vec<QueryHit> {
+        let dir = Vector2::new(dir[0], dir[1]);
+        let length = na::norm(&dir);
+        if length == 0.0 {
+            return vec![];
+        }
+        let ray = Ray::new(Point2::new(origin[0], origin[1]), dir / length);
+        let mut hits: Vec<QueryHit> = vec![];
+        for object in self.objects.iter().filter(|o| filter.accepts(o)) {
+            let cast = match object.shape.as_ray_cast() {
+                Some(cast) => cast,
+                None => continue,
+            };
+            if let Some(i) = cast.toi_and_normal_with_ray(&object.position, &ray, true) {
+                if i.toi > max_distance {
+                    continue;
+                }
+                // Outlines report either side's normal; turn it back towards the ray.
+                let normal = if na::dot(&i.normal, &ray.dir) > 0.0 { -i.normal } else { i.normal };
+                let h = hit(object.entity, ray.origin + ray.dir * i.toi, normal, i.toi);
+                match hits.iter().position(|other| other.entity == object.entity) {
+                    Some(k) if hits[k].distance <= h.distance => {}
+                    Some(k) => hits[k] = h,
+                    None => hits.push(h),
+                }
+            }
+        }
+        hits.sort_by(|a, b| {
+            a.distance.partial_cmp(&b.distance).unwrap_or(Ordering::Equal)
+        });
+        hits
+    }
+
+    // The entities with a shape containing `point`. Outlines that couldn't be cut into
+    // convex pieces have no inside, so only their edges count.
+    pub fn point(&self, point: [f64; 2], filter: &QueryFilter) -> Vec<Entity> {
+        let p = Point2::new(point[0], point[1]);
+        dedup(self.objects
+            .iter()
+            .filter(|o| filter.accepts(o))
+            .filter(|o| o.shape.as_point_query().map_or(false, |q| q.contains_point(&o.position, &p)))
+            .map(|o| o.entity)
+            .collect())
+    }
+
+    // The entities whose bounding boxes overlap the box from `min` to `max`.
+    pub fn aabb(&self, min: [f64; 2], max: [f64; 2], filter: &QueryFilter) -> Vec<Entity> {
+        let area = AABB::new(Point2::new(min[0], min[1]), Point2::new(max[0], max[1]));
+        dedup(self.objects
+            .iter()
+            .filter(|o| filter.accepts(o))
+            .filter(|o| o.shape.as_ref().aabb(&o.position).intersects(&area))
+            .map(|o| o.entity)
+            .collect())
+    }
+
+    // Moves a shape of `bounds` in a straight line from `from` to `to` and reports the
+    // first thing it would touch, with `distance` how far it got.
+    pub fn sweep(&self,
+                 bounds: &Bounds,
+                 from: [f64; 2],
+                 to: [f64; 2],
+                 filter: &QueryFilter)
+                 -> Option<QueryHit> {
+        let shape = match shape_of(bounds) {
+            Some(shape) => shape,
+            None => return None,
+        };
+        let start = Isometry2::new(Vector2::new(from[0], from[1]), na::zero());
+        let motion = Vector2::new(to[0] - from[0], to[1] - from[1]);
+        let length = na::norm(&motion);
+        let still = na::zero::<Vector2<f64>>();
+        let mut best: Option<QueryHit> = None;
+        for object in self.objects.iter().filter(|o| filter.accepts(o)) {
+            let toi = match query::time_of_impact(&start,
+                                                  &motion,
+                                                  shape.as_ref(),
+                                                  &object.position,
+                                                  &still,
+                                                  object.shape.as_ref()) {
+                Some(toi) if toi <= 1.0 => toi,
+                _ => continue,
+            };
+            if best.map_or(false, |b| b.distance <= toi * length) {
+                continue;
+            }
+            let at = Isometry2::new(Vector2::new(from[0], from[1]) + motion * toi, na::zero());
+            let found = match query::contact(&at,
+                                             shape.as_ref(),
+                                             &object.position,
+                                             object.shape.as_ref(),
+                                             SWEEP_PREDICTION) {
+                Some(c) => hit(object.entity, c.world2, -c.normal, toi * length),
+                None => {
+                    let centre = Point2::new(at.translation.vector[0], at.translation.vector[1]);
+                    hit(object.entity, centre, -motion / length.max(1e-9), toi * length)
+                }
+            };
+            best = Some(found);
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use specs::{Join, World};
+    use std::collections::HashMap;
+    use Game;
+    use level;
+    use net::TICK;
+
+    // Ground along the bottom, and a box and a ball in the air between the two wizards.
+    const LEVEL: &'static str = "terrain rect 0 400 800 100 rock\n\
+                                 spawn 100 300\nspawn 700 300\n\
+                                 prop rect 300 300 40 40\nprop circle 500 300 20\n";
+
+    // The query as the collision system left it, and the ground, box and ball.
+    fn query() -> (CollisionQuery, Entity, Entity, Entity) {
+        let mut game = Game::new(&level::parse(LEVEL).ok().unwrap());
+        game.step(&HashMap::new(), TICK);
+        let world: &World = &game.world;
+        let (ent, bounds, terrain) = (world.entities(),
+                                      world.read::<Bounds>(),
+                                      world.read::<Terrain>());
+        let ground = (&*ent, &terrain).join().map(|(e, _)| e).next().unwrap();
+        let find = |wanted: Bounds| {
+            (&*ent, &bounds).join().find(|&(_, b)| *b == wanted).map(|(e, _)| e).unwrap()
+        };
+        let (boxed, ball) = (find(Bounds::Rectangle(40.0, 40.0)), find(Bounds::Circle(20.0)));
+        let query = world.read_resource::<CollisionQuery>().clone();
+        (query, ground, boxed, ball)
+    }
+
+    fn close(a: [f64; 2], b: [f64; 2]) -> bool {
+        (a[0] - b[0]).abs() < 1e-3 && (a[1] - b[1]).abs() < 1e-3
+    }
+
+    #[test]
+    fn raycast_finds_the_nearest_hit() {
+        let (query, _, boxed, ball) = query();
+        let terrain = QueryFilter::layers(&[Layer::Terrain]);
+        let hit = query.raycast([200.0, 300.0], [1.0, 0.0], 500.0, &terrain).unwrap();
+        assert_eq!(hit.entity, boxed);
+        assert!(close(hit.point, [280.0, 300.0]));
+        assert!(close(hit.normal, [-1.0, 0.0]));
+        assert!((hit.distance - 80.0).abs() < 1e-3);
+
+        let all = query.raycast_all([200.0, 300.0], [1.0, 0.0], 500.0, &terrain);
+        assert_eq!(all.iter().map(|h| h.entity).collect::<Vec<_>>(), vec![boxed, ball]);
+        assert!((all[1].distance - 280.0).abs() < 1e-3);
+        assert!(query.raycast([200.0, 300.0], [1.0, 0.0], 50.0, &terrain).is_none());
+        assert!(query.raycast([200.0, 300.0], [1.0, 0.0], 500.0, &terrain.excluding(boxed))
+            .map_or(false, |h| h.entity == ball));
+    }
+
+    #[test]
+    fn point_finds_what_contains_it() {
+        let (query, ground, boxed, _) = query();
+        let solid = QueryFilter::solid();
+        assert_eq!(query.point([310.0, 290.0], &solid), vec![boxed]);
+        assert_eq!(query.point([150.0, 450.0], &solid), vec![ground]);
+        assert!(query.point([200.0, 300.0], &solid).is_empty());
+    }
+
+    #[test]
+    fn aabb_finds_what_overlaps_it() {
+        let (query, _, boxed, ball) = query();
+        let terrain = QueryFilter::layers(&[Layer::Terrain]);
+        assert_eq!(query.aabb([270.0, 270.0], [530.0, 330.0], &terrain),
+                   dedup(vec![boxed, ball]));
+        assert_eq!(query.aabb([290.0, 250.0], [310.0, 270.0], &terrain), vec![]);
+    }
+
+    #[test]
+    fn sweep_stops_where_it_first_touches() {
+        let (query, ground, boxed, _) = query();
+        let terrain = QueryFilter::layers(&[Layer::Terrain]);
+        let ball = Bounds::Circle(10.0);
+        let hit = query.sweep(&ball, [200.0, 300.0], [400.0, 300.0], &terrain).unwrap();
+        assert_eq!(hit.entity, boxed);
+        assert!((hit.distance - 70.0).abs() < 1e-2);
+        assert!(close(hit.normal, [-1.0, 0.0]));
+
+        let hit = query.sweep(&ball, [150.0, 300.0], [150.0, 500.0], &terrain).unwrap();
+        assert_eq!(hit.entity, ground);
+        assert!((hit.distance - 90.0).abs() < 1e-2);
+        assert!(query.sweep(&ball, [150.0, 300.0], [150.0, 350.0], &terrain).is_none());
+    }
+}
@@ -0,0 +1,262 @@
+use specs::{ReadStorage, System, WriteStorage, Join, Fetch, FetchMut, Entities, Entity, World};
+
+use systems::character::KNOCKBACK_TIME;
+use systems::collision::CollisionEvents;
+use systems::components::*;
+use systems::particles::*;
+use systems::terrain::carve;
+
+use std::f64::consts::PI;
+use std::iter::*;
+
+pub const AIM_SPEED: f64 = 2.0;
+pub const CHARGE_SPEED: f64 = 0.8;
+pub const GRAVITY: f64 = 200.0;
+pub const PROJECTILE_RADIUS: f64 = 4.0;
+const OUT_OF_WORLD: f64 = 5000.0;
+// Seconds a new projectile passes through whoever cast it.
+const CASTER_GRACE: f64 = 0.5;
+// Explosions hurt out to this many times their radius.
+pub const BLAST_REACH: f64 = 1.5;
+// Speed a blast throws a wizard at its centre, falling off towards the edge of its reach.
+const KNOCKBACK: f64 = 150.0;
+
+pub struct Cast {
+    pub caster: Entity,
+    pub pos: [f64; 2],
+    pub vel: [f64; 2],
+    pub spell: Spell,
+}
+
+// Projectiles waiting to be created once the dispatcher has finished.
+pub struct SpellCasts(pub Vec<Cast>);
+
+// How far from the caster's position spells are launched, clear of its own bounds.
+pub fn launch_reach(bounds: Option<&Bounds>) -> f64 {
+    bounds.map_or(0.0, |b| {
+        let h = b.half_extents();
+        h[0].max(h[1])
+    }) + PROJECTILE_RADIUS * 2.0
+}
+
+pub struct SpellSystem;
+
+impl<'a> System<'a> for SpellSystem {
+    type SystemData = (Entities<'a>,
+     ReadStorage<'a, Player>,
+     ReadStorage<'a, Pos>,
+     ReadStorage<'a, Bounds>,
+     ReadStorage<'a, Health>,
+     WriteStorage<'a, Aim>,
+     WriteStorage<'a, SpellBook>,
+     Fetch<'a, TurnState>,
+     Fetch<'a, GameInput>,
+     Fetch<'a, Delta>,
+     FetchMut<'a, SpellCasts>,
+     FetchMut<'a, AnimationEvents>);
+    fn run(&mut self,
+           (ent, player, pos, bounds, health, mut aim, mut books, turn, input, delta,
+            mut casts, mut anim_events): Self::SystemData) {
+        for (e, p, pos, aim, book) in (&*ent, &player, &pos, &mut aim, &mut books).join() {
+            if health.get(e).map_or(false, |h| !h.alive()) {
+                continue;
+            }
+            let actions = input.get(p.0);
+            let last = input.last(p.0);
+            if actions.contains(action::AIM_UP) {
+                aim.angle = (aim.angle - AIM_SPEED * delta.0).max(-PI);
+            }
+            if actions.contains(action::AIM_DOWN) {
+                aim.angle = (aim.angle + AIM_SPEED * delta.0).min(PI);
+            }
+            if actions.contains(action::NEXT_SPELL) && !last.contains(action::NEXT_SPELL) {
+                book.next();
+            }
+            if turn.active != p.0 {
+                aim.charging = false;
+                aim.power = 0.0;
+                continue;
+            }
+            if actions.contains(action::CAST) {
+                if book.ready() {
+                    aim.charging = true;
+                    aim.power = (aim.power + CHARGE_SPEED * delta.0).min(1.0);
+                }
+            } else if aim.charging {
+                if let Some(spell) = book.active_spell().cloned() {
+                    let dir = [aim.angle.cos(), aim.angle.sin()];
+                    let reach = launch_reach(bounds.get(e));
+                    let speed = spell.speed * aim.power;
+                    casts.0.push(Cast {
+                        caster: e,
+                        pos: [pos.x + dir[0] * reach, pos.y + dir[1] * reach],
+                        vel: [dir[0] * speed, dir[1] * speed],
+                        spell: spell.clone(),
+                    });
+                    book.cooldowns[book.active] = spell.cooldown;
+                    anim_events.0.push((e, AnimEvent::Cast));
+                }
+                aim.charging = false;
+                aim.power = 0.0;
+            }
+        }
+    }
+}
+
+pub fn spawn_projectiles(world: &mut World) {
+    let casts: Vec<Cast> = world.write_resource::<SpellCasts>().0.drain(..).collect();
+    for cast in casts {
+        world.create_entity()
+            .with(Pos {
+                x: cast.pos[0],
+                y: cast.pos[1],
+            })
+            .with(Vel {
+                x: cast.vel[0],
+                y: cast.vel[1],
+            })
+            .with(Bounds::Circle(PROJECTILE_RADIUS))
+            .with(CollisionObjectData {
+                ignore: vec![(cast.caster, CASTER_GRACE)],
+                fast: true,
+                ..CollisionObjectData::new(Layer::Projectiles)
+            })
+            .with(Emitter::new(EmitterConfig::trail()))
+            .with(Projectile {
+                caster: cast.caster,
+                damage: cast.spell.damage,
+                radius: cast.spell.radius,
+            });
+    }
+}
+
+struct Explosion {
+    caster: Entity,
+    point: [f64; 2],
+    damage: f64,
+    radius: f64,
+}
+
+pub struct ProjectileSystem;
+
+impl<'a> System<'a> for ProjectileSystem {
+    type SystemData = (Entities<'a>,
+     ReadStorage<'a, Projectile>,
+     ReadStorage<'a, Pos>,
+     WriteStorage<'a, Vel>,
+     WriteStorage<'a, Health>,
+     WriteStorage<'a, Score>,
+     WriteStorage<'a, Terrain>,
+     WriteStorage<'a, CharacterController>,
+     Fetch<'a, Wind>,
+     Fetch<'a, Delta>,
+     Fetch<'a, CollisionEvents>,
+     FetchMut<'a, Bursts>,
+     FetchMut<'a, AnimationEvents>);
+    fn run(&mut self,
+           (ent, projectile, pos, mut vel, mut health, mut score, mut terrain, mut controller,
+            wind, delta, events, mut bursts, mut anim_events): Self::SystemData) {
+        let mut explosions = vec![];
+        let touching = events.touching();
+        for (e, proj, pos, vel) in (&*ent, &projectile, &pos, &mut vel).join() {
+            vel.y += GRAVITY * delta.0;
+            vel.x += wind.speed * delta.0;
+            // Events come in pair order, so every peer blows up at the same point.
+            let hit = touching.iter()
+                .find(|c| c.entities.0 == e || c.entities.1 == e)
+                .and_then(|c| {
+                    c.points.first().map(|&(a, b)| if c.entities.0 == e { a } else { b })
+                });
+            if let Some(point) = hit {
+                explosions.push(Explosion {
+                    caster: proj.caster,
+                    point: point,
+                    damage: proj.damage,
+                    radius: proj.radius,
+                });
+                ent.delete(e);
+            } else if pos.x.abs() > OUT_OF_WORLD || pos.y.abs() > OUT_OF_WORLD {
+                ent.delete(e);
+            }
+        }
+
+        for ex in explosions {
+            bursts.push(ex.point, 40, EmitterConfig::explosion());
+            for terrain in (&mut terrain).join() {
+                if ex.point[0] >= 0.0 && ex.point[1] >= 0.0 {
+                    let material = terrain.material_at([ex.point[0] as usize,
+                                                        ex.point[1] as usize]);
+                    bursts.push(ex.point, 12, EmitterConfig::debris(material));
+                }
+                carve(terrain, ex.point, ex.radius);
+            }
+            let reach = ex.radius * BLAST_REACH;
+            let mut dealt = 0.0;
+            let mut kills = 0;
+            for (e, pos, health) in (&*ent, &pos, &mut health).join() {
+                let d = ((pos.x - ex.point[0]).powi(2) + (pos.y - ex.point[1]).powi(2)).sqrt();
+                if d >= reach || !health.alive() {
+                    continue;
+                }
+                let damage = ex.damage * (1.0 - d / reach);
+                health.current -= damage;
+                if let (Some(vel), Some(cc)) = (vel.get_mut(e), controller.get_mut(e)) {
+                    let away = if d > 0.0 {
+                        [(pos.x - ex.point[0]) / d, (pos.y - ex.point[1]) / d]
+                    } else {
+                        [0.0, -1.0]
+                    };
+                    let speed = KNOCKBACK * (1.0 - d / reach);
+                    vel.x += away[0] * speed;
+                    vel.y += away[1] * speed;
+                    cc.knockback = KNOCKBACK_TIME;
+                }
+                dealt += damage;
+                if health.alive() {
+                    anim_events.0.push((e, AnimEvent::Hurt));
+                } else {
+                    anim_events.0.push((e, AnimEvent::Death));
+                    kills += 1;
+                }
+            }
+            if let Some(score) = score.get_mut(ex.caster) {
+                score.damage += dealt;
+                score.kills += kills;
+            }
+        }
+    }
+}
+
+pub struct TurnSystem;
+
+impl<'a> System<'a> for TurnSystem {
+    type SystemData = (ReadStorage<'a, Player>,
+     ReadStorage<'a, Health>,
+     WriteStorage<'a, SpellBook>,
+     FetchMut<'a, TurnState>,
+     FetchMut<'a, Wind>,
+     Fetch<'a, Delta>);
+    fn run(&mut self, (player, health, mut books, mut turn, mut wind, delta): Self::SystemData) {
+        for book in (&mut books).join() {
+            for c in book.cooldowns.iter_mut() {
+                *c = (*c - delta.0).max(0.0);
+            }
+        }
+        let mut alive = Vec::from_iter((&player, &health)
+            .join()
+            .filter(|&(_, h)| h.alive())
+            .map(|(p, _)| p.0));
+        alive.sort();
+        turn.time_left -= delta.0;
+        if alive.is_empty() || (turn.time_left > 0.0 && alive.contains(&turn.active)) {
+            return;
+        }
+        let active = turn.active;
+        turn.active = alive.iter().cloned().find(|&p| p > active).unwrap_or(alive[0]);
+        turn.time_left = turn.turn_length;
+        turn.turn += 1;
+        // Deterministic so every peer agrees on the wind without syncing it.
+        let t = ((turn.turn as u64).wrapping_mul(2654435761) % 1000) as f64 / 1000.0;
+        wind.speed = wind.range[0] + (wind.range[1] - wind.range[0]) * t;
+    }
+}
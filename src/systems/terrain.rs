@@ -1,133 +1,262 @@
-use specs::{ReadStorage, System, VecStorage, World, WriteStorage, Join, Fetch, HashMapStorage};
+use specs::{System, World, WriteStorage, Join};
 
 use systems::components::*;
-use std::collections::HashSet;
-use std::iter::*;
-use nalgebra::*;
-pub struct TerrainSystem;
-
-
-fn get_edges(point: &Vector2<f64>, points: &HashSet<[usize; 2]>) -> HashSet<[usize; 2]> {
-    let mut neighboughs = HashSet::new();
-    let px = point[0] as usize;
-    let py = point[1] as usize;
-    for p1 in px - 1..px + 2 {
-        for p2 in py - 1..py + 2 {
-            if !(px == p1 && py == p2) {
-                neighboughs.insert([p1, p2]);
+use systems::decompose::{bridge, nest};
+use systems::profile::*;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+pub struct TerrainSystem {
+    profiler: Profiler,
+}
+
+impl TerrainSystem {
+    pub fn new(profiler: &Profiler) -> TerrainSystem {
+        TerrainSystem { profiler: profiler.clone() }
+    }
+}
+
+
+// How far each side of a pinch, where cells meet only at a corner, is cut back, so no
+// two outlines share a point.
+const PINCH: f64 = 1.0 / 16.0;
+
+// Every outline around the cells, along the cell edges with the solid on the left of each
+// edge: the outside of each island one way round and the holes in it the other. Where two
+// ways lead on from a corner, the outline takes the one turning the way a single cell's
+// corners turn, which keeps to one side of the pinch. Edges are followed in row order, so
+// the outlines come out the same on every peer.
+fn outlines(points: &HashSet<[usize; 2]>) -> Vec<Vec<[f64; 2]>> {
+    let solid = |x: i64, y: i64| x >= 0 && y >= 0 && points.contains(&[x as usize, y as usize]);
+    let mut edges: Vec<([i64; 2], [i64; 2])> = vec![];
+    for p in points.iter() {
+        let (x, y) = (p[0] as i64, p[1] as i64);
+        if !solid(x, y - 1) {
+            edges.push(([x, y], [x + 1, y]));
+        }
+        if !solid(x + 1, y) {
+            edges.push(([x + 1, y], [x + 1, y + 1]));
+        }
+        if !solid(x, y + 1) {
+            edges.push(([x + 1, y + 1], [x, y + 1]));
+        }
+        if !solid(x - 1, y) {
+            edges.push(([x, y + 1], [x, y]));
+        }
+    }
+    edges.sort_by_key(|&(a, b)| (a[1], a[0], b[1], b[0]));
+    let mut ways: HashMap<[i64; 2], Vec<[i64; 2]>> = HashMap::new();
+    for &(a, b) in edges.iter() {
+        ways.entry(a).or_insert_with(Vec::new).push(b);
+    }
+    let next = |a: [i64; 2], b: [i64; 2]| {
+        let on = &ways[&b];
+        let turn = |c: [i64; 2]| (b[0] - a[0]) * (c[1] - b[1]) - (b[1] - a[1]) * (c[0] - b[0]);
+        on.iter().find(|c| on.len() == 1 || turn(**c) > 0).cloned().unwrap_or(on[0])
+    };
+
+    let mut followed: HashSet<([i64; 2], [i64; 2])> = HashSet::new();
+    let mut outlines = vec![];
+    for &first in edges.iter() {
+        let mut outline: Vec<[f64; 2]> = vec![];
+        let mut edge = first;
+        while followed.insert(edge) {
+            let (a, b) = edge;
+            let c = next(a, b);
+            let at = [b[0] as f64, b[1] as f64];
+            if ways[&b].len() > 1 {
+                outline.push([at[0] - (b[0] - a[0]) as f64 * PINCH,
+                              at[1] - (b[1] - a[1]) as f64 * PINCH]);
+                outline.push([at[0] + (c[0] - b[0]) as f64 * PINCH,
+                              at[1] + (c[1] - b[1]) as f64 * PINCH]);
+            } else {
+                outline.push(at);
             }
+            edge = (b, c);
+        }
+        // Only the corners; points along a straight edge add nothing.
+        let n = outline.len();
+        let corners: Vec<[f64; 2]> = (0..n)
+            .filter(|&i| {
+                let (p, q, r) = (outline[(i + n - 1) % n], outline[i], outline[(i + 1) % n]);
+                (q[0] - p[0]) * (r[1] - q[1]) - (q[1] - p[1]) * (r[0] - q[0]) != 0.0
+            })
+            .map(|i| outline[i])
+            .collect();
+        if !corners.is_empty() {
+            outlines.push(corners);
         }
     }
-    neighboughs.difference(points).cloned().collect()
+    outlines
 }
+
+// One polygon for each island, with any holes in it joined on, so caves and tunnels stay
+// open. A compound of them when there's more than one.
 fn new_bounds(points: &HashSet<[usize; 2]>) -> Bounds {
-    let mut poly = vec![];
-    let mut complete = false;
-    let mut start = None;
-    let mut direction = Vector2::new(-1.0, 0.0);
-    if let Some(mut p) = points.iter().next().cloned() {
-        let mut current_point = Vector2::new(p[0] as f64, p[1] as f64);
-        let mut edges;
-        while !complete {
-            while start == None {
-                edges = get_edges(&current_point, &points);
-                let edge = !edges.is_empty();
-                if edge {
-                    start = Some(current_point);
-                } else {
-                    current_point[0] -= 1.0;
-                }
-            }
-            poly.push(current_point);
-            {
-                let clockwise: [(f64, f64); 8] = [(-1.0, 1.0),
-                                                  (0.0, 1.0),
-                                                  (1.0, 1.0),
-                                                  (1.0, 0.0),
-                                                  (1.0, -1.0),
-                                                  (0.0, -1.0),
-                                                  (-1.0, -1.0),
-                                                  (-1.0, 0.0)];
-
-                let count = clockwise.iter()
-                    .take_while(|&&(x, y)| x != direction[0] || y != direction[1])
-                    .count();
-                for v in clockwise.iter()
-                    .cycle()
-                    .skip((count + 7) % 8)
-                    .map(|&(x, y)| Vector2::new(x, y)) {
-                    let mut at = current_point.clone();
-                    at += v;
-                    edges = get_edges(&at, &points);
-                    if !edges.is_empty() {
-                        current_point = at;
-                        direction = v;
-                        break;
-                    }
-                }
-            }
-            if start == Some(current_point) {
-                complete = true;
-            }
-        }
+    let mut polygons: Vec<Vec<[f64; 2]>> = nest(outlines(points))
+        .into_iter()
+        .map(|(outline, holes)| bridge(&outline, &holes))
+        .collect();
+    if polygons.len() == 1 {
+        return Bounds::Polygon(Box::new(polygons.remove(0)));
     }
-    let mut two_back = None;
-    let mut one_back = None;
-    let mut vec = vec![];
-    for p in poly {
-        match two_back {
-            None => {
-                two_back = Some(p);
-            }
-            Some(tb) => {
-                match one_back {
-                    None => {
-                        one_back = Some(p);
-                    }
-                    Some(ob) => {
-                        let d1 = ob - tb;
-                        let d2 = p - ob;
-
-                        if d2.normalize() == d1.normalize() {
-                            one_back = Some(p);
-                        } else {
-                            vec.push([tb[0] as f64, tb[1] as f64]);
-                            two_back = Some(ob);
-                            one_back = Some(p);
-                        }
-                    }
-                }
+    Bounds::Compound(polygons.into_iter()
+        .map(|polygon| {
+            BoundsPart {
+                offset: [0.0, 0.0],
+                angle: 0.0,
+                shape: Bounds::Polygon(Box::new(polygon)),
             }
-        }
+        })
+        .collect())
+}
 
+// Re-traces dirty terrain outside the dispatcher, e.g. while the level editor is open.
+pub fn retrace_all(world: &World) {
+    let mut terrain = world.write::<Terrain>();
+    let mut bounds = world.write::<Bounds>();
+    for (terrain, bounds) in (&mut terrain, &mut bounds).join() {
+        if terrain.dirty {
+            *bounds = new_bounds(&terrain.points);
+            terrain.dirty = false;
+        }
     }
-    return Bounds::Polygon(Box::new(vec));
 }
 
-fn handle_collision(terrain: &mut Terrain, col: &CollisionObjectData) {
+const SCORCH_BAND: f64 = 3.0;
 
-    for contact in col.contacts.values().flat_map(|x| x) {
-        terrain.dirty = true;
-        for x  in -5..5 {
-            for y in -5..5 {
-                let p = [((contact[0]) + (x as f64)) as usize , ((contact[1])  + (y as f64)) as usize];
-                !terrain.points.remove(&p);  
+// Removes every cell within `radius` of `centre` and scorches a band around the hole.
+pub fn carve(terrain: &mut Terrain, centre: [f64; 2], radius: f64) {
+    let scorch = radius + SCORCH_BAND;
+    let r = scorch.ceil() as i64;
+    terrain.dirty = true;
+    terrain.revision += 1;
+    let low = |c: f64| (c - r as f64).max(0.0) as usize;
+    let high = |c: f64| (c + r as f64).max(0.0) as usize;
+    terrain.mark_edited([low(centre[0]), low(centre[1])], [high(centre[0]), high(centre[1])]);
+    for x in -r..(r + 1) {
+        for y in -r..(r + 1) {
+            let px = centre[0] + (x as f64);
+            let py = centre[1] + (y as f64);
+            if px < 0.0 || py < 0.0 {
+                continue;
+            }
+            let p = [px as usize, py as usize];
+            let d = ((x * x + y * y) as f64).sqrt();
+            if d <= radius {
+                if terrain.points.remove(&p) {
+                    terrain.scorched.remove(&p);
+                    terrain.note_changed(p);
+                }
+            } else if d <= scorch && terrain.points.contains(&p) && terrain.scorched.insert(p) {
+                terrain.note_changed(p);
             }
         }
     }
 }
+
 impl<'a> System<'a> for TerrainSystem {
-    type SystemData = (WriteStorage<'a, Terrain>,
-     WriteStorage<'a, Bounds>,
-     ReadStorage<'a, CollisionObjectData>);
-    fn run(&mut self, (mut terrain, mut bounds, col): Self::SystemData) {
-        for (mut terrain, mut bounds, col) in (&mut terrain, &mut bounds, &col).join() {
-            handle_collision(terrain, col);
+    type SystemData = (WriteStorage<'a, Terrain>, WriteStorage<'a, Bounds>);
+    fn run(&mut self, (mut terrain, mut bounds): Self::SystemData) {
+        for (mut terrain, mut bounds) in (&mut terrain, &mut bounds).join() {
             if terrain.dirty {
-                println!("Dirty copy");
+                let start = Instant::now();
                 *bounds = new_bounds(&terrain.points);
-                (*terrain).dirty = false; 
+                (*terrain).dirty = false;
+                let ms = millis(start.elapsed());
+                self.profiler.terrain_retrace(ms);
+                let outlines: Vec<usize> = bounds.parts()
+                    .iter()
+                    .filter_map(|part| match part.shape {
+                        Bounds::Polygon(ref ps) => Some(ps.len()),
+                        _ => None,
+                    })
+                    .collect();
+                debug!("retraced terrain cells={} outlines={} vertices={} ms={:.3}",
+                       terrain.points.len(),
+                       outlines.len(),
+                       outlines.iter().sum::<usize>(),
+                       ms);
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use systems::decompose::{contains, convex_pieces};
+
+    // A block of cells with some left out.
+    fn block(width: usize, height: usize, without: &[[usize; 2]]) -> HashSet<[usize; 2]> {
+        let mut points = HashSet::new();
+        for x in 0..width {
+            for y in 0..height {
+                if !without.contains(&[x, y]) {
+                    points.insert([x, y]);
+                }
             }
         }
+        points
     }
-}
\ No newline at end of file
+
+    fn polygons(points: &HashSet<[usize; 2]>) -> Vec<Vec<[f64; 2]>> {
+        new_bounds(points)
+            .parts()
+            .into_iter()
+            .filter_map(|part| match part.shape {
+                Bounds::Polygon(ps) => Some(*ps),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // The area the collision world gets to see, from the polygon's convex pieces.
+    fn solid_area(polygon: &[[f64; 2]]) -> f64 {
+        convex_pieces(polygon)
+            .unwrap()
+            .iter()
+            .map(|piece| {
+                let n = piece.len();
+                (0..n)
+                    .map(|i| {
+                        let (a, b) = (piece[i], piece[(i + 1) % n]);
+                        a[0] * b[1] - b[0] * a[1]
+                    })
+                    .sum::<f64>()
+                    .abs() / 2.0
+            })
+            .sum()
+    }
+
+    #[test]
+    fn holes_stay_open() {
+        let polygons = polygons(&block(6, 5, &[[2, 2]]));
+        assert_eq!(polygons.len(), 1);
+        assert_eq!(solid_area(&polygons[0]), 29.0);
+        assert!(!contains(&polygons[0], [2.5, 2.5]));
+        assert!(contains(&polygons[0], [0.5, 0.5]));
+        assert!(contains(&polygons[0], [3.5, 2.5]));
+    }
+
+    #[test]
+    fn cells_meeting_at_a_corner_are_split_apart() {
+        let polygons = polygons(&block(2, 2, &[[1, 0], [0, 1]]));
+        assert_eq!(polygons.len(), 2);
+        // Each loses a sliver at the pinch.
+        let sliver = PINCH * PINCH / 2.0;
+        for polygon in polygons.iter() {
+            assert_eq!(solid_area(polygon), 1.0 - sliver);
+        }
+        assert!(!polygons[0].iter().any(|p| polygons[1].contains(p)));
+    }
+
+    #[test]
+    fn hole_pinched_to_the_outside_stays_open() {
+        // The middle cell is a hole touching the empty corner.
+        let polygons = polygons(&block(3, 3, &[[1, 1], [2, 2]]));
+        assert_eq!(polygons.len(), 1);
+        assert_eq!(solid_area(&polygons[0]), 7.0 - PINCH * PINCH);
+        assert!(!contains(&polygons[0], [1.5, 1.5]));
+        assert!(!contains(&polygons[0], [2.5, 2.5]));
+        assert!(contains(&polygons[0], [0.5, 0.5]));
+    }
+}
@@ -0,0 +1,49 @@
+use specs::{ReadStorage, System, WriteStorage, Join, Fetch, FetchMut};
+
+use systems::collision::{CollisionEvent, CollisionEvents};
+use systems::components::*;
+
+// Applies each `Zone` to the wizards its sensor reports overlapping.
+pub struct ZoneSystem;
+
+impl<'a> System<'a> for ZoneSystem {
+    type SystemData = (ReadStorage<'a, Zone>,
+     ReadStorage<'a, CollisionObjectData>,
+     WriteStorage<'a, Health>,
+     Fetch<'a, CollisionEvents>,
+     Fetch<'a, Delta>,
+     FetchMut<'a, AnimationEvents>);
+    fn run(&mut self,
+           (zones, col, mut health, events, delta, mut anim_events): Self::SystemData) {
+        for event in events.0.iter() {
+            if let CollisionEvent::SensorEntered(overlap) = *event {
+                if zones.get(overlap.sensor) != Some(&Zone::Kill) {
+                    continue;
+                }
+                if let Some(health) = health.get_mut(overlap.other) {
+                    if health.alive() {
+                        health.current = 0.0;
+                        anim_events.0.push((overlap.other, AnimEvent::Death));
+                    }
+                }
+            }
+        }
+        for (zone, col) in (&zones, &col).join() {
+            let per_second = match *zone {
+                Zone::Water(damage) => damage,
+                Zone::Kill => continue,
+            };
+            for &e in col.overlaps.iter() {
+                if let Some(health) = health.get_mut(e) {
+                    if !health.alive() {
+                        continue;
+                    }
+                    health.current -= per_second * delta.0;
+                    if !health.alive() {
+                        anim_events.0.push((e, AnimEvent::Death));
+                    }
+                }
+            }
+        }
+    }
+}